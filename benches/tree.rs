@@ -0,0 +1,86 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use sparse_merkle_tree::{
+    blake2b::Blake2bHasher, default_store::DefaultStore, h256::H256, traits::Hasher,
+    tree::SparseMerkleTree,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+
+fn random_keys(count: usize) -> Vec<H256> {
+    (0..count as u64)
+        .map(|i| {
+            let mut hasher = Blake2bHasher::default();
+            for b in i.to_be_bytes() {
+                hasher.write_byte(b);
+            }
+            hasher.finish()
+        })
+        .collect()
+}
+
+fn build_tree(keys: &[H256]) -> SMT {
+    let mut tree = SMT::default();
+    for key in keys {
+        tree.update(*key, *key, true).expect("update");
+    }
+    tree
+}
+
+fn bench_update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("update");
+    for size in [100, 1_000] {
+        let keys = random_keys(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &keys, |b, keys| {
+            b.iter(|| build_tree(keys));
+        });
+    }
+    group.finish();
+}
+
+fn bench_from_leaves(c: &mut Criterion) {
+    let mut group = c.benchmark_group("from_leaves");
+    for size in [1_000, 10_000] {
+        let members = random_keys(size)
+            .into_iter()
+            .map(|key| (key, key))
+            .collect::<Vec<_>>();
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &members, |b, members| {
+            b.iter_batched(
+                || members.clone(),
+                |members| SMT::from_leaves(DefaultStore::default(), members).expect("build"),
+                BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+fn bench_proofs(c: &mut Criterion) {
+    let keys = random_keys(1_000);
+    let tree = build_tree(&keys);
+    let proof_keys = keys[..100].to_vec();
+
+    let mut group = c.benchmark_group("proof");
+    group.throughput(Throughput::Elements(proof_keys.len() as u64));
+    group.bench_function("member_proof", |b| {
+        b.iter_batched(
+            || proof_keys.clone(),
+            |keys| tree.member_proof(keys).expect("member proof"),
+            BatchSize::SmallInput,
+        );
+    });
+    group.bench_function("modify_root_proof", |b| {
+        b.iter_batched(
+            || proof_keys.clone(),
+            |keys| tree.modify_root_proof(keys).expect("modify root proof"),
+            BatchSize::SmallInput,
+        );
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_update, bench_from_leaves, bench_proofs);
+criterion_main!(benches);
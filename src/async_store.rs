@@ -0,0 +1,349 @@
+use core::cell::RefCell;
+use core::fmt::Debug;
+use core::future::Future;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    merkle_proof::Side,
+    overlay_store::StagedBatch,
+    traits::{Hasher, StoreReadOps, Value, WriteBatch},
+    tree::{BranchKey, BranchNode, ChildKey, ModifyProof, SparseMerkleTree, TreeCounts},
+};
+
+/// Async counterpart of `StoreReadOps`, for backends doing I/O
+pub trait AsyncStoreReadOps<V> {
+    fn get_branch(
+        &self,
+        branch_key: &BranchKey,
+    ) -> impl Future<Output = Result<Option<BranchNode>>> + Send;
+    fn get_leaf(&self, leaf_key: &H256) -> impl Future<Output = Result<Option<V>>> + Send;
+    /// Fetch several branches, in the order of branch_keys
+    /// backends able to batch reads should override it, by default the
+    /// branches are read one after the other
+    fn get_branches(
+        &self,
+        branch_keys: &[BranchKey],
+    ) -> impl Future<Output = Result<Vec<Option<BranchNode>>>> + Send
+    where
+        Self: Sync,
+    {
+        async move {
+            let mut branches = Vec::with_capacity(branch_keys.len());
+            for branch_key in branch_keys {
+                branches.push(self.get_branch(branch_key).await?);
+            }
+            Ok(branches)
+        }
+    }
+    /// Fetch several leaves, in the order of leaf_keys
+    fn get_leaves(&self, leaf_keys: &[H256]) -> impl Future<Output = Result<Vec<Option<V>>>> + Send
+    where
+        Self: Sync,
+        V: Send,
+    {
+        async move {
+            let mut leaves = Vec::with_capacity(leaf_keys.len());
+            for leaf_key in leaf_keys {
+                leaves.push(self.get_leaf(leaf_key).await?);
+            }
+            Ok(leaves)
+        }
+    }
+}
+
+/// Async counterpart of `StoreWriteOps`
+///
+/// The tree stages every write of an update, so a backend only receives
+/// whole batches.
+pub trait AsyncStoreWriteOps<V> {
+    fn write_batch(&mut self, batch: WriteBatch<V>) -> impl Future<Output = Result<()>> + Send;
+    /// Called by the tree before `commit_root`, with the counts of the tree
+    /// at root
+    fn commit_counts(
+        &mut self,
+        _root: &H256,
+        _counts: &TreeCounts,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+    /// Called by the tree once an update is fully written, with the new root
+    fn commit_root(&mut self, _root: &H256) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Nodes a synchronous walk read but were not fetched yet
+#[derive(Debug, Default)]
+struct Missing {
+    branches: BTreeSet<BranchKey>,
+    leaves: BTreeSet<H256>,
+}
+
+impl Missing {
+    fn is_empty(&self) -> bool {
+        self.branches.is_empty() && self.leaves.is_empty()
+    }
+}
+
+/// Where the prefetch descends below a branch
+#[derive(Debug, Clone)]
+enum Descent {
+    /// Toward key, with the nearest subtrees on each side of the path so far
+    Path {
+        key: H256,
+        left: Option<ChildKey>,
+        right: Option<ChildKey>,
+    },
+    /// Along the right edge if true, the left one otherwise
+    Edge(bool),
+}
+
+/// Nodes fetched from an async store, read by the synchronous tree code
+///
+/// The paths to the keys of an operation and to their neighbours are fetched
+/// first, one level of the tree per batch. A walk reading a node off those paths fails and records
+/// it in `Missing`; its result is then dropped whatever the error, and every
+/// node recorded is fetched in one batch before the walk is run again.
+struct Prefetch<V> {
+    branches: HashMap<BranchKey, Option<BranchNode>>,
+    leaves: HashMap<H256, Option<V>>,
+    missing: RefCell<Missing>,
+}
+
+impl<V: Send> Prefetch<V> {
+    fn new() -> Self {
+        Prefetch {
+            branches: HashMap::new(),
+            leaves: HashMap::new(),
+            missing: RefCell::new(Missing::default()),
+        }
+    }
+
+    /// Fetch the branches and leaves on the paths from the root to keys,
+    /// and on the paths to the neighbours of each key
+    async fn fetch_paths<S>(&mut self, store: &S, keys: &[H256]) -> Result<()>
+    where
+        S: AsyncStoreReadOps<V> + Sync,
+    {
+        let mut leaves = keys.iter().copied().collect::<BTreeSet<_>>();
+        let mut level = HashMap::<BranchKey, Vec<Descent>>::new();
+        level.insert(
+            BranchKey::root(),
+            keys.iter()
+                .map(|key| Descent::Path {
+                    key: *key,
+                    left: None,
+                    right: None,
+                })
+                .collect(),
+        );
+        while !level.is_empty() {
+            let (branch_keys, descents): (Vec<_>, Vec<_>) = level.into_iter().unzip();
+            let branches = store.get_branches(&branch_keys).await?;
+            let mut next = HashMap::<BranchKey, Vec<Descent>>::new();
+            let mut descend = |child: &ChildKey, descent: Descent| match child {
+                ChildKey::Branch(child_key) => {
+                    next.entry(child_key.clone()).or_default().push(descent)
+                }
+                ChildKey::Leaf(leaf_key) => {
+                    leaves.insert(*leaf_key);
+                }
+            };
+            for ((branch_key, descents), branch) in branch_keys.iter().zip(descents).zip(&branches)
+            {
+                let Some(branch) = branch else { continue };
+                for descent in descents {
+                    match descent {
+                        Descent::Path {
+                            key,
+                            mut left,
+                            mut right,
+                        } => {
+                            let child = if key.get_bit(branch_key.height) {
+                                left = Some(branch.left.1.clone());
+                                &branch.right.1
+                            } else {
+                                right = Some(branch.right.1.clone());
+                                &branch.left.1
+                            };
+                            let (lowest, highest) = match child {
+                                ChildKey::Branch(child_key) => child_key.key_range(),
+                                ChildKey::Leaf(leaf_key) => (*leaf_key, *leaf_key),
+                            };
+                            if lowest <= key && key <= highest && lowest != highest {
+                                descend(child, Descent::Path { key, left, right });
+                                continue;
+                            }
+                            // the path ends, the nearest members on each side
+                            // sit on the edges of the nearest subtrees
+                            if highest < key {
+                                left = Some(child.clone());
+                            } else if key < lowest {
+                                right = Some(child.clone());
+                            }
+                            if let Some(left) = &left {
+                                descend(left, Descent::Edge(true));
+                            }
+                            if let Some(right) = &right {
+                                descend(right, Descent::Edge(false));
+                            }
+                        }
+                        Descent::Edge(rightmost) => {
+                            let child = if rightmost {
+                                &branch.right.1
+                            } else {
+                                &branch.left.1
+                            };
+                            descend(child, Descent::Edge(rightmost));
+                        }
+                    }
+                }
+            }
+            self.branches.extend(branch_keys.into_iter().zip(branches));
+            level = next;
+        }
+        self.fetch(
+            store,
+            Missing {
+                branches: BTreeSet::new(),
+                leaves,
+            },
+        )
+        .await
+    }
+
+    /// Fetch every node of missing, one batch per kind
+    async fn fetch<S>(&mut self, store: &S, missing: Missing) -> Result<()>
+    where
+        S: AsyncStoreReadOps<V> + Sync,
+    {
+        if !missing.branches.is_empty() {
+            let branch_keys = missing.branches.into_iter().collect::<Vec<_>>();
+            let branches = store.get_branches(&branch_keys).await?;
+            self.branches.extend(branch_keys.into_iter().zip(branches));
+        }
+        if !missing.leaves.is_empty() {
+            let leaf_keys = missing.leaves.into_iter().collect::<Vec<_>>();
+            let leaves = store.get_leaves(&leaf_keys).await?;
+            self.leaves.extend(leaf_keys.into_iter().zip(leaves));
+        }
+        Ok(())
+    }
+
+    /// Run walk over the paths to keys, until every node it reads has been
+    /// fetched from store
+    async fn run<T, S, F>(&mut self, store: &S, keys: &[H256], mut walk: F) -> Result<T>
+    where
+        S: AsyncStoreReadOps<V> + Sync,
+        F: FnMut(&Self) -> Result<T>,
+    {
+        self.fetch_paths(store, keys).await?;
+        loop {
+            let result = walk(self);
+            let missing = self.missing.take();
+            if missing.is_empty() {
+                return result;
+            }
+            self.fetch(store, missing).await?;
+        }
+    }
+}
+
+impl<V: Clone> StoreReadOps<V> for &Prefetch<V> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        match self.branches.get(branch_key) {
+            Some(branch) => Ok(branch.clone()),
+            None => {
+                self.missing
+                    .borrow_mut()
+                    .branches
+                    .insert(branch_key.clone());
+                Err(Error::MissingBranch(branch_key.height, branch_key.node_key))
+            }
+        }
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        match self.leaves.get(leaf_key) {
+            Some(leaf) => Ok(leaf.clone()),
+            None => {
+                self.missing.borrow_mut().leaves.insert(*leaf_key);
+                Err(Error::MissingLeaf(*leaf_key))
+            }
+        }
+    }
+}
+
+impl<H, V, S> SparseMerkleTree<H, V, S>
+where
+    H: Hasher + Default,
+    V: Value + Clone + Debug + Send,
+    S: AsyncStoreReadOps<V> + Sync,
+{
+    /// Async version of `get`
+    pub async fn get_async(&self, key: &H256) -> Result<V> {
+        let root = *self.root();
+        Prefetch::new()
+            .run(self.store(), &[*key], |prefetch| {
+                SparseMerkleTree::<H, V, _>::new(root, prefetch).get(key)
+            })
+            .await
+    }
+
+    /// Async version of `member_proof`
+    pub async fn member_proof_async(&self, keys: Vec<H256>) -> Result<Vec<(Vec<Side>, H256)>> {
+        let root = *self.root();
+        Prefetch::new()
+            .run(self.store(), &keys, |prefetch| {
+                SparseMerkleTree::<H, V, _>::new(root, prefetch).member_proof(keys.clone())
+            })
+            .await
+    }
+
+    /// Async version of `modify_root_proof`
+    pub async fn modify_root_proof_async(&self, keys: Vec<H256>) -> Result<Vec<ModifyProof>> {
+        let root = *self.root();
+        Prefetch::new()
+            .run(self.store(), &keys, |prefetch| {
+                SparseMerkleTree::<H, V, _>::new(root, prefetch).modify_root_proof(keys.clone())
+            })
+            .await
+    }
+}
+
+impl<H, V, S> SparseMerkleTree<H, V, S>
+where
+    H: Hasher + Default,
+    V: Value + Clone + Debug + Send,
+    S: AsyncStoreReadOps<V> + AsyncStoreWriteOps<V> + Sync,
+{
+    /// Async version of `update`
+    /// the update is computed over the fetched nodes, then written as one batch
+    pub async fn update_async(&mut self, key: H256, value: V, insertion: bool) -> Result<&H256> {
+        let root = *self.root();
+        let counts = self.counts().cloned();
+        let staged = Prefetch::new()
+            .run(self.store(), &[key], |prefetch| {
+                let tree = SparseMerkleTree::<H, V, _>::with_counts(root, prefetch, counts.clone());
+                let mut overlay = tree.overlay();
+                overlay.update(key, value.clone(), insertion)?;
+                Ok(overlay.into_batch())
+            })
+            .await?;
+
+        self.commit_staged_async(staged).await?;
+        Ok(self.root())
+    }
+
+    /// Async version of `commit_staged`
+    async fn commit_staged_async(&mut self, staged: StagedBatch<V>) -> Result<()> {
+        self.store_mut().write_batch(staged.batch).await?;
+        if let Some(counts) = &staged.counts {
+            self.store_mut().commit_counts(&staged.root, counts).await?;
+        }
+        self.store_mut().commit_root(&staged.root).await?;
+        self.set_root(staged.root, staged.counts);
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use core::ops::{Add, AddAssign};
+use std::collections::BTreeMap;
+
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    merkle_proof::Side,
+    multi_proof::{MultiProof, ProofOp},
+    tree::ModifyProof,
+};
+
+// bytes of a remainder proof: side, hash and height
+const REMAINDER_PROOF_SIZE: usize = 34;
+// bytes of a left, right or continuing side proof: hash and height
+const SIDE_PROOF_SIZE: usize = 33;
+// Plutus data splits longer byte strings into chunks of this size
+const CBOR_CHUNK_SIZE: usize = 64;
+
+/// Size of the CBOR head of an item with argument n
+fn cbor_head(n: usize) -> usize {
+    match n {
+        0..=23 => 1,
+        24..=0xff => 2,
+        0x100..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+/// Size of a byte string of len bytes encoded as Plutus data
+fn cbor_bytes(len: usize) -> usize {
+    if len <= CBOR_CHUNK_SIZE {
+        return cbor_head(len) + len;
+    }
+    // indefinite length string of chunks, closed by a break
+    let full = len / CBOR_CHUNK_SIZE;
+    let last = len % CBOR_CHUNK_SIZE;
+    let last = if last == 0 { 0 } else { cbor_head(last) + last };
+    1 + full * (cbor_head(CBOR_CHUNK_SIZE) + CBOR_CHUNK_SIZE) + last + 1
+}
+
+/// Size of a constructor holding fields of the given sizes, as Plutus data
+fn cbor_constr(fields: &[usize]) -> usize {
+    // tag 121, then an indefinite length list closed by a break
+    2 + 1 + fields.iter().sum::<usize>() + 1
+}
+
+/// Which Aiken verifier a proof is checked by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProofKind {
+    /// `member_in_tree`, from `member_proof`
+    Member,
+    /// `verify_added_member` or `verify_deleted_member`, from
+    /// `modify_root_proof`
+    Modify,
+    /// `members_in_tree`, from `multi_proof`
+    Members,
+    /// `verify_added_members` or `verify_deleted_members`, from
+    /// `modify_root_multi_proof`
+    ModifyMembers,
+}
+
+/// Expected cost of checking proofs on chain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Budget {
+    /// Number of proofs counted
+    pub proofs: usize,
+    /// Size of the proofs in the redeemer, encoded as Plutus data
+    pub cbor_size: usize,
+    /// Number of `blake2b_256` calls made by the verifier, hashing the
+    /// members included
+    pub blake2b_calls: usize,
+}
+
+impl Add for Budget {
+    type Output = Budget;
+    fn add(self, other: Budget) -> Budget {
+        Budget {
+            proofs: self.proofs + other.proofs,
+            cbor_size: self.cbor_size + other.cbor_size,
+            blake2b_calls: self.blake2b_calls + other.blake2b_calls,
+        }
+    }
+}
+
+impl AddAssign for Budget {
+    fn add_assign(&mut self, other: Budget) {
+        *self = *self + other;
+    }
+}
+
+impl core::iter::Sum for Budget {
+    fn sum<I: Iterator<Item = Budget>>(iter: I) -> Budget {
+        iter.fold(Budget::default(), Add::add)
+    }
+}
+
+/// Proof whose on chain cost can be estimated before building a transaction
+pub trait ProofBudget {
+    /// Verifier the proof is checked by
+    fn kind(&self) -> ProofKind;
+    /// Expected cost of checking the proof, fails on a malformed proof
+    fn budget(&self) -> Result<Budget>;
+}
+
+/// Proof of a single member, as returned by `member_proof`
+#[derive(Debug, Clone, Copy)]
+pub struct MemberProof<'a>(pub &'a (Vec<Side>, H256));
+
+impl<'a> ProofBudget for MemberProof<'a> {
+    fn kind(&self) -> ProofKind {
+        ProofKind::Member
+    }
+    fn budget(&self) -> Result<Budget> {
+        let (path, _) = self.0;
+        let steps = path.len();
+        Ok(Budget {
+            proofs: 1,
+            cbor_size: cbor_bytes(REMAINDER_PROOF_SIZE * steps),
+            // the member, then one hash per step
+            blake2b_calls: 1 + steps,
+        })
+    }
+}
+
+/// Proof of a single insertion or removal, as returned by `modify_root_proof`
+///
+/// Heights are counted as two bytes integers, the largest they can take.
+impl ProofBudget for ModifyProof {
+    fn kind(&self) -> ProofKind {
+        ProofKind::Modify
+    }
+    fn budget(&self) -> Result<Budget> {
+        let (remaining, left, continuing, right, _, _) = self;
+        // the first hash of each side is the neighbouring leaf
+        let left_proofs = left.len().saturating_sub(1);
+        let right_proofs = right.len().saturating_sub(1);
+        let cbor_size = cbor_constr(&[
+            cbor_bytes(32),
+            cbor_bytes(32),
+            cbor_bytes(SIDE_PROOF_SIZE * left_proofs),
+            cbor_bytes(SIDE_PROOF_SIZE * right_proofs),
+            cbor_bytes(SIDE_PROOF_SIZE * continuing.len()),
+            cbor_bytes(REMAINDER_PROOF_SIZE * remaining.len()),
+            cbor_head(0xff),
+            cbor_head(0xff),
+        ]);
+        // the member and both leaves, each side, the continuing side and
+        // the remaining steps once before and once after the update, and
+        // the merges of the sides with and without the member
+        let blake2b_calls =
+            7 + left_proofs + right_proofs + 2 * continuing.len() + 2 * remaining.len();
+        Ok(Budget {
+            proofs: 1,
+            cbor_size,
+            blake2b_calls,
+        })
+    }
+}
+
+/// Fold the steps of proof over the stack the verifiers keep: member makes
+/// the node of a member or neighbour leaf, sibling the node over a node and
+/// a sibling hash, merge the node over two nodes
+/// fails unless a single node is left, as the verifiers do
+fn fold_stack<T>(
+    proof: &MultiProof,
+    member: impl Fn(bool) -> T,
+    sibling: impl Fn(T) -> T,
+    merge: impl Fn(T, T) -> T,
+) -> Result<T> {
+    let mut stack = Vec::new();
+    for op in proof.ops() {
+        let node = match op {
+            ProofOp::Leaf => member(true),
+            ProofOp::Neighbour(_) => member(false),
+            ProofOp::Sibling(_) => sibling(stack.pop().ok_or(Error::CorruptedStack)?),
+            ProofOp::Merge => {
+                let right = stack.pop().ok_or(Error::CorruptedStack)?;
+                let left = stack.pop().ok_or(Error::CorruptedStack)?;
+                merge(left, right)
+            }
+        };
+        stack.push(node);
+    }
+    match (stack.pop(), stack.is_empty()) {
+        (Some(root), true) => Ok(root),
+        _ => Err(Error::CorruptedStack),
+    }
+}
+
+/// Proof of several members, as returned by `multi_proof`
+impl ProofBudget for MultiProof {
+    fn kind(&self) -> ProofKind {
+        ProofKind::Members
+    }
+    fn budget(&self) -> Result<Budget> {
+        fold_stack(self, |_| (), |_| (), |_, _| ())?;
+        // each member and its leaf, then one hash per other step, the leaf
+        // of each neighbour included
+        let leaves = self.leaves_count();
+        Ok(Budget {
+            proofs: 1,
+            cbor_size: cbor_bytes(self.to_bytes().len()),
+            blake2b_calls: 2 * leaves + (self.ops().len() - leaves),
+        })
+    }
+}
+
+/// Proof of several insertions or removals, as returned by
+/// `modify_root_multi_proof`
+#[derive(Debug, Clone, Copy)]
+pub struct ModifyMultiProof<'a>(pub &'a MultiProof);
+
+impl<'a> ProofBudget for ModifyMultiProof<'a> {
+    fn kind(&self) -> ProofKind {
+        ProofKind::ModifyMembers
+    }
+    fn budget(&self) -> Result<Budget> {
+        let members = self.0.budget()?;
+        // the tree without the members only hashes nodes where both sides
+        // still hold leaves, track which nodes are empty along with the
+        // hashes below them
+        let (_, without) = fold_stack(
+            self.0,
+            |member| (member, 0),
+            |(empty, without)| (false, without + usize::from(!empty)),
+            |(left, left_without), (right, right_without)| {
+                let merged = usize::from(!left && !right);
+                (left && right, left_without + right_without + merged)
+            },
+        )?;
+        Ok(Budget {
+            blake2b_calls: members.blake2b_calls + without,
+            ..members
+        })
+    }
+}
+
+/// Budgets of several proofs, per kind
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BudgetReport {
+    kinds: BTreeMap<ProofKind, Budget>,
+}
+
+impl BudgetReport {
+    /// Count proof in the budget of its kind, a malformed proof is not
+    /// counted
+    pub fn add(&mut self, proof: &dyn ProofBudget) -> Result<()> {
+        let budget = proof.budget()?;
+        *self.kinds.entry(proof.kind()).or_default() += budget;
+        Ok(())
+    }
+
+    /// Budget of the proofs of kind
+    pub fn get(&self, kind: ProofKind) -> Budget {
+        self.kinds.get(&kind).copied().unwrap_or_default()
+    }
+
+    /// Budget of every kind counted, in kind order
+    pub fn kinds(&self) -> impl Iterator<Item = (ProofKind, Budget)> + '_ {
+        self.kinds.iter().map(|(kind, budget)| (*kind, *budget))
+    }
+
+    /// Budget of all the proofs counted
+    pub fn total(&self) -> Budget {
+        self.kinds.values().copied().sum()
+    }
+}
@@ -0,0 +1,159 @@
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    merge::{merge, MergeValue},
+    traits::{Hasher, StoreReadOps, StoreWriteOps, Value, WriteBatch},
+    tree::{
+        check_empty_store, is_bound_key, BranchKey, BranchNode, Child, ChildKey, SparseMerkleTree,
+        SubtreeCounts, TreeCounts, MERKLE_LOWER_BOUND, MERKLE_UPPER_BOUND,
+    },
+};
+
+/// Subtrees with fewer leaves are built on the current thread
+#[cfg(feature = "rayon")]
+const PARALLEL_THRESHOLD: usize = 1024;
+
+type Branches = Vec<(BranchKey, BranchNode)>;
+
+/// Sort the members, reject the reserved and repeated keys and add the bound
+/// leaves
+fn sorted_leaves<V: Value>(mut members: Vec<(H256, V)>) -> Result<Vec<(H256, V)>> {
+    members.sort_unstable_by_key(|(key, _)| *key);
+    if let Some((key, _)) = members.iter().find(|(key, _)| is_bound_key(key)) {
+        return Err(Error::ReservedKey(*key));
+    }
+    if let Some(pair) = members.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(Error::LeafExists(pair[0].0));
+    }
+
+    let mut leaves = Vec::with_capacity(members.len() + 2);
+    leaves.push((MERKLE_LOWER_BOUND, V::zero()));
+    leaves.extend(members);
+    leaves.push((MERKLE_UPPER_BOUND, V::max()));
+    Ok(leaves)
+}
+
+/// Branch joining left and right at height, above the leaf first
+fn branch<H: Hasher + Default>(
+    first: &H256,
+    height: u8,
+    left: Child,
+    right: Child,
+    branches: &mut Branches,
+) -> Child {
+    let branch_key = BranchKey::new(height, first.parent_path_by_height(height));
+    let branch = BranchNode::new(left, right);
+    let merge_value = merge::<H>(&branch.left.0, &branch.right.0);
+    let counts = branch.counts();
+    branches.push((branch_key.clone(), branch));
+    ((merge_value, ChildKey::Branch(branch_key)), counts)
+}
+
+/// Build the subtree holding leaves, sorted by key and hashed
+///
+/// The subtree forks at the highest bit that differs between its keys, the
+/// same branch every sequence of updates ends up with.
+fn build_subtree<H: Hasher + Default>(leaves: &[(H256, H256)], branches: &mut Branches) -> Child {
+    let (first, last) = (&leaves[0].0, &leaves[leaves.len() - 1].0);
+    let height = match first.highest_differing_bit(last) {
+        Some(height) => height,
+        None => {
+            let leaf = (MergeValue::from_h256(leaves[0].1), ChildKey::Leaf(*first));
+            return (leaf, SubtreeCounts::leaf(first));
+        }
+    };
+    let split = leaves.partition_point(|(key, _)| !key.get_bit(height));
+    let left = build_subtree::<H>(&leaves[..split], branches);
+    let right = build_subtree::<H>(&leaves[split..], branches);
+    branch::<H>(first, height, left, right, branches)
+}
+
+/// Build the subtree holding leaves, the subtrees on both sides of large
+/// forks being built in parallel
+#[cfg(feature = "rayon")]
+fn par_build_subtree<H: Hasher + Default>(leaves: &[(H256, H256)]) -> (Child, Branches) {
+    let mut branches = Vec::new();
+    let (first, last) = (&leaves[0].0, &leaves[leaves.len() - 1].0);
+    let height = match first.highest_differing_bit(last) {
+        Some(height) if leaves.len() >= PARALLEL_THRESHOLD => height,
+        _ => {
+            let node = build_subtree::<H>(leaves, &mut branches);
+            return (node, branches);
+        }
+    };
+    let split = leaves.partition_point(|(key, _)| !key.get_bit(height));
+    let ((left, left_branches), (right, right_branches)) = rayon::join(
+        || par_build_subtree::<H>(&leaves[..split]),
+        || par_build_subtree::<H>(&leaves[split..]),
+    );
+    branches.extend(left_branches);
+    branches.extend(right_branches);
+    let node = branch::<H>(first, height, left, right, &mut branches);
+    (node, branches)
+}
+
+impl<H: Hasher + Default, V: Value, S: StoreReadOps<V> + StoreWriteOps<V>>
+    SparseMerkleTree<H, V, S>
+{
+    /// Build a tree holding members in an empty store
+    ///
+    /// The tree is hashed bottom up from the sorted members and written as a
+    /// single batch, giving the same root and nodes as inserting every member
+    /// with `update`. A store already holding a tree is rejected.
+    pub fn from_leaves(store: S, members: Vec<(H256, V)>) -> Result<Self> {
+        check_empty_store::<H, V, _>(&store)?;
+        let leaves = sorted_leaves(members)?;
+        let hashed = leaves
+            .iter()
+            .map(|(key, value)| (*key, value.to_h256::<H>()))
+            .collect::<Vec<_>>();
+        let mut branches = Vec::with_capacity(leaves.len() - 1);
+        let ((root, _), counts) = build_subtree::<H>(&hashed, &mut branches);
+        Self::write_built(store, root.hash(), counts, leaves, branches)
+    }
+
+    fn write_built(
+        mut store: S,
+        root: H256,
+        root_counts: SubtreeCounts,
+        leaves: Vec<(H256, V)>,
+        branches: Branches,
+    ) -> Result<Self> {
+        let counts = TreeCounts::from(root_counts);
+        let mut batch = WriteBatch::default();
+        batch
+            .leaves
+            .extend(leaves.into_iter().map(|(key, value)| (key, Some(value))));
+        batch.branches.extend(
+            branches
+                .into_iter()
+                .map(|(branch_key, branch)| (branch_key, Some(branch))),
+        );
+        store.write_batch(batch)?;
+        store.commit_counts(&root, &counts)?;
+        store.commit_root(&root)?;
+        Ok(SparseMerkleTree::with_counts(root, store, Some(counts)))
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<H: Hasher + Default, V: Value + Send + Sync, S: StoreReadOps<V> + StoreWriteOps<V>>
+    SparseMerkleTree<H, V, S>
+{
+    /// Build a tree holding members in an empty store, like `from_leaves`,
+    /// hashing the leaves and the disjoint subtrees in parallel
+    pub fn par_from_leaves(store: S, mut members: Vec<(H256, V)>) -> Result<Self> {
+        check_empty_store::<H, V, _>(&store)?;
+        members.par_sort_unstable_by_key(|(key, _)| *key);
+        let leaves = sorted_leaves(members)?;
+        let hashed = leaves
+            .par_iter()
+            .map(|(key, value)| (*key, value.to_h256::<H>()))
+            .collect::<Vec<_>>();
+        let (((root, _), counts), branches) = par_build_subtree::<H>(&hashed);
+        Self::write_built(store, root.hash(), counts, leaves, branches)
+    }
+}
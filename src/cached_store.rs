@@ -0,0 +1,359 @@
+use core::num::NonZeroUsize;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use lru::LruCache;
+
+use crate::{
+    error::Result,
+    h256::H256,
+    instrumented_store::StoreMetrics,
+    traits::{StoreReadOps, StoreWriteOps, WriteBatch},
+    tree::{BranchKey, BranchNode, TreeCounts},
+};
+
+/// Number of branches cached by `CachedStore::default`
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// Number of levels below the root whose branches are never evicted
+///
+/// A branch of height h has its key masked to the bits above h, so at most
+/// 2^8 - 1 branches sit in the top 8 levels, whatever the keys.
+pub const DEFAULT_PINNED_LEVELS: u8 = 8;
+
+/// When writes reach the wrapped store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Every write is forwarded to the wrapped store right away
+    WriteThrough,
+    /// Writes are held until `CachedStore::flush`
+    WriteBack,
+}
+
+/// Store wrapper caching the most recently used branches
+///
+/// Every update and proof reads the branches near the root, so a small LRU
+/// saves most reads of a slow backend. Absent branches are cached as well.
+/// The branches of the top levels are pinned outside of the LRU, so a scan
+/// over many deep branches never evicts them.
+/// With `WritePolicy::WriteBack` writes and commits are held back and reach
+/// the wrapped store as a single batch on `flush`, followed by the commit of
+/// the latest root.
+#[derive(Debug)]
+pub struct CachedStore<V, S> {
+    store: S,
+    cache: Mutex<BranchCache>,
+    policy: WritePolicy,
+    // writes held back by the write back policy
+    dirty: WriteBatch<V>,
+    // latest root committed since the last flush, with its counts
+    dirty_root: Option<H256>,
+    dirty_counts: Option<(H256, TreeCounts)>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl<V, S: Default> Default for CachedStore<V, S> {
+    fn default() -> Self {
+        CachedStore::new(S::default(), DEFAULT_CACHE_SIZE, WritePolicy::WriteThrough)
+    }
+}
+
+impl<V, S> CachedStore<V, S> {
+    /// Wrap store, caching at most capacity branches
+    pub fn new(store: S, capacity: usize, policy: WritePolicy) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        CachedStore {
+            store,
+            cache: Mutex::new(BranchCache {
+                lru: LruCache::new(capacity),
+                pinned: HashMap::new(),
+                pinned_levels: DEFAULT_PINNED_LEVELS,
+            }),
+            policy,
+            dirty: WriteBatch::default(),
+            dirty_root: None,
+            dirty_counts: None,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the wrapped store, which misses the writes not flushed yet
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Write policy of the cache
+    pub fn policy(&self) -> WritePolicy {
+        self.policy
+    }
+
+    /// Number of reads answered without the wrapped store
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of reads forwarded to the wrapped store
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Reset the hit and miss counters
+    pub fn reset_counters(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+
+    /// Drop every cached branch, writes held back are kept
+    pub fn clear_cache(&self) {
+        self.cache().clear();
+    }
+
+    /// Number of levels below the root whose branches are pinned
+    pub fn pinned_levels(&self) -> u8 {
+        self.cache().pinned_levels
+    }
+
+    /// Pin the branches of levels levels below the root, 0 leaves every
+    /// branch to the LRU
+    pub fn set_pinned_levels(&mut self, levels: u8) {
+        let mut cache = self.cache();
+        cache.pinned_levels = levels;
+        let unpinned = cache
+            .pinned
+            .keys()
+            .filter(|branch_key| !cache.is_pinned(branch_key))
+            .cloned()
+            .collect::<Vec<_>>();
+        for branch_key in unpinned {
+            if let Some(branch) = cache.pinned.remove(&branch_key) {
+                cache.lru.put(branch_key, branch);
+            }
+        }
+    }
+
+    /// Number of branches cached, pinned ones included
+    pub fn cached_len(&self) -> usize {
+        let cache = self.cache();
+        cache.lru.len() + cache.pinned.len()
+    }
+
+    /// Check whether writes are waiting for a flush
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty() || self.dirty_root.is_some()
+    }
+
+    fn cache(&self) -> MutexGuard<'_, BranchCache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// LRU of branches, besides the pinned branches near the root
+#[derive(Debug)]
+struct BranchCache {
+    lru: LruCache<BranchKey, Option<BranchNode>>,
+    pinned: HashMap<BranchKey, Option<BranchNode>>,
+    pinned_levels: u8,
+}
+
+impl BranchCache {
+    fn is_pinned(&self, branch_key: &BranchKey) -> bool {
+        u16::from(branch_key.height) + u16::from(self.pinned_levels) >= 256
+    }
+
+    fn get(&mut self, branch_key: &BranchKey) -> Option<&Option<BranchNode>> {
+        if self.is_pinned(branch_key) {
+            self.pinned.get(branch_key)
+        } else {
+            self.lru.get(branch_key)
+        }
+    }
+
+    fn put(&mut self, branch_key: BranchKey, branch: Option<BranchNode>) {
+        if self.is_pinned(&branch_key) {
+            self.pinned.insert(branch_key, branch);
+        } else {
+            self.lru.put(branch_key, branch);
+        }
+    }
+
+    fn pop(&mut self, branch_key: &BranchKey) {
+        if self.is_pinned(branch_key) {
+            self.pinned.remove(branch_key);
+        } else {
+            self.lru.pop(branch_key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.lru.clear();
+        self.pinned.clear();
+    }
+}
+
+impl<V: Clone, S: StoreWriteOps<V>> CachedStore<V, S> {
+    /// Write the held back writes to the wrapped store as one batch, then
+    /// commit the latest root
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.dirty.is_empty() {
+            self.store.write_batch(self.dirty.clone())?;
+            self.dirty = WriteBatch::default();
+        }
+        if let Some((root, counts)) = self.dirty_counts.take() {
+            self.store.commit_counts(&root, &counts)?;
+        }
+        if let Some(root) = self.dirty_root.take() {
+            self.store.commit_root(&root)?;
+        }
+        Ok(())
+    }
+
+    /// Flush and retake the wrapped store
+    pub fn into_inner(mut self) -> Result<S> {
+        self.flush()?;
+        Ok(self.store)
+    }
+}
+
+impl<V: Clone, S: StoreReadOps<V>> StoreReadOps<V> for CachedStore<V, S> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        if let Some(branch) = self.dirty.branches.get(branch_key) {
+            self.hit();
+            return Ok(branch.clone());
+        }
+        if let Some(branch) = self.cache().get(branch_key) {
+            self.hit();
+            return Ok(branch.clone());
+        }
+        self.miss();
+        let branch = self.store.get_branch(branch_key)?;
+        self.cache().put(branch_key.clone(), branch.clone());
+        Ok(branch)
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        match self.dirty.leaves.get(leaf_key) {
+            Some(leaf) => Ok(leaf.clone()),
+            None => self.store.get_leaf(leaf_key),
+        }
+    }
+    fn get_counts(&self, root: &H256) -> Result<Option<TreeCounts>> {
+        match &self.dirty_counts {
+            Some((counted, counts)) if counted == root => Ok(Some(counts.clone())),
+            _ => self.store.get_counts(root),
+        }
+    }
+    fn store_metrics(&self) -> Option<StoreMetrics> {
+        self.store.store_metrics()
+    }
+}
+
+impl<V: Clone, S: StoreWriteOps<V>> StoreWriteOps<V> for CachedStore<V, S> {
+    fn insert_branch(&mut self, branch_key: BranchKey, branch: BranchNode) -> Result<()> {
+        match self.policy {
+            WritePolicy::WriteThrough => {
+                let written = self.store.insert_branch(branch_key.clone(), branch.clone());
+                match written {
+                    Ok(()) => self.cache().put(branch_key, Some(branch)),
+                    Err(_) => self.cache().pop(&branch_key),
+                }
+                written?;
+            }
+            WritePolicy::WriteBack => {
+                self.cache().pop(&branch_key);
+                self.dirty.insert_branch(branch_key, branch)?;
+            }
+        }
+        Ok(())
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<()> {
+        match self.policy {
+            WritePolicy::WriteThrough => self.store.insert_leaf(leaf_key, leaf),
+            WritePolicy::WriteBack => self.dirty.insert_leaf(leaf_key, leaf),
+        }
+    }
+    fn remove_branch(&mut self, branch_key: &BranchKey) -> Result<()> {
+        match self.policy {
+            WritePolicy::WriteThrough => {
+                let removed = self.store.remove_branch(branch_key);
+                match removed {
+                    Ok(()) => self.cache().put(branch_key.clone(), None),
+                    Err(_) => self.cache().pop(branch_key),
+                }
+                removed?;
+            }
+            WritePolicy::WriteBack => {
+                self.cache().pop(branch_key);
+                self.dirty.remove_branch(branch_key)?;
+            }
+        }
+        Ok(())
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<()> {
+        match self.policy {
+            WritePolicy::WriteThrough => self.store.remove_leaf(leaf_key),
+            WritePolicy::WriteBack => self.dirty.remove_leaf(leaf_key),
+        }
+    }
+    fn write_batch(&mut self, batch: WriteBatch<V>) -> Result<()> {
+        match self.policy {
+            WritePolicy::WriteThrough => {
+                // the cache only learns about the batch once it is written, a
+                // failing batch may have been written in part so every
+                // branch it touches is forgotten
+                let branches = batch
+                    .branches
+                    .iter()
+                    .map(|(branch_key, branch)| (branch_key.clone(), branch.clone()))
+                    .collect::<Vec<_>>();
+                let written = self.store.write_batch(batch);
+                let mut cache = self.cache();
+                for (branch_key, branch) in branches {
+                    match written {
+                        Ok(()) => cache.put(branch_key, branch),
+                        Err(_) => cache.pop(&branch_key),
+                    }
+                }
+                drop(cache);
+                written?;
+            }
+            WritePolicy::WriteBack => {
+                let mut cache = self.cache();
+                for branch_key in batch.branches.keys() {
+                    cache.pop(branch_key);
+                }
+                drop(cache);
+                self.dirty.branches.extend(batch.branches);
+                self.dirty.leaves.extend(batch.leaves);
+            }
+        }
+        Ok(())
+    }
+    fn commit_counts(&mut self, root: &H256, counts: &TreeCounts) -> Result<()> {
+        match self.policy {
+            WritePolicy::WriteThrough => self.store.commit_counts(root, counts),
+            WritePolicy::WriteBack => {
+                self.dirty_counts = Some((*root, counts.clone()));
+                Ok(())
+            }
+        }
+    }
+    fn commit_root(&mut self, root: &H256) -> Result<()> {
+        match self.policy {
+            WritePolicy::WriteThrough => self.store.commit_root(root),
+            WritePolicy::WriteBack => {
+                self.dirty_root = Some(*root);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    merge::MergeValue,
+    tree::{BranchKey, BranchNode, Child, ChildKey, SubtreeCounts, TreeCounts},
+};
+
+/// Encoded size of a branch key: the height and the node key
+pub const BRANCH_KEY_SIZE: usize = 33;
+
+/// Encoded size of a branch: for each side the merge value, a tag byte,
+/// the height of a branch child, the child key and the counts of its members
+pub const BRANCH_NODE_SIZE: usize = 2 * (66 + SUBTREE_COUNTS_SIZE);
+
+/// Encoded size of the counts of the members below a child
+const SUBTREE_COUNTS_SIZE: usize = 3 * 8;
+
+/// Encoded size of tree counts
+pub const COUNTS_SIZE: usize = 4 * 8;
+
+const LEAF_TAG: u8 = 0;
+const BRANCH_TAG: u8 = 1;
+
+/// Trait for values persisted as bytes by the stores writing to disk
+pub trait ValueCodec: Sized {
+    fn encode(&self) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+impl ValueCodec for H256 {
+    fn encode(&self) -> Vec<u8> {
+        self.as_slice().to_vec()
+    }
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        H256::try_from(bytes)
+    }
+}
+
+fn check_length(bytes: &[u8], expected: usize) -> Result<()> {
+    if bytes.len() == expected {
+        Ok(())
+    } else {
+        Err(Error::InvalidLength {
+            expected,
+            actual: bytes.len(),
+        })
+    }
+}
+
+/// Encode a branch key, encoded keys sort like the keys themselves
+pub fn encode_branch_key(branch_key: &BranchKey) -> [u8; BRANCH_KEY_SIZE] {
+    let mut bytes = [0u8; BRANCH_KEY_SIZE];
+    bytes[0] = branch_key.height;
+    bytes[1..].copy_from_slice(branch_key.node_key.as_slice());
+    bytes
+}
+
+pub fn decode_branch_key(bytes: &[u8]) -> Result<BranchKey> {
+    check_length(bytes, BRANCH_KEY_SIZE)?;
+    Ok(BranchKey::new(bytes[0], H256::try_from(&bytes[1..])?))
+}
+
+/// Encode numbers as big endian u64 into bytes, 8 bytes each
+fn encode_numbers(numbers: &[usize], bytes: &mut [u8]) {
+    for (number, chunk) in numbers.iter().zip(bytes.chunks_exact_mut(8)) {
+        chunk.copy_from_slice(&(*number as u64).to_be_bytes());
+    }
+}
+
+fn decode_numbers<const N: usize>(bytes: &[u8]) -> [usize; N] {
+    let mut numbers = [0; N];
+    for (number, chunk) in numbers.iter_mut().zip(bytes.chunks_exact(8)) {
+        *number = u64::from_be_bytes(chunk.try_into().expect("8 bytes")) as usize;
+    }
+    numbers
+}
+
+fn encode_child(child: &(MergeValue, ChildKey), counts: &SubtreeCounts, bytes: &mut [u8]) {
+    bytes[..32].copy_from_slice(child.0.hash().as_slice());
+    match &child.1 {
+        ChildKey::Leaf(key) => {
+            bytes[32] = LEAF_TAG;
+            bytes[34..66].copy_from_slice(key.as_slice());
+        }
+        ChildKey::Branch(branch_key) => {
+            bytes[32] = BRANCH_TAG;
+            bytes[33..66].copy_from_slice(&encode_branch_key(branch_key));
+        }
+    }
+    encode_numbers(
+        &[
+            counts.members,
+            counts.total_path_length,
+            counts.max_path_length,
+        ],
+        &mut bytes[66..],
+    );
+}
+
+fn decode_child(bytes: &[u8]) -> Result<Child> {
+    let value = MergeValue::from_h256(H256::try_from(&bytes[..32])?);
+    let child = match bytes[32] {
+        LEAF_TAG => ChildKey::Leaf(H256::try_from(&bytes[34..66])?),
+        BRANCH_TAG => ChildKey::Branch(decode_branch_key(&bytes[33..66])?),
+        tag => return Err(Error::InvalidCode(tag)),
+    };
+    let [members, total_path_length, max_path_length] = decode_numbers(&bytes[66..]);
+    let counts = SubtreeCounts {
+        members,
+        total_path_length,
+        max_path_length,
+    };
+    Ok(((value, child), counts))
+}
+
+pub fn encode_branch(branch: &BranchNode) -> [u8; BRANCH_NODE_SIZE] {
+    let mut bytes = [0u8; BRANCH_NODE_SIZE];
+    let (left, right) = bytes.split_at_mut(BRANCH_NODE_SIZE / 2);
+    encode_child(&branch.left, &branch.left_counts, left);
+    encode_child(&branch.right, &branch.right_counts, right);
+    bytes
+}
+
+pub fn decode_branch(bytes: &[u8]) -> Result<BranchNode> {
+    check_length(bytes, BRANCH_NODE_SIZE)?;
+    let (left, right) = bytes.split_at(BRANCH_NODE_SIZE / 2);
+    Ok(BranchNode::new(decode_child(left)?, decode_child(right)?))
+}
+
+/// Encode tree counts: the member and branch counts, then the sum and the
+/// longest of the path lengths, all as big endian u64
+pub fn encode_counts(counts: &TreeCounts) -> [u8; COUNTS_SIZE] {
+    let mut bytes = [0u8; COUNTS_SIZE];
+    encode_numbers(
+        &[
+            counts.leaves,
+            counts.branches,
+            counts.total_path_length,
+            counts.max_path_length,
+        ],
+        &mut bytes,
+    );
+    bytes
+}
+
+pub fn decode_counts(bytes: &[u8]) -> Result<TreeCounts> {
+    check_length(bytes, COUNTS_SIZE)?;
+    let [leaves, branches, total_path_length, max_path_length] = decode_numbers(bytes);
+    Ok(TreeCounts {
+        leaves,
+        branches,
+        total_path_length,
+        max_path_length,
+    })
+}
@@ -3,20 +3,30 @@ use std::collections::HashMap;
 use crate::{
     error::Error,
     h256::H256,
-    traits::{StoreReadOps, StoreWriteOps},
-    tree::{BranchKey, BranchNode},
+    traits::{StoreKeysOps, StoreReadOps, StoreWriteOps},
+    tree::{BranchKey, BranchNode, TreeCounts},
 };
 
 #[derive(Debug, Clone, Default)]
 pub struct DefaultStore<V> {
     branches_map: HashMap<BranchKey, BranchNode>,
     leaves_map: HashMap<H256, V>,
+    counts: Option<(H256, TreeCounts)>,
 }
 
 impl<V> DefaultStore<V> {
     pub fn clear(&mut self) {
         self.branches_map.clear();
         self.leaves_map.clear();
+        self.counts = None;
+    }
+
+    pub fn branches_map(&self) -> &HashMap<BranchKey, BranchNode> {
+        &self.branches_map
+    }
+
+    pub fn leaves_map(&self) -> &HashMap<H256, V> {
+        &self.leaves_map
     }
 }
 
@@ -27,11 +37,12 @@ impl<V: Clone> StoreReadOps<V> for DefaultStore<V> {
     fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>, Error> {
         Ok(self.leaves_map.get(leaf_key).cloned())
     }
-    fn branches_map(&self) -> &HashMap<BranchKey, BranchNode> {
-        &self.branches_map
-    }
-    fn leaves_map(&self) -> &HashMap<H256, V> {
-        &self.leaves_map
+    fn get_counts(&self, root: &H256) -> Result<Option<TreeCounts>, Error> {
+        Ok(self
+            .counts
+            .as_ref()
+            .filter(|(counted, _)| counted == root)
+            .map(|(_, counts)| counts.clone()))
     }
 }
 
@@ -52,4 +63,17 @@ impl<V> StoreWriteOps<V> for DefaultStore<V> {
         self.leaves_map.remove(leaf_key);
         Ok(())
     }
+    fn commit_counts(&mut self, root: &H256, counts: &TreeCounts) -> Result<(), Error> {
+        self.counts = Some((*root, counts.clone()));
+        Ok(())
+    }
+}
+
+impl<V> StoreKeysOps for DefaultStore<V> {
+    fn branch_keys(&self) -> Result<Vec<BranchKey>, Error> {
+        Ok(self.branches_map.keys().cloned().collect())
+    }
+    fn leaf_keys(&self) -> Result<Vec<H256>, Error> {
+        Ok(self.leaves_map.keys().copied().collect())
+    }
 }
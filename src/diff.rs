@@ -0,0 +1,145 @@
+use core::cmp::Ordering;
+
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    merge::MergeValue,
+    traits::StoreReadOps,
+    tree::{is_bound_key, BranchKey, ChildKey, SparseMerkleTree},
+};
+
+/// Leaves added and removed between two states of a tree, in key order
+///
+/// Leaves are not updated in place, so a key holding different values in the
+/// two states is found in both lists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diff<V> {
+    /// Leaves only found in the second state
+    pub added: Vec<(H256, V)>,
+    /// Leaves only found in the first state
+    pub removed: Vec<(H256, V)>,
+}
+
+impl<V> Diff<V> {
+    /// Check whether both states hold the same leaves
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Children of one state left to compare, the next smallest one on top
+struct Cursor<'a, S> {
+    store: &'a S,
+    stack: Vec<(MergeValue, ChildKey)>,
+}
+
+impl<'a, S> Cursor<'a, S> {
+    fn new(store: &'a S, root: &H256) -> Self {
+        let stack = if root.is_zero() {
+            Vec::new()
+        } else {
+            vec![(
+                MergeValue::from_h256(*root),
+                ChildKey::Branch(BranchKey::root()),
+            )]
+        };
+        Cursor { store, stack }
+    }
+
+    /// Pop the smallest child, taking it if it is a leaf and visiting its
+    /// children if it is a branch
+    fn advance<V>(&mut self, leaves: &mut Vec<(H256, V)>) -> Result<()>
+    where
+        S: StoreReadOps<V>,
+    {
+        match self.stack.pop() {
+            Some((_, ChildKey::Leaf(key))) if !is_bound_key(&key) => {
+                let leaf = self.store.get_leaf(&key)?.ok_or(Error::MissingLeaf(key))?;
+                leaves.push((key, leaf));
+            }
+            Some((_, ChildKey::Branch(branch_key))) => {
+                let branch = self
+                    .store
+                    .get_branch(&branch_key)?
+                    .ok_or(Error::MissingBranch(branch_key.height, branch_key.node_key))?;
+                self.stack.push(branch.right);
+                self.stack.push(branch.left);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn key_range(child: &ChildKey) -> (H256, H256) {
+    match child {
+        ChildKey::Leaf(key) => (*key, *key),
+        ChildKey::Branch(branch_key) => branch_key.key_range(),
+    }
+}
+
+/// Side to advance: the child coming first if their ranges are disjoint,
+/// otherwise the one whose range holds the other, both if they are the same
+fn next_side(x: &ChildKey, y: &ChildKey) -> Ordering {
+    let ((x_low, x_high), (y_low, y_high)) = (key_range(x), key_range(y));
+    if x_high < y_low {
+        Ordering::Less
+    } else if y_high < x_low {
+        Ordering::Greater
+    } else if (x_low, x_high) == (y_low, y_high) {
+        Ordering::Equal
+    } else if x_low <= y_low && y_high <= x_high {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+/// Compare the state of store_a at root_a with the state of store_b at root_b
+///
+/// Both states are walked side by side and children holding the same merge
+/// value at the same position are skipped, so only the subtrees that differ
+/// are visited.
+pub fn diff<V, A: StoreReadOps<V>, B: StoreReadOps<V>>(
+    store_a: &A,
+    root_a: &H256,
+    store_b: &B,
+    root_b: &H256,
+) -> Result<Diff<V>> {
+    let mut diff = Diff {
+        added: Vec::new(),
+        removed: Vec::new(),
+    };
+    let mut a = Cursor::new(store_a, root_a);
+    let mut b = Cursor::new(store_b, root_b);
+
+    loop {
+        let side = match (a.stack.last(), b.stack.last()) {
+            (None, None) => break,
+            (Some(x), Some(y)) if x == y => {
+                a.stack.pop();
+                b.stack.pop();
+                continue;
+            }
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((_, x)), Some((_, y))) => next_side(x, y),
+        };
+        match side {
+            Ordering::Less => a.advance(&mut diff.removed)?,
+            Ordering::Greater => b.advance(&mut diff.added)?,
+            Ordering::Equal => {
+                a.advance(&mut diff.removed)?;
+                b.advance(&mut diff.added)?;
+            }
+        }
+    }
+    Ok(diff)
+}
+
+impl<H, V, S: StoreReadOps<V>> SparseMerkleTree<H, V, S> {
+    /// Leaves to add and remove to turn this tree into other
+    pub fn diff<T: StoreReadOps<V>>(&self, other: &SparseMerkleTree<H, V, T>) -> Result<Diff<V>> {
+        diff(self.store(), self.root(), other.store(), other.root())
+    }
+}
@@ -15,6 +15,17 @@ pub enum Error {
     NonSiblings,
     InvalidCode(u8),
     NonMergableRange,
+    InvalidLength { expected: usize, actual: usize },
+    InvalidHex(String),
+    ReservedKey(H256),
+    LeafExists(H256),
+    LeafNotFound(H256),
+    MissingRoot(H256),
+    StaleBatch { base_root: H256, root: H256 },
+    NonEmptyStore(H256),
+    MisplacedLeaf(H256),
+    MissingNeighbour(H256),
+    LeafNotKey(H256),
 }
 
 impl core::fmt::Display for Error {
@@ -61,10 +72,50 @@ impl core::fmt::Display for Error {
             Error::NonMergableRange => {
                 write!(f, "Ranges can not be merged")?;
             }
+            Error::InvalidLength { expected, actual } => {
+                write!(
+                    f,
+                    "Invalid length, expected {} bytes actual {}",
+                    expected, actual
+                )?;
+            }
+            Error::InvalidHex(err_msg) => {
+                write!(f, "Invalid hex string: {}", err_msg)?;
+            }
+            Error::ReservedKey(key) => {
+                write!(f, "Key {:?} is reserved for the tree bounds", key)?;
+            }
+            Error::LeafExists(key) => {
+                write!(f, "Leaf {:?} is already in the tree", key)?;
+            }
+            Error::LeafNotFound(key) => {
+                write!(f, "Leaf {:?} is not in the tree", key)?;
+            }
+            Error::MissingRoot(root) => {
+                write!(f, "Root {:?} is not retained by the store", root)?;
+            }
+            Error::StaleBatch { base_root, root } => {
+                write!(
+                    f,
+                    "Batch staged over root {:?} applied to the tree at {:?}",
+                    base_root, root
+                )?;
+            }
+            Error::NonEmptyStore(root) => {
+                write!(f, "Store already holds the tree at root {:?}", root)?;
+            }
+            Error::MisplacedLeaf(key) => {
+                write!(f, "Leaf {:?} is proven out of key order", key)?;
+            }
+            Error::MissingNeighbour(key) => {
+                write!(f, "Member {:?} is proven without its neighbours", key)?;
+            }
+            Error::LeafNotKey(key) => {
+                write!(f, "Leaf {:?} does not hold its key as value", key)?;
+            }
         }
         Ok(())
     }
 }
 
-#[cfg(feature = "std")]
 impl std::error::Error for Error {}
@@ -1,6 +1,10 @@
-use std::{cmp::Ordering, fmt::Debug};
+use std::{
+    cmp::Ordering,
+    fmt::{Debug, Display, LowerHex},
+    str::FromStr,
+};
 
-use bitvec::{array::BitArray, order::Msb0, vec::BitVec};
+use crate::error::Error;
 
 /// Represent 256 bits
 #[derive(Eq, PartialEq, Default, Hash, Clone, Copy)]
@@ -9,6 +13,8 @@ pub struct H256([u8; 32]);
 const ZERO: H256 = H256([0u8; 32]);
 const MAX: H256 = H256([255u8; 32]);
 const BYTE_SIZE: u8 = 8;
+const WORD_SIZE: u32 = 64;
+const WORDS: usize = 4;
 pub const LEAF_BYTE: u8 = 13;
 
 impl Debug for H256 {
@@ -17,6 +23,21 @@ impl Debug for H256 {
     }
 }
 
+impl Display for H256 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl LowerHex for H256 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if f.alternate() {
+            f.write_str("0x")?;
+        }
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
 impl H256 {
     pub const fn zero() -> Self {
         ZERO
@@ -69,33 +90,87 @@ impl H256 {
     /// Treat H256 as a path in a tree
     /// fork height is the number of common bits(from heigher to lower: 255..=0) of two H256
     pub fn fork_height(&self, key: &H256) -> u8 {
-        for h in (0..=core::u8::MAX).rev() {
-            if self.get_bit(h) != key.get_bit(h) {
-                return h;
+        self.highest_differing_bit(key).unwrap_or(0)
+    }
+
+    /// Return the height of the highest bit that differs between self and key,
+    /// or None if both are equal
+    #[inline]
+    pub fn highest_differing_bit(&self, key: &H256) -> Option<u8> {
+        let (lhs, rhs) = (self.words(), key.words());
+        for i in 0..WORDS {
+            let diff = lhs[i] ^ rhs[i];
+            if diff != 0 {
+                let bit = (WORDS - i) as u32 * WORD_SIZE - 1 - diff.leading_zeros();
+                return Some(bit as u8);
             }
         }
-        0
+        None
     }
 
     /// Treat H256 as a path in a tree
     /// return parent_path of self
+    #[inline]
     pub fn parent_path_by_height(&self, height: u8) -> Self {
-        if height == core::u8::MAX {
-            H256::zero()
-        } else {
-            let height = height + 1;
-            let mut x = *self;
-
-            let x: &mut BitVec<_, Msb0> = &mut BitVec::from_slice(x.as_mut_slice());
+        self.shift_right(u32::from(height) + 1)
+    }
 
-            x.shift_right(height.into());
+    /// Shift the 256 bits right by n, filling with zeros from the highest bit
+    #[inline]
+    pub fn shift_right(&self, n: u32) -> Self {
+        if n >= 256 {
+            return ZERO;
+        }
+        let words = self.words();
+        let word_shift = (n / WORD_SIZE) as usize;
+        let bit_shift = n % WORD_SIZE;
+        let mut shifted = [0u64; WORDS];
+        for (src, word) in shifted.iter_mut().skip(word_shift).enumerate() {
+            *word = words[src] >> bit_shift;
+            if bit_shift > 0 && src > 0 {
+                *word |= words[src - 1] << (WORD_SIZE - bit_shift);
+            }
+        }
+        H256::from_words(shifted)
+    }
 
-            let target: BitArray<[u8; 32], _> = x.as_bitslice().try_into().unwrap();
+    /// Shift the 256 bits left by n, filling with zeros from the lowest bit
+    #[inline]
+    pub fn shift_left(&self, n: u32) -> Self {
+        if n >= 256 {
+            return ZERO;
+        }
+        let words = self.words();
+        let word_shift = (n / WORD_SIZE) as usize;
+        let bit_shift = n % WORD_SIZE;
+        let mut shifted = [0u64; WORDS];
+        for (dst, word) in shifted.iter_mut().take(WORDS - word_shift).enumerate() {
+            let src = dst + word_shift;
+            *word = words[src] << bit_shift;
+            if bit_shift > 0 && src + 1 < WORDS {
+                *word |= words[src + 1] >> (WORD_SIZE - bit_shift);
+            }
+        }
+        H256::from_words(shifted)
+    }
 
-            let y: [u8; 32] = target.data;
+    /// Split into big endian words, the first word holds the highest bits
+    #[inline]
+    fn words(&self) -> [u64; WORDS] {
+        let mut words = [0u64; WORDS];
+        for (word, chunk) in words.iter_mut().zip(self.0.chunks_exact(8)) {
+            *word = u64::from_be_bytes(chunk.try_into().expect("8 bytes chunk"));
+        }
+        words
+    }
 
-            y.into()
+    #[inline]
+    fn from_words(words: [u64; WORDS]) -> Self {
+        let mut bytes = [0u8; 32];
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(words) {
+            chunk.copy_from_slice(&word.to_be_bytes());
         }
+        H256(bytes)
     }
 }
 
@@ -123,3 +198,32 @@ impl From<H256> for [u8; 32] {
         h256.0
     }
 }
+
+impl TryFrom<&[u8]> for H256 {
+    type Error = Error;
+
+    fn try_from(v: &[u8]) -> Result<H256, Error> {
+        let bytes: [u8; 32] = v.try_into().map_err(|_| Error::InvalidLength {
+            expected: 32,
+            actual: v.len(),
+        })?;
+        Ok(H256(bytes))
+    }
+}
+
+impl AsRef<[u8]> for H256 {
+    fn as_ref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+/// Parse a hex string, with or without a 0x prefix
+impl FromStr for H256 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<H256, Error> {
+        let s = s.strip_prefix("0x").unwrap_or(s);
+        let bytes = hex::decode(s).map_err(|err| Error::InvalidHex(err.to_string()))?;
+        H256::try_from(bytes.as_slice())
+    }
+}
@@ -0,0 +1,313 @@
+use core::ops::Add;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use std::time::Instant;
+
+use crate::{
+    error::Result,
+    h256::H256,
+    prune::{PruneReport, Reachable},
+    traits::{StoreKeysOps, StoreReadOps, StoreWriteOps, WriteBatch},
+    tree::{BranchKey, BranchNode, TreeCounts},
+};
+
+/// Calls made to an `InstrumentedStore` and the time spent in them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreMetrics {
+    pub branch_reads: u64,
+    pub branch_writes: u64,
+    pub branch_removes: u64,
+    pub leaf_reads: u64,
+    pub leaf_writes: u64,
+    pub leaf_removes: u64,
+    /// Number of write batches, their writes are counted with the others
+    pub batches: u64,
+    /// Time spent reading the wrapped store
+    pub read_time: Duration,
+    /// Time spent writing the wrapped store
+    pub write_time: Duration,
+}
+
+impl Add for StoreMetrics {
+    type Output = StoreMetrics;
+    fn add(self, other: StoreMetrics) -> StoreMetrics {
+        StoreMetrics {
+            branch_reads: self.branch_reads + other.branch_reads,
+            branch_writes: self.branch_writes + other.branch_writes,
+            branch_removes: self.branch_removes + other.branch_removes,
+            leaf_reads: self.leaf_reads + other.leaf_reads,
+            leaf_writes: self.leaf_writes + other.leaf_writes,
+            leaf_removes: self.leaf_removes + other.leaf_removes,
+            batches: self.batches + other.batches,
+            read_time: self.read_time + other.read_time,
+            write_time: self.write_time + other.write_time,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    branch_reads: AtomicU64,
+    branch_writes: AtomicU64,
+    branch_removes: AtomicU64,
+    leaf_reads: AtomicU64,
+    leaf_writes: AtomicU64,
+    leaf_removes: AtomicU64,
+    batches: AtomicU64,
+    read_nanos: AtomicU64,
+    write_nanos: AtomicU64,
+}
+
+fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+fn add_time(counter: &AtomicU64, start: Instant) {
+    let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+    counter.fetch_add(nanos, Ordering::Relaxed);
+}
+
+/// Debug span of a tree operation, with the fields a `StoreSpan` records
+#[cfg(feature = "tracing")]
+macro_rules! store_span {
+    ($name:literal, $($fields:tt)*) => {
+        tracing::debug_span!(
+            $name,
+            $($fields)*,
+            reads = tracing::field::Empty,
+            writes = tracing::field::Empty,
+            removes = tracing::field::Empty,
+            elapsed_us = tracing::field::Empty,
+        )
+    };
+}
+#[cfg(feature = "tracing")]
+pub(crate) use store_span;
+
+/// Entered span of a tree operation
+///
+/// Closing it records the time the operation took, and the reads, writes and
+/// removes it made when the store counts them. Calls made meanwhile by other
+/// users of a shared store are counted too.
+#[cfg(feature = "tracing")]
+pub(crate) struct StoreSpan {
+    span: tracing::span::EnteredSpan,
+    before: Option<StoreMetrics>,
+    start: Instant,
+}
+
+#[cfg(feature = "tracing")]
+impl StoreSpan {
+    pub(crate) fn open<V, S: StoreReadOps<V>>(span: tracing::Span, store: &S) -> Self {
+        StoreSpan {
+            span: span.entered(),
+            before: store.store_metrics(),
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn close<V, S: StoreReadOps<V>>(self, store: &S) {
+        let elapsed = u64::try_from(self.start.elapsed().as_micros()).unwrap_or(u64::MAX);
+        let span = self.span.exit();
+        span.record("elapsed_us", elapsed);
+        // metrics taken meanwhile start again from zero, saturate
+        if let (Some(before), Some(after)) = (self.before, store.store_metrics()) {
+            let delta = |after: u64, before: u64| after.saturating_sub(before);
+            span.record(
+                "reads",
+                delta(after.branch_reads, before.branch_reads)
+                    + delta(after.leaf_reads, before.leaf_reads),
+            );
+            span.record(
+                "writes",
+                delta(after.branch_writes, before.branch_writes)
+                    + delta(after.leaf_writes, before.leaf_writes),
+            );
+            span.record(
+                "removes",
+                delta(after.branch_removes, before.branch_removes)
+                    + delta(after.leaf_removes, before.leaf_removes),
+            );
+        }
+    }
+}
+
+/// Store wrapper counting and timing the calls made to the wrapped store
+///
+/// Take the metrics after an operation of the tree to see the reads and
+/// writes it made. With the `tracing` feature each call also emits a trace
+/// event, within the spans of the tree operations, and those spans record
+/// the reads, writes and removes made within them.
+#[derive(Debug, Default)]
+pub struct InstrumentedStore<S> {
+    store: S,
+    counters: Counters,
+}
+
+impl<S> InstrumentedStore<S> {
+    pub fn new(store: S) -> Self {
+        InstrumentedStore {
+            store,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Get the wrapped store
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Retake the wrapped store
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// Metrics gathered since the store was built or the metrics last taken
+    pub fn metrics(&self) -> StoreMetrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StoreMetrics {
+            branch_reads: load(&self.counters.branch_reads),
+            branch_writes: load(&self.counters.branch_writes),
+            branch_removes: load(&self.counters.branch_removes),
+            leaf_reads: load(&self.counters.leaf_reads),
+            leaf_writes: load(&self.counters.leaf_writes),
+            leaf_removes: load(&self.counters.leaf_removes),
+            batches: load(&self.counters.batches),
+            read_time: Duration::from_nanos(load(&self.counters.read_nanos)),
+            write_time: Duration::from_nanos(load(&self.counters.write_nanos)),
+        }
+    }
+
+    /// Return the metrics and start counting again from zero
+    pub fn take_metrics(&self) -> StoreMetrics {
+        let take = |counter: &AtomicU64| counter.swap(0, Ordering::Relaxed);
+        StoreMetrics {
+            branch_reads: take(&self.counters.branch_reads),
+            branch_writes: take(&self.counters.branch_writes),
+            branch_removes: take(&self.counters.branch_removes),
+            leaf_reads: take(&self.counters.leaf_reads),
+            leaf_writes: take(&self.counters.leaf_writes),
+            leaf_removes: take(&self.counters.leaf_removes),
+            batches: take(&self.counters.batches),
+            read_time: Duration::from_nanos(take(&self.counters.read_nanos)),
+            write_time: Duration::from_nanos(take(&self.counters.write_nanos)),
+        }
+    }
+}
+
+impl<V, S: StoreReadOps<V>> StoreReadOps<V> for InstrumentedStore<S> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(height = branch_key.height, "get_branch");
+        let start = Instant::now();
+        let branch = self.store.get_branch(branch_key);
+        add_time(&self.counters.read_nanos, start);
+        add(&self.counters.branch_reads, 1);
+        branch
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(key = %leaf_key, "get_leaf");
+        let start = Instant::now();
+        let leaf = self.store.get_leaf(leaf_key);
+        add_time(&self.counters.read_nanos, start);
+        add(&self.counters.leaf_reads, 1);
+        leaf
+    }
+    fn get_counts(&self, root: &H256) -> Result<Option<TreeCounts>> {
+        self.store.get_counts(root)
+    }
+    fn retained_roots(&self) -> Result<Vec<H256>> {
+        self.store.retained_roots()
+    }
+    fn get_branch_at(&self, root: &H256, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        self.store.get_branch_at(root, branch_key)
+    }
+    fn store_metrics(&self) -> Option<StoreMetrics> {
+        Some(self.metrics())
+    }
+}
+
+impl<V, S: StoreWriteOps<V>> StoreWriteOps<V> for InstrumentedStore<S> {
+    fn insert_branch(&mut self, branch_key: BranchKey, branch: BranchNode) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(height = branch_key.height, "insert_branch");
+        let start = Instant::now();
+        let written = self.store.insert_branch(branch_key, branch);
+        add_time(&self.counters.write_nanos, start);
+        add(&self.counters.branch_writes, 1);
+        written
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(key = %leaf_key, "insert_leaf");
+        let start = Instant::now();
+        let written = self.store.insert_leaf(leaf_key, leaf);
+        add_time(&self.counters.write_nanos, start);
+        add(&self.counters.leaf_writes, 1);
+        written
+    }
+    fn remove_branch(&mut self, branch_key: &BranchKey) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(height = branch_key.height, "remove_branch");
+        let start = Instant::now();
+        let removed = self.store.remove_branch(branch_key);
+        add_time(&self.counters.write_nanos, start);
+        add(&self.counters.branch_removes, 1);
+        removed
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(key = %leaf_key, "remove_leaf");
+        let start = Instant::now();
+        let removed = self.store.remove_leaf(leaf_key);
+        add_time(&self.counters.write_nanos, start);
+        add(&self.counters.leaf_removes, 1);
+        removed
+    }
+    fn write_batch(&mut self, batch: WriteBatch<V>) -> Result<()> {
+        let count = |writes: &mut dyn Iterator<Item = bool>| {
+            writes.fold((0, 0), |(inserts, removes), insert| {
+                if insert {
+                    (inserts + 1, removes)
+                } else {
+                    (inserts, removes + 1)
+                }
+            })
+        };
+        let branches = count(&mut batch.branches().map(|(_, branch)| branch.is_some()));
+        let leaves = count(&mut batch.leaves().map(|(_, leaf)| leaf.is_some()));
+        #[cfg(feature = "tracing")]
+        tracing::trace!(writes = batch.len(), "write_batch");
+
+        let start = Instant::now();
+        let written = self.store.write_batch(batch);
+        add_time(&self.counters.write_nanos, start);
+        add(&self.counters.batches, 1);
+        add(&self.counters.branch_writes, branches.0);
+        add(&self.counters.branch_removes, branches.1);
+        add(&self.counters.leaf_writes, leaves.0);
+        add(&self.counters.leaf_removes, leaves.1);
+        written
+    }
+    fn commit_counts(&mut self, root: &H256, counts: &TreeCounts) -> Result<()> {
+        self.store.commit_counts(root, counts)
+    }
+    fn commit_root(&mut self, root: &H256) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(root = %root, metrics = ?self.metrics(), "commit_root");
+        self.store.commit_root(root)
+    }
+    fn release_roots(&mut self, keep_roots: &[H256], reachable: &Reachable) -> Result<PruneReport> {
+        self.store.release_roots(keep_roots, reachable)
+    }
+}
+
+impl<S: StoreKeysOps> StoreKeysOps for InstrumentedStore<S> {
+    fn branch_keys(&self) -> Result<Vec<BranchKey>> {
+        self.store.branch_keys()
+    }
+    fn leaf_keys(&self) -> Result<Vec<H256>> {
+        self.store.leaf_keys()
+    }
+}
@@ -0,0 +1,102 @@
+use core::marker::PhantomData;
+use core::ops::{Bound, RangeBounds};
+
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    traits::StoreReadOps,
+    tree::{is_bound_key, BranchKey, ChildKey},
+};
+
+/// Iterator over the leaves of a tree in key order
+///
+/// Walks the branches from the root, only descending into branches whose key
+/// range overlaps the requested bounds. The reserved bound leaves
+/// `MERKLE_LOWER_BOUND` and `MERKLE_UPPER_BOUND` are skipped.
+pub struct Iter<'a, V, S> {
+    store: &'a S,
+    // children left to visit, the next smallest one on top
+    stack: Vec<ChildKey>,
+    start: Bound<H256>,
+    end: Bound<H256>,
+    phantom: PhantomData<V>,
+}
+
+impl<'a, V, S: StoreReadOps<V>> Iter<'a, V, S> {
+    pub(crate) fn new<R: RangeBounds<H256>>(store: &'a S, root: &H256, range: R) -> Self {
+        let stack = if root.is_zero() {
+            Vec::new()
+        } else {
+            vec![ChildKey::Branch(BranchKey::root())]
+        };
+        Iter {
+            store,
+            stack,
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            phantom: PhantomData,
+        }
+    }
+
+    fn after_start(&self, key: &H256) -> bool {
+        match &self.start {
+            Bound::Included(start) => key >= start,
+            Bound::Excluded(start) => key > start,
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn before_end(&self, key: &H256) -> bool {
+        match &self.end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn fail(&mut self, err: Error) -> Option<Result<(H256, V)>> {
+        self.stack.clear();
+        Some(Err(err))
+    }
+}
+
+impl<'a, V, S: StoreReadOps<V>> Iterator for Iter<'a, V, S> {
+    type Item = Result<(H256, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(child) = self.stack.pop() {
+            match child {
+                ChildKey::Leaf(key) => {
+                    if is_bound_key(&key) || !self.after_start(&key) || !self.before_end(&key) {
+                        continue;
+                    }
+                    return match self.store.get_leaf(&key) {
+                        Ok(Some(value)) => Some(Ok((key, value))),
+                        Ok(None) => self.fail(Error::MissingLeaf(key)),
+                        Err(err) => self.fail(err),
+                    };
+                }
+                ChildKey::Branch(branch_key) => {
+                    let (lowest, highest) = branch_key.key_range();
+                    if !self.after_start(&highest) || !self.before_end(&lowest) {
+                        continue;
+                    }
+                    match self.store.get_branch(&branch_key) {
+                        Ok(Some(branch)) => {
+                            self.stack.push(branch.right.1);
+                            self.stack.push(branch.left.1);
+                        }
+                        Ok(None) => {
+                            return self.fail(Error::MissingBranch(
+                                branch_key.height,
+                                branch_key.node_key,
+                            ));
+                        }
+                        Err(err) => return self.fail(err),
+                    }
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    instrumented_store::StoreMetrics,
+    prune::{PruneReport, Reachable},
+    traits::{Hasher, StoreKeysOps, StoreReadOps, StoreWriteOps},
+    tree::{BranchKey, BranchNode, SparseMerkleTree, TreeCounts},
+};
+
+/// Number of checkpoints retained by `JournalStore::default`
+pub const DEFAULT_JOURNAL_DEPTH: usize = 256;
+
+/// Previous state of a node overwritten by an update
+#[derive(Debug, Clone)]
+enum Undo<V> {
+    Branch(BranchKey, Option<BranchNode>),
+    Leaf(H256, Option<V>),
+}
+
+/// Undo records of one committed update
+#[derive(Debug, Clone)]
+struct Checkpoint<V> {
+    // root once the update was applied
+    root: H256,
+    // counts of the tree at root, if committed
+    counts: Option<TreeCounts>,
+    undo: Vec<Undo<V>>,
+}
+
+/// Store wrapper keeping an undo journal of the last updates
+///
+/// Before each write the previous state of the node is recorded, and every
+/// `commit_root` closes a checkpoint holding the records of one update.
+/// `rollback_to` replays them backwards, restoring the wrapped store exactly
+/// as it was when the root was committed. Only the last `depth` checkpoints
+/// are kept, older ones are finalized and can no longer be rolled back.
+///
+/// The roots of the checkpoints are retained roots: `prune` drops the ones
+/// not kept by folding their undo records into the next checkpoint, without
+/// journaling anything. `sweep` goes through the journal like any update.
+#[derive(Debug, Clone)]
+pub struct JournalStore<V, S> {
+    store: S,
+    // undo records of the update being written
+    pending: Vec<Undo<V>>,
+    // counts committed with the update being written
+    pending_counts: Option<TreeCounts>,
+    // retained checkpoints, oldest first
+    checkpoints: VecDeque<Checkpoint<V>>,
+    depth: usize,
+}
+
+impl<V, S: Default> Default for JournalStore<V, S> {
+    fn default() -> Self {
+        JournalStore::new(S::default(), DEFAULT_JOURNAL_DEPTH)
+    }
+}
+
+impl<V, S> JournalStore<V, S> {
+    /// Wrap store, retaining at most depth checkpoints
+    pub fn new(store: S, depth: usize) -> Self {
+        JournalStore {
+            store,
+            pending: Vec::new(),
+            pending_counts: None,
+            checkpoints: VecDeque::new(),
+            depth,
+        }
+    }
+
+    /// Get the wrapped store
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Drop the journal and retake the wrapped store
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// Maximum number of retained checkpoints
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Change the retention depth, finalizing checkpoints beyond it
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.checkpoints.len() > self.depth {
+            self.checkpoints.pop_front();
+        }
+    }
+
+    /// Roots that can be rolled back to, oldest first
+    pub fn checkpoints(&self) -> impl Iterator<Item = &H256> {
+        self.checkpoints.iter().map(|checkpoint| &checkpoint.root)
+    }
+
+    /// Finalize every checkpoint older than the latest one that committed
+    /// root, which becomes the oldest root to roll back to, return how many
+    /// were dropped
+    pub fn finalize(&mut self, root: &H256) -> Result<usize> {
+        let position = self
+            .checkpoints
+            .iter()
+            .rposition(|checkpoint| &checkpoint.root == root)
+            .ok_or(Error::MissingRoot(*root))?;
+        self.checkpoints.drain(..position);
+        Ok(position)
+    }
+
+    /// Earliest undo record of branch_key written after the latest
+    /// checkpoint of root, holding the branch of the tree at root
+    fn branch_undo(&self, root: &H256, branch_key: &BranchKey) -> Result<Option<&Undo<V>>> {
+        let position = self
+            .checkpoints
+            .iter()
+            .rposition(|checkpoint| &checkpoint.root == root)
+            .ok_or(Error::MissingRoot(*root))?;
+        Ok(self
+            .checkpoints
+            .iter()
+            .skip(position + 1)
+            .flat_map(|checkpoint| checkpoint.undo.iter())
+            .chain(self.pending.iter())
+            .find(|record| matches!(record, Undo::Branch(key, _) if key == branch_key)))
+    }
+
+    /// Counts committed along with the latest checkpoint of root
+    fn checkpoint_counts(&self, root: &H256) -> Option<&TreeCounts> {
+        self.checkpoints
+            .iter()
+            .rev()
+            .find(|checkpoint| &checkpoint.root == root)
+            .and_then(|checkpoint| checkpoint.counts.as_ref())
+    }
+}
+
+impl<V, S: StoreWriteOps<V>> JournalStore<V, S> {
+    fn undo(&mut self, records: Vec<Undo<V>>) -> Result<()> {
+        for record in records.into_iter().rev() {
+            match record {
+                Undo::Branch(key, Some(branch)) => self.store.insert_branch(key, branch)?,
+                Undo::Branch(key, None) => self.store.remove_branch(&key)?,
+                Undo::Leaf(key, Some(leaf)) => self.store.insert_leaf(key, leaf)?,
+                Undo::Leaf(key, None) => self.store.remove_leaf(&key)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo every update committed after root, along with any uncommitted
+    /// writes, the latest checkpoint of root is kept
+    pub fn rollback_to(&mut self, root: &H256) -> Result<()> {
+        let position = self
+            .checkpoints
+            .iter()
+            .rposition(|checkpoint| &checkpoint.root == root)
+            .ok_or(Error::MissingRoot(*root))?;
+
+        let pending = core::mem::take(&mut self.pending);
+        self.pending_counts = None;
+        self.undo(pending)?;
+        while self.checkpoints.len() > position + 1 {
+            let checkpoint = self.checkpoints.pop_back().expect("checkpoint after root");
+            self.undo(checkpoint.undo)?;
+        }
+        if let Some(counts) = &self.checkpoints[position].counts {
+            self.store.commit_counts(root, counts)?;
+        }
+        self.store.commit_root(root)
+    }
+}
+
+impl<V, S: StoreReadOps<V>> StoreReadOps<V> for JournalStore<V, S> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        self.store.get_branch(branch_key)
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        self.store.get_leaf(leaf_key)
+    }
+    fn get_counts(&self, root: &H256) -> Result<Option<TreeCounts>> {
+        match self.checkpoint_counts(root) {
+            Some(counts) => Ok(Some(counts.clone())),
+            None => self.store.get_counts(root),
+        }
+    }
+    fn store_metrics(&self) -> Option<StoreMetrics> {
+        self.store.store_metrics()
+    }
+    fn retained_roots(&self) -> Result<Vec<H256>> {
+        Ok(self.checkpoints().copied().collect())
+    }
+    fn get_branch_at(&self, root: &H256, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        match self.branch_undo(root, branch_key)? {
+            Some(Undo::Branch(_, branch)) => Ok(branch.clone()),
+            _ => self.store.get_branch(branch_key),
+        }
+    }
+}
+
+impl<V, S: StoreKeysOps> StoreKeysOps for JournalStore<V, S> {
+    fn branch_keys(&self) -> Result<Vec<BranchKey>> {
+        self.store.branch_keys()
+    }
+    fn leaf_keys(&self) -> Result<Vec<H256>> {
+        self.store.leaf_keys()
+    }
+}
+
+impl<V, S: StoreReadOps<V> + StoreWriteOps<V>> StoreWriteOps<V> for JournalStore<V, S> {
+    fn insert_branch(&mut self, branch_key: BranchKey, branch: BranchNode) -> Result<()> {
+        let previous = self.store.get_branch(&branch_key)?;
+        self.store.insert_branch(branch_key.clone(), branch)?;
+        self.pending.push(Undo::Branch(branch_key, previous));
+        Ok(())
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<()> {
+        let previous = self.store.get_leaf(&leaf_key)?;
+        self.store.insert_leaf(leaf_key, leaf)?;
+        self.pending.push(Undo::Leaf(leaf_key, previous));
+        Ok(())
+    }
+    fn remove_branch(&mut self, branch_key: &BranchKey) -> Result<()> {
+        let previous = self.store.get_branch(branch_key)?;
+        self.store.remove_branch(branch_key)?;
+        self.pending
+            .push(Undo::Branch(branch_key.clone(), previous));
+        Ok(())
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<()> {
+        let previous = self.store.get_leaf(leaf_key)?;
+        self.store.remove_leaf(leaf_key)?;
+        self.pending.push(Undo::Leaf(*leaf_key, previous));
+        Ok(())
+    }
+    fn commit_counts(&mut self, root: &H256, counts: &TreeCounts) -> Result<()> {
+        self.store.commit_counts(root, counts)?;
+        self.pending_counts = Some(counts.clone());
+        Ok(())
+    }
+    fn commit_root(&mut self, root: &H256) -> Result<()> {
+        self.store.commit_root(root)?;
+        let undo = core::mem::take(&mut self.pending);
+        let counts = self.pending_counts.take();
+        self.checkpoints.push_back(Checkpoint {
+            root: *root,
+            counts,
+            undo,
+        });
+        while self.checkpoints.len() > self.depth {
+            self.checkpoints.pop_front();
+        }
+        Ok(())
+    }
+    /// The undo records of a dropped checkpoint are prepended to the next
+    /// one, so rolling back over it restores the same nodes. Only the
+    /// earliest record of each node is needed by a rollback, and none of the
+    /// records of nodes no kept tree reaches.
+    fn release_roots(&mut self, keep_roots: &[H256], reachable: &Reachable) -> Result<PruneReport> {
+        let mut report = PruneReport::default();
+        let mut dropped = |record: &Undo<V>| match record {
+            Undo::Branch(..) => report.branches_removed += 1,
+            Undo::Leaf(..) => report.leaves_removed += 1,
+        };
+        let last = self.checkpoints.len().saturating_sub(1);
+        let mut checkpoints = VecDeque::with_capacity(self.checkpoints.len());
+        let mut carried: Vec<Undo<V>> = Vec::new();
+        for (i, mut checkpoint) in core::mem::take(&mut self.checkpoints)
+            .into_iter()
+            .enumerate()
+        {
+            carried.append(&mut checkpoint.undo);
+            if i != last && !keep_roots.contains(&checkpoint.root) {
+                report.roots_removed += 1;
+                continue;
+            }
+            // nothing can be rolled back past the oldest retained root
+            if checkpoints.is_empty() {
+                carried.iter().for_each(&mut dropped);
+                carried.clear();
+            }
+            let mut branches = HashSet::new();
+            let mut leaves = HashSet::new();
+            for record in carried.drain(..) {
+                let needed = match &record {
+                    Undo::Branch(key, _) => {
+                        reachable.contains_branch(key) && branches.insert(key.clone())
+                    }
+                    Undo::Leaf(key, _) => reachable.contains_leaf(key) && leaves.insert(*key),
+                };
+                if needed {
+                    checkpoint.undo.push(record);
+                } else {
+                    dropped(&record);
+                }
+            }
+            checkpoints.push_back(checkpoint);
+        }
+        self.checkpoints = checkpoints;
+        Ok(report)
+    }
+}
+
+impl<H: Hasher + Default, V, S: StoreWriteOps<V>> SparseMerkleTree<H, V, JournalStore<V, S>> {
+    /// Restore the tree to a root committed by one of the retained updates
+    pub fn rollback_to(&mut self, root: &H256) -> Result<()> {
+        self.store_mut().rollback_to(root)?;
+        let counts = self.store().checkpoint_counts(root).cloned();
+        self.set_root(*root, counts);
+        Ok(())
+    }
+}
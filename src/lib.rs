@@ -1,15 +1,35 @@
+pub mod async_store;
 pub mod blake2b;
+pub mod budget;
+pub mod bulk;
+pub mod cached_store;
+pub mod codec;
 pub mod default_store;
+pub mod diff;
 pub mod error;
 pub mod h256;
+pub mod instrumented_store;
+pub mod iter;
+pub mod journal_store;
+pub mod log_store;
 pub mod merge;
 pub mod merkle_proof;
+#[cfg(feature = "mmap")]
+pub mod mmap_store;
+pub mod multi_proof;
+pub mod overlay_store;
+pub mod prune;
+pub mod sharded_store;
+pub mod shared_tree;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
 
 #[cfg(test)]
 mod tests;
 
 pub mod traits;
 pub mod tree;
+pub mod versioned_store;
 
 /// Expected path size: log2(256) * 2, used for hint vector capacity
 pub const EXPECTED_PATH_SIZE: usize = 16;
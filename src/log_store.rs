@@ -0,0 +1,855 @@
+use core::marker::PhantomData;
+use std::collections::{hash_map::Entry, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, PoisonError,
+};
+use std::thread::{self, JoinHandle};
+
+use cryptoxide::{blake2b::Blake2b, digest::Digest};
+
+use crate::{
+    codec::{
+        decode_branch, decode_branch_key, decode_counts, encode_branch, encode_branch_key,
+        encode_counts, ValueCodec, BRANCH_KEY_SIZE,
+    },
+    error::{Error, Result},
+    h256::H256,
+    traits::{StoreKeysOps, StoreReadOps, StoreWriteOps, WriteBatch},
+    tree::{BranchKey, BranchNode, TreeCounts},
+};
+
+/// Size after which `LogOptions::default` seals a segment and starts a new one
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 << 20;
+
+const INSERT_BRANCH: u8 = 1;
+const REMOVE_BRANCH: u8 = 2;
+const INSERT_LEAF: u8 = 3;
+const REMOVE_LEAF: u8 = 4;
+const COMMIT: u8 = 5;
+const COUNTS: u8 = 6;
+
+// a record is its kind, the payload length, the payload and a checksum
+const HEADER_SIZE: usize = 5;
+const CHECKSUM_SIZE: usize = 4;
+const SEGMENT_EXTENSION: &str = "log";
+// extension of a compacted segment until it is complete
+const COMPACTING_EXTENSION: &str = "compact";
+
+fn io_error(err: std::io::Error) -> Error {
+    Error::Store(err.to_string())
+}
+
+fn checksum(record: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut hasher = Blake2b::new(CHECKSUM_SIZE);
+    hasher.input(record);
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    hasher.result(&mut checksum);
+    checksum
+}
+
+/// Append a record to buf, return the offset of its payload in buf
+fn encode_record(buf: &mut Vec<u8>, kind: u8, parts: &[&[u8]]) -> usize {
+    let start = buf.len();
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    buf.push(kind);
+    buf.extend_from_slice(&(len as u32).to_le_bytes());
+    for part in parts {
+        buf.extend_from_slice(part);
+    }
+    let checksum = checksum(&buf[start..]);
+    buf.extend_from_slice(&checksum);
+    start + HEADER_SIZE
+}
+
+/// Make the creations and removals of files in dir durable
+fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(io_error)?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{:016}.{}", segment, SEGMENT_EXTENSION))
+}
+
+/// Read the value at location, opening its segment in readers if needed
+fn read_at(readers: &mut HashMap<u64, File>, dir: &Path, location: &Location) -> Result<Vec<u8>> {
+    let file = match readers.entry(location.segment) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            entry.insert(File::open(segment_path(dir, location.segment)).map_err(io_error)?)
+        }
+    };
+    let mut bytes = vec![0u8; location.len as usize];
+    file.seek(SeekFrom::Start(location.offset))
+        .map_err(io_error)?;
+    file.read_exact(&mut bytes).map_err(io_error)?;
+    Ok(bytes)
+}
+
+/// Tuning of a `LogStore`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogOptions {
+    /// Size after which the segment being written is sealed, checked on
+    /// every commit so that an update never spans two segments
+    pub segment_size: u64,
+    /// Sync the segment to disk on every commit
+    pub sync: bool,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions {
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            sync: true,
+        }
+    }
+}
+
+/// Position of the value held by a record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u32,
+}
+
+/// Change of the index, applied once its record is written, or on replay
+/// once the update holding it is committed
+enum IndexWrite {
+    Branch(BranchKey, Option<Location>),
+    Leaf(H256, Option<Location>),
+}
+
+/// Append only store writing every change as a record of segment files
+///
+/// Each write appends a record to the last segment and an in memory index
+/// points at the latest record of every node. `commit_root` appends a commit
+/// record, preceded by the counts of the tree at the committed root: on open
+/// the segments are replayed and the records following the
+/// last commit, left by an update that never completed, are truncated away.
+/// Removed and overwritten nodes keep taking room until a compaction
+/// rewrites the live records of the sealed segments, on a worker thread while
+/// writes go on to the active one. The directory is synced after every
+/// segment created or deleted, so a crash never loses a segment a commit
+/// went to.
+pub struct LogStore<V> {
+    dir: PathBuf,
+    options: LogOptions,
+    branches: HashMap<BranchKey, Location>,
+    leaves: HashMap<H256, Location>,
+    // segments in order, the last one being written
+    segments: Vec<u64>,
+    active: File,
+    active_size: u64,
+    readers: Mutex<HashMap<u64, File>>,
+    last_root: Option<H256>,
+    // latest committed counts, and the ones written for the next commit
+    counts: Option<(H256, TreeCounts)>,
+    pending_counts: Option<(H256, TreeCounts)>,
+    // writes made since the last commit
+    dirty: bool,
+    compaction: Option<Compaction>,
+    phantom: PhantomData<V>,
+}
+
+/// Compaction running on a worker thread
+struct Compaction {
+    // segment the live records are written to, ordered between the sealed
+    // segments it replaces and the ones written since
+    segment: u64,
+    sealed: Vec<u64>,
+    worker: JoinHandle<Result<Compacted>>,
+    // set when the store is dropped, stopping the worker
+    cancel: Arc<AtomicBool>,
+}
+
+/// Segment written by a compaction, with the location every node was copied
+/// from and the one it was copied to
+struct Compacted {
+    size: u64,
+    branches: Vec<(BranchKey, Location, Location)>,
+    leaves: Vec<(H256, Location, Location)>,
+}
+
+impl<V> LogStore<V> {
+    /// Open the store kept in dir with the default options, creating it if
+    /// needed
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Self::open_with(dir, LogOptions::default())
+    }
+
+    /// Open the store kept in dir, replaying its segments
+    pub fn open_with<P: AsRef<Path>>(dir: P, options: LogOptions) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(io_error)?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            let extension = path.extension().and_then(|ext| ext.to_str());
+            if extension == Some(COMPACTING_EXTENSION) {
+                // left by a compaction that never completed
+                fs::remove_file(&path).map_err(io_error)?;
+                continue;
+            }
+            if extension != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(segment) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                segments.push(segment);
+            }
+        }
+        segments.sort_unstable();
+        if segments.is_empty() {
+            segments.push(0);
+        }
+
+        let mut branches = HashMap::new();
+        let mut leaves = HashMap::new();
+        let mut last_root = None;
+        let mut counts = None;
+        let mut active_size = 0;
+        for (i, segment) in segments.iter().enumerate() {
+            let path = segment_path(&dir, *segment);
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => return Err(io_error(err)),
+            };
+            let (committed, root) =
+                replay(*segment, &data, &mut branches, &mut leaves, &mut counts)?;
+            last_root = root.or(last_root);
+            if committed < data.len() as u64 {
+                if i + 1 < segments.len() {
+                    return Err(Error::Store(format!(
+                        "Corrupted log segment {}",
+                        path.display()
+                    )));
+                }
+                // drop the records of an update that never completed
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(&path)
+                    .map_err(io_error)?;
+                file.set_len(committed).map_err(io_error)?;
+                file.sync_all().map_err(io_error)?;
+            }
+            active_size = committed;
+        }
+
+        let segment = *segments.last().expect("at least one segment");
+        let active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&dir, segment))
+            .map_err(io_error)?;
+        sync_dir(&dir)?;
+        Ok(LogStore {
+            dir,
+            options,
+            branches,
+            leaves,
+            segments,
+            active,
+            active_size,
+            readers: Mutex::new(HashMap::new()),
+            last_root,
+            counts,
+            pending_counts: None,
+            dirty: false,
+            compaction: None,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Root of the last committed update
+    pub fn last_root(&self) -> Option<H256> {
+        self.last_root
+    }
+
+    /// Directory holding the segments
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of segment files
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Size in bytes of every segment
+    pub fn log_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for segment in &self.segments[..self.segments.len() - 1] {
+            size += fs::metadata(segment_path(&self.dir, *segment))
+                .map_err(io_error)?
+                .len();
+        }
+        Ok(size + self.active_size)
+    }
+
+    /// Size in bytes the live records would take once compacted
+    pub fn live_size(&self) -> u64 {
+        let record = |key_size: usize, location: &Location| {
+            (HEADER_SIZE + key_size + location.len as usize + CHECKSUM_SIZE) as u64
+        };
+        let branches: u64 = self
+            .branches
+            .values()
+            .map(|location| record(BRANCH_KEY_SIZE, location))
+            .sum();
+        let leaves: u64 = self
+            .leaves
+            .values()
+            .map(|location| record(32, location))
+            .sum();
+        branches + leaves
+    }
+
+    fn read(&self, location: &Location) -> Result<Vec<u8>> {
+        let mut readers = self.readers.lock().unwrap_or_else(PoisonError::into_inner);
+        read_at(&mut readers, &self.dir, location)
+    }
+
+    /// Append records to the active segment, return the offset they start at
+    fn append(&mut self, buf: &[u8]) -> Result<u64> {
+        let start = self.active_size;
+        if let Err(err) = self.active.write_all(buf) {
+            // leave no partial record behind
+            let _ = self.active.set_len(start);
+            return Err(io_error(err));
+        }
+        self.active_size += buf.len() as u64;
+        Ok(start)
+    }
+
+    /// Seal the active segment and start writing a new one
+    fn roll(&mut self) -> Result<()> {
+        self.roll_to(self.segments.last().expect("at least one segment") + 1)
+    }
+
+    /// Seal the active segment and start writing segment
+    fn roll_to(&mut self, segment: u64) -> Result<()> {
+        self.active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, segment))
+            .map_err(io_error)?;
+        sync_dir(&self.dir)?;
+        self.segments.push(segment);
+        self.active_size = 0;
+        Ok(())
+    }
+
+    /// Append the record of a branch to buf, locating it relative to buf
+    fn stage_branch(buf: &mut Vec<u8>, branch_key: &BranchKey, branch: &BranchNode) -> IndexWrite {
+        let key = encode_branch_key(branch_key);
+        let value = encode_branch(branch);
+        let offset = encode_record(buf, INSERT_BRANCH, &[&key, &value]) + key.len();
+        IndexWrite::Branch(
+            branch_key.clone(),
+            Some(Location {
+                segment: 0,
+                offset: offset as u64,
+                len: value.len() as u32,
+            }),
+        )
+    }
+
+    /// Write buf to the log, then point the index at the staged records
+    fn apply(&mut self, buf: Vec<u8>, staged: Vec<IndexWrite>) -> Result<()> {
+        let start = self.append(&buf)?;
+        let segment = *self.segments.last().expect("at least one segment");
+        let locate = |location: Option<Location>| {
+            location.map(|location| Location {
+                segment,
+                offset: start + location.offset,
+                len: location.len,
+            })
+        };
+        for write in staged {
+            match write {
+                IndexWrite::Branch(branch_key, location) => match locate(location) {
+                    Some(location) => {
+                        self.branches.insert(branch_key, location);
+                    }
+                    None => {
+                        self.branches.remove(&branch_key);
+                    }
+                },
+                IndexWrite::Leaf(leaf_key, location) => match locate(location) {
+                    Some(location) => {
+                        self.leaves.insert(leaf_key, location);
+                    }
+                    None => {
+                        self.leaves.remove(&leaf_key);
+                    }
+                },
+            }
+        }
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+impl<V: ValueCodec> LogStore<V> {
+    /// Append the record of a leaf to buf, locating it relative to buf
+    fn stage_leaf(buf: &mut Vec<u8>, leaf_key: &H256, leaf: &V) -> IndexWrite {
+        let value = leaf.encode();
+        let offset = encode_record(buf, INSERT_LEAF, &[leaf_key.as_slice(), &value]) + 32;
+        IndexWrite::Leaf(
+            *leaf_key,
+            Some(Location {
+                segment: 0,
+                offset: offset as u64,
+                len: value.len() as u32,
+            }),
+        )
+    }
+
+    /// Start rewriting the live records of the sealed segments on a worker
+    /// thread
+    ///
+    /// The active segment is sealed and writes go on to a new one while the
+    /// worker copies every live record to a compacted segment, closed by the
+    /// commit of the last root. `poll_compaction` or `wait_compaction` then
+    /// swap it in for the sealed segments. Only a committed state can be
+    /// compacted, a store holding no commit has nothing to compact.
+    pub fn start_compaction(&mut self) -> Result<()> {
+        if self.compaction.is_some() {
+            return Err(Error::Store("Compaction already running".to_string()));
+        }
+        if self.dirty {
+            return Err(Error::Store(
+                "Can not compact uncommitted writes".to_string(),
+            ));
+        }
+        let root = match self.last_root {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+
+        // the compacted segment goes between the sealed ones and the writes
+        // made from now on
+        let segment = self.segments.last().expect("at least one segment") + 1;
+        self.roll_to(segment + 1)?;
+        let sealed = self.segments[..self.segments.len() - 1].to_vec();
+
+        let branches = self
+            .branches
+            .iter()
+            .map(|(branch_key, location)| (branch_key.clone(), *location))
+            .collect();
+        let leaves = self
+            .leaves
+            .iter()
+            .map(|(leaf_key, location)| (*leaf_key, *location))
+            .collect();
+        let mut commit = Vec::new();
+        if let Some((_, counts)) = self.counts.as_ref().filter(|(counted, _)| *counted == root) {
+            encode_record(
+                &mut commit,
+                COUNTS,
+                &[root.as_slice(), &encode_counts(counts)],
+            );
+        }
+        encode_record(&mut commit, COMMIT, &[root.as_slice()]);
+
+        let dir = self.dir.clone();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancelled = cancel.clone();
+        let worker = thread::Builder::new()
+            .name("log-store-compaction".to_string())
+            .spawn(move || write_compacted(&dir, segment, branches, leaves, &commit, &cancelled))
+            .map_err(io_error)?;
+        self.compaction = Some(Compaction {
+            segment,
+            sealed,
+            worker,
+            cancel,
+        });
+        Ok(())
+    }
+
+    /// Swap in the compacted segment if the worker is done, return the
+    /// number of bytes reclaimed, None while no compaction is done
+    pub fn poll_compaction(&mut self) -> Result<Option<u64>> {
+        match &self.compaction {
+            Some(compaction) if compaction.worker.is_finished() => self.wait_compaction().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Wait for the running compaction and swap in the compacted segment,
+    /// return the number of bytes reclaimed, 0 if none is running
+    pub fn wait_compaction(&mut self) -> Result<u64> {
+        let compaction = match self.compaction.take() {
+            Some(compaction) => compaction,
+            None => return Ok(0),
+        };
+        let compacted = compaction
+            .worker
+            .join()
+            .map_err(|_| Error::Store("Compaction worker panicked".to_string()))
+            .and_then(|compacted| compacted);
+        match compacted {
+            Ok(compacted) => self.swap_compacted(compaction.segment, &compaction.sealed, compacted),
+            Err(err) => {
+                // the sealed segments still hold every record
+                let path = segment_path(&self.dir, compaction.segment);
+                let _ = fs::remove_file(path.with_extension(COMPACTING_EXTENSION));
+                Err(err)
+            }
+        }
+    }
+
+    /// Rewrite the live records on a worker thread and wait for it, return
+    /// the number of bytes reclaimed
+    ///
+    /// Blocks the calling thread while every live record is read and
+    /// rewritten, `start_compaction` lets writes go on meanwhile.
+    pub fn compact_blocking(&mut self) -> Result<u64> {
+        self.start_compaction()?;
+        self.wait_compaction()
+    }
+
+    /// Publish the compacted segment, point the index at its records and
+    /// delete the sealed segments
+    ///
+    /// A crash at any point leaves segments replaying to the last commit:
+    /// the compacted segment holds the state the sealed ones replay to.
+    fn swap_compacted(
+        &mut self,
+        segment: u64,
+        sealed: &[u64],
+        compacted: Compacted,
+    ) -> Result<u64> {
+        let path = segment_path(&self.dir, segment);
+        fs::rename(path.with_extension(COMPACTING_EXTENSION), &path).map_err(io_error)?;
+        sync_dir(&self.dir)?;
+
+        let mut before = 0;
+        for segment in sealed {
+            before += fs::metadata(segment_path(&self.dir, *segment))
+                .map_err(io_error)?
+                .len();
+        }
+
+        let mut readers = self.readers.lock().unwrap_or_else(PoisonError::into_inner);
+        // nodes written since the compaction started point at the active
+        // segments, and removed ones are gone from the index
+        for (branch_key, from, to) in compacted.branches {
+            if let Some(location) = self.branches.get_mut(&branch_key) {
+                if *location == from {
+                    *location = to;
+                }
+            }
+        }
+        for (leaf_key, from, to) in compacted.leaves {
+            if let Some(location) = self.leaves.get_mut(&leaf_key) {
+                if *location == from {
+                    *location = to;
+                }
+            }
+        }
+        for segment in sealed {
+            readers.remove(segment);
+        }
+        drop(readers);
+
+        let mut segments = vec![segment];
+        segments.extend(self.segments.drain(..).filter(|s| !sealed.contains(s)));
+        self.segments = segments;
+        // oldest first, so that a crash never leaves a removal replayed
+        // without the records it removes
+        for segment in sealed {
+            match fs::remove_file(segment_path(&self.dir, *segment)) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(io_error(err)),
+            }
+        }
+
+        // nothing was written meanwhile, the compacted segment goes on
+        // being written
+        if self.segments.len() == 2 && self.active_size == 0 {
+            let empty = self.segments.pop().expect("two segments");
+            self.active = OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .map_err(io_error)?;
+            self.active_size = compacted.size;
+            fs::remove_file(segment_path(&self.dir, empty)).map_err(io_error)?;
+        }
+        sync_dir(&self.dir)?;
+        Ok(before.saturating_sub(compacted.size))
+    }
+}
+
+impl<V> Drop for LogStore<V> {
+    /// Stop the running compaction, its segment is never swapped in
+    fn drop(&mut self) {
+        if let Some(compaction) = self.compaction.take() {
+            compaction.cancel.store(true, Ordering::Relaxed);
+            let _ = compaction.worker.join();
+            let path = segment_path(&self.dir, compaction.segment);
+            let _ = fs::remove_file(path.with_extension(COMPACTING_EXTENSION));
+        }
+    }
+}
+
+/// Copy the live records of branches and leaves to segment, under its
+/// compacting name, closed by the commit records
+fn write_compacted(
+    dir: &Path,
+    segment: u64,
+    branches: Vec<(BranchKey, Location)>,
+    leaves: Vec<(H256, Location)>,
+    commit: &[u8],
+    cancel: &AtomicBool,
+) -> Result<Compacted> {
+    let file = File::create(segment_path(dir, segment).with_extension(COMPACTING_EXTENSION))
+        .map_err(io_error)?;
+    let mut writer = BufWriter::new(&file);
+    let mut readers = HashMap::new();
+    let mut offset = 0u64;
+    let mut buf = Vec::new();
+    let mut copy = |kind: u8, key: &[u8], location: &Location| -> Result<Location> {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(Error::Store("Compaction cancelled".to_string()));
+        }
+        let value = read_at(&mut readers, dir, location)?;
+        buf.clear();
+        let value_offset = encode_record(&mut buf, kind, &[key, &value]) + key.len();
+        writer.write_all(&buf).map_err(io_error)?;
+        let copied = Location {
+            segment,
+            offset: offset + value_offset as u64,
+            len: location.len,
+        };
+        offset += buf.len() as u64;
+        Ok(copied)
+    };
+
+    let mut compacted = Compacted {
+        size: 0,
+        branches: Vec::with_capacity(branches.len()),
+        leaves: Vec::with_capacity(leaves.len()),
+    };
+    for (branch_key, location) in branches {
+        let copied = copy(INSERT_BRANCH, &encode_branch_key(&branch_key), &location)?;
+        compacted.branches.push((branch_key, location, copied));
+    }
+    for (leaf_key, location) in leaves {
+        let copied = copy(INSERT_LEAF, leaf_key.as_slice(), &location)?;
+        compacted.leaves.push((leaf_key, location, copied));
+    }
+    writer.write_all(commit).map_err(io_error)?;
+    writer.flush().map_err(io_error)?;
+    drop(writer);
+    file.sync_all().map_err(io_error)?;
+    compacted.size = offset + commit.len() as u64;
+    Ok(compacted)
+}
+
+/// Replay the records of a segment into the index and the counts, return the
+/// end of the last commit and its root
+fn replay(
+    segment: u64,
+    data: &[u8],
+    branches: &mut HashMap<BranchKey, Location>,
+    leaves: &mut HashMap<H256, Location>,
+    counts: &mut Option<(H256, TreeCounts)>,
+) -> Result<(u64, Option<H256>)> {
+    let mut pending = Vec::new();
+    let mut pending_counts = None;
+    let mut committed = 0;
+    let mut root = None;
+    let mut pos = 0;
+
+    while pos + HEADER_SIZE <= data.len() {
+        let kind = data[pos];
+        let len = u32::from_le_bytes(
+            data[pos + 1..pos + HEADER_SIZE]
+                .try_into()
+                .expect("4 bytes"),
+        ) as usize;
+        let payload_start = pos + HEADER_SIZE;
+        let end = payload_start + len + CHECKSUM_SIZE;
+        if end > data.len()
+            || checksum(&data[pos..payload_start + len]) != data[end - CHECKSUM_SIZE..end]
+        {
+            break;
+        }
+        let payload = &data[payload_start..payload_start + len];
+        let location = |key_size: usize| Location {
+            segment,
+            offset: (payload_start + key_size) as u64,
+            len: (len - key_size) as u32,
+        };
+        match kind {
+            INSERT_BRANCH if len > BRANCH_KEY_SIZE => pending.push(IndexWrite::Branch(
+                decode_branch_key(&payload[..BRANCH_KEY_SIZE])?,
+                Some(location(BRANCH_KEY_SIZE)),
+            )),
+            REMOVE_BRANCH => pending.push(IndexWrite::Branch(decode_branch_key(payload)?, None)),
+            INSERT_LEAF if len >= 32 => pending.push(IndexWrite::Leaf(
+                H256::try_from(&payload[..32])?,
+                Some(location(32)),
+            )),
+            REMOVE_LEAF => pending.push(IndexWrite::Leaf(H256::try_from(payload)?, None)),
+            COUNTS if len >= 32 => {
+                pending_counts = Some((
+                    H256::try_from(&payload[..32])?,
+                    decode_counts(&payload[32..])?,
+                ))
+            }
+            COMMIT => {
+                for write in pending.drain(..) {
+                    match write {
+                        IndexWrite::Branch(branch_key, Some(location)) => {
+                            branches.insert(branch_key, location);
+                        }
+                        IndexWrite::Branch(branch_key, None) => {
+                            branches.remove(&branch_key);
+                        }
+                        IndexWrite::Leaf(leaf_key, Some(location)) => {
+                            leaves.insert(leaf_key, location);
+                        }
+                        IndexWrite::Leaf(leaf_key, None) => {
+                            leaves.remove(&leaf_key);
+                        }
+                    }
+                }
+                let committed_root = H256::try_from(payload)?;
+                if let Some(pending) = pending_counts.take() {
+                    if pending.0 == committed_root {
+                        *counts = Some(pending);
+                    }
+                }
+                root = Some(committed_root);
+                committed = end as u64;
+            }
+            _ => break,
+        }
+        pos = end;
+    }
+    Ok((committed, root))
+}
+
+impl<V: ValueCodec> StoreReadOps<V> for LogStore<V> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        match self.branches.get(branch_key) {
+            Some(location) => decode_branch(&self.read(location)?).map(Some),
+            None => Ok(None),
+        }
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        match self.leaves.get(leaf_key) {
+            Some(location) => V::decode(&self.read(location)?).map(Some),
+            None => Ok(None),
+        }
+    }
+    fn get_counts(&self, root: &H256) -> Result<Option<TreeCounts>> {
+        Ok(self
+            .counts
+            .as_ref()
+            .filter(|(counted, _)| counted == root)
+            .map(|(_, counts)| counts.clone()))
+    }
+}
+
+impl<V: ValueCodec> StoreWriteOps<V> for LogStore<V> {
+    fn insert_branch(&mut self, branch_key: BranchKey, branch: BranchNode) -> Result<()> {
+        let mut buf = Vec::new();
+        let staged = Self::stage_branch(&mut buf, &branch_key, &branch);
+        self.apply(buf, vec![staged])
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<()> {
+        let mut buf = Vec::new();
+        let staged = Self::stage_leaf(&mut buf, &leaf_key, &leaf);
+        self.apply(buf, vec![staged])
+    }
+    fn remove_branch(&mut self, branch_key: &BranchKey) -> Result<()> {
+        let mut buf = Vec::new();
+        encode_record(&mut buf, REMOVE_BRANCH, &[&encode_branch_key(branch_key)]);
+        self.apply(buf, vec![IndexWrite::Branch(branch_key.clone(), None)])
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<()> {
+        let mut buf = Vec::new();
+        encode_record(&mut buf, REMOVE_LEAF, &[leaf_key.as_slice()]);
+        self.apply(buf, vec![IndexWrite::Leaf(*leaf_key, None)])
+    }
+    fn write_batch(&mut self, batch: WriteBatch<V>) -> Result<()> {
+        // a single append, so that a failing write leaves the index untouched
+        let mut buf = Vec::new();
+        let mut staged = Vec::with_capacity(batch.len());
+        for (branch_key, branch) in &batch.branches {
+            staged.push(match branch {
+                Some(branch) => Self::stage_branch(&mut buf, branch_key, branch),
+                None => {
+                    encode_record(&mut buf, REMOVE_BRANCH, &[&encode_branch_key(branch_key)]);
+                    IndexWrite::Branch(branch_key.clone(), None)
+                }
+            });
+        }
+        for (leaf_key, leaf) in &batch.leaves {
+            staged.push(match leaf {
+                Some(leaf) => Self::stage_leaf(&mut buf, leaf_key, leaf),
+                None => {
+                    encode_record(&mut buf, REMOVE_LEAF, &[leaf_key.as_slice()]);
+                    IndexWrite::Leaf(*leaf_key, None)
+                }
+            });
+        }
+        self.apply(buf, staged)
+    }
+    fn commit_counts(&mut self, root: &H256, counts: &TreeCounts) -> Result<()> {
+        let mut buf = Vec::new();
+        encode_record(&mut buf, COUNTS, &[root.as_slice(), &encode_counts(counts)]);
+        self.append(&buf)?;
+        self.pending_counts = Some((*root, counts.clone()));
+        Ok(())
+    }
+    fn commit_root(&mut self, root: &H256) -> Result<()> {
+        let mut buf = Vec::new();
+        encode_record(&mut buf, COMMIT, &[root.as_slice()]);
+        self.append(&buf)?;
+        if self.options.sync {
+            self.active.sync_data().map_err(io_error)?;
+        }
+        if let Some(pending) = self.pending_counts.take() {
+            if &pending.0 == root {
+                self.counts = Some(pending);
+            }
+        }
+        self.last_root = Some(*root);
+        self.dirty = false;
+        if self.active_size >= self.options.segment_size {
+            self.roll()?;
+        }
+        Ok(())
+    }
+}
+
+impl<V> StoreKeysOps for LogStore<V> {
+    fn branch_keys(&self) -> Result<Vec<BranchKey>> {
+        Ok(self.branches.keys().cloned().collect())
+    }
+    fn leaf_keys(&self) -> Result<Vec<H256>> {
+        Ok(self.leaves.keys().copied().collect())
+    }
+}
@@ -0,0 +1,222 @@
+use core::cmp::Ordering;
+use core::marker::PhantomData;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::{
+    codec::{
+        decode_branch, encode_branch, encode_branch_key, ValueCodec, BRANCH_KEY_SIZE,
+        BRANCH_NODE_SIZE,
+    },
+    error::{Error, Result},
+    h256::H256,
+    traits::StoreReadOps,
+    tree::{BranchKey, BranchNode, ChildKey},
+};
+
+const MAGIC: &[u8; 8] = b"SMTMMAP1";
+// magic, root, branch count and leaf count
+const HEADER_SIZE: usize = 8 + 32 + 8 + 8;
+const BRANCH_ENTRY_SIZE: usize = BRANCH_KEY_SIZE + BRANCH_NODE_SIZE;
+// leaf key, then offset and length of the value in the value section
+const LEAF_ENTRY_SIZE: usize = 32 + 8 + 4;
+
+fn io_error(err: std::io::Error) -> Error {
+    Error::Store(err.to_string())
+}
+
+fn corrupted(path: &Path) -> Error {
+    Error::Store(format!("Corrupted tree file {}", path.display()))
+}
+
+/// Immutable store reading a tree file through a memory map
+///
+/// The file holds the branches sorted by encoded key, the leaf keys sorted
+/// with the position of their values, then the values. Reads are binary
+/// searches in the mapped file, so processes opening the same file share its
+/// pages instead of each holding the tree in memory. `build` writes the file
+/// next to its final path and renames it, so that files being read are never
+/// modified.
+pub struct MmapStore<V> {
+    map: Mmap,
+    root: H256,
+    branch_count: usize,
+    leaf_count: usize,
+    phantom: PhantomData<V>,
+}
+
+impl<V> MmapStore<V> {
+    /// Map the tree file at path
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(io_error)?;
+        // SAFETY: tree files are never modified once written, `build`
+        // replaces them by renaming a new file over them
+        let map = unsafe { Mmap::map(&file) }.map_err(io_error)?;
+
+        if map.len() < HEADER_SIZE || &map[..8] != MAGIC {
+            return Err(corrupted(path));
+        }
+        let root = H256::try_from(&map[8..40])?;
+        let count = |at: usize| {
+            usize::try_from(u64::from_le_bytes(
+                map[at..at + 8].try_into().expect("8 bytes"),
+            ))
+            .map_err(|_| corrupted(path))
+        };
+        let (branch_count, leaf_count) = (count(40)?, count(48)?);
+        let values = branch_count
+            .checked_mul(BRANCH_ENTRY_SIZE)
+            .zip(leaf_count.checked_mul(LEAF_ENTRY_SIZE))
+            .and_then(|(branches, leaves)| branches.checked_add(leaves))
+            .and_then(|entries| entries.checked_add(HEADER_SIZE))
+            .ok_or_else(|| corrupted(path))?;
+        if map.len() < values {
+            return Err(corrupted(path));
+        }
+        Ok(MmapStore {
+            map,
+            root,
+            branch_count,
+            leaf_count,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Root of the tree held by the file
+    pub fn root(&self) -> &H256 {
+        &self.root
+    }
+
+    /// Number of branches held by the file
+    pub fn branch_count(&self) -> usize {
+        self.branch_count
+    }
+
+    /// Number of leaves held by the file, including the bound leaves
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+
+    fn branch_entry(&self, i: usize) -> &[u8] {
+        let start = HEADER_SIZE + i * BRANCH_ENTRY_SIZE;
+        &self.map[start..start + BRANCH_ENTRY_SIZE]
+    }
+
+    fn leaf_entry(&self, i: usize) -> &[u8] {
+        let start = HEADER_SIZE + self.branch_count * BRANCH_ENTRY_SIZE + i * LEAF_ENTRY_SIZE;
+        &self.map[start..start + LEAF_ENTRY_SIZE]
+    }
+
+    fn values_start(&self) -> usize {
+        HEADER_SIZE + self.branch_count * BRANCH_ENTRY_SIZE + self.leaf_count * LEAF_ENTRY_SIZE
+    }
+}
+
+/// Index of the entry whose key is key, entries are sorted by key
+fn search<'a>(count: usize, entry: impl Fn(usize) -> &'a [u8], key: &[u8]) -> Option<usize> {
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match entry(mid)[..key.len()].cmp(key) {
+            Ordering::Less => low = mid + 1,
+            Ordering::Greater => high = mid,
+            Ordering::Equal => return Some(mid),
+        }
+    }
+    None
+}
+
+impl<V: ValueCodec> MmapStore<V> {
+    /// Write the tree of store at root to a file at path
+    ///
+    /// Only the nodes reachable from root are written, so a store holding
+    /// stale nodes is compacted on the way.
+    pub fn build<S: StoreReadOps<V>, P: AsRef<Path>>(
+        store: &S,
+        root: &H256,
+        path: P,
+    ) -> Result<()> {
+        let mut branches = Vec::new();
+        let mut leaves = Vec::new();
+        if !root.is_zero() {
+            let mut stack = vec![ChildKey::Branch(BranchKey::root())];
+            while let Some(child) = stack.pop() {
+                match child {
+                    ChildKey::Leaf(key) => {
+                        let leaf = store.get_leaf(&key)?.ok_or(Error::MissingLeaf(key))?;
+                        leaves.push((key, leaf.encode()));
+                    }
+                    ChildKey::Branch(branch_key) => {
+                        let branch = store
+                            .get_branch(&branch_key)?
+                            .ok_or(Error::MissingBranch(branch_key.height, branch_key.node_key))?;
+                        stack.push(branch.left.1.clone());
+                        stack.push(branch.right.1.clone());
+                        branches.push((encode_branch_key(&branch_key), encode_branch(&branch)));
+                    }
+                }
+            }
+        }
+        branches.sort_unstable_by_key(|(key, _)| *key);
+        leaves.sort_unstable_by_key(|(key, _)| *key);
+
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let file = File::create(&tmp).map_err(io_error)?;
+        let mut writer = BufWriter::new(&file);
+        let mut write = |bytes: &[u8]| writer.write_all(bytes).map_err(io_error);
+
+        write(MAGIC)?;
+        write(root.as_slice())?;
+        write(&(branches.len() as u64).to_le_bytes())?;
+        write(&(leaves.len() as u64).to_le_bytes())?;
+        for (key, branch) in &branches {
+            write(key)?;
+            write(branch)?;
+        }
+        let mut offset = 0u64;
+        for (key, value) in &leaves {
+            write(key.as_slice())?;
+            write(&offset.to_le_bytes())?;
+            write(&(value.len() as u32).to_le_bytes())?;
+            offset += value.len() as u64;
+        }
+        for (_, value) in &leaves {
+            write(value)?;
+        }
+        writer.flush().map_err(io_error)?;
+        drop(writer);
+        file.sync_all().map_err(io_error)?;
+        fs::rename(&tmp, path).map_err(io_error)
+    }
+}
+
+impl<V: ValueCodec> StoreReadOps<V> for MmapStore<V> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        let key = encode_branch_key(branch_key);
+        search(self.branch_count, |i| self.branch_entry(i), &key)
+            .map(|i| decode_branch(&self.branch_entry(i)[BRANCH_KEY_SIZE..]))
+            .transpose()
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        let i = match search(self.leaf_count, |i| self.leaf_entry(i), leaf_key.as_slice()) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let entry = self.leaf_entry(i);
+        let offset = u64::from_le_bytes(entry[32..40].try_into().expect("8 bytes"));
+        let len = u32::from_le_bytes(entry[40..44].try_into().expect("4 bytes"));
+        let start = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| offset.checked_add(self.values_start()));
+        let value = start
+            .and_then(|start| self.map.get(start..start.checked_add(len as usize)?))
+            .ok_or(Error::Store("Corrupted tree file".to_string()))?;
+        V::decode(value).map(Some)
+    }
+}
@@ -0,0 +1,502 @@
+use core::fmt::Debug;
+use std::collections::BTreeSet;
+
+#[cfg(feature = "tracing")]
+use crate::instrumented_store::{store_span, StoreSpan};
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    merge::{merge, MergeValue},
+    merkle_proof::Side,
+    traits::{Hasher, StoreReadOps, Value},
+    tree::{is_bound_key, BranchKey, BranchNode, ChildKey, SparseMerkleTree},
+};
+
+/// Byte of `ProofOp::Leaf` in encoded proofs
+pub const OP_LEAF: u8 = 0;
+/// Byte of `ProofOp::Merge` in encoded proofs
+pub const OP_MERGE: u8 = 1;
+/// Byte of a left `ProofOp::Sibling` in encoded proofs, followed by its hash
+pub const OP_LEFT_SIBLING: u8 = 2;
+/// Byte of a right `ProofOp::Sibling` in encoded proofs, followed by its hash
+pub const OP_RIGHT_SIBLING: u8 = 3;
+/// Byte of `ProofOp::Neighbour` in encoded proofs, followed by its key
+pub const OP_NEIGHBOUR: u8 = 4;
+
+/// Step of a `MultiProof`, run by a stack machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProofOp {
+    /// Push the hash of the next proven leaf
+    Leaf,
+    /// Push the hash of the leaf at a key next to the proven ones
+    Neighbour(H256),
+    /// Merge the top of the stack with a sibling on the given side
+    Sibling(Side),
+    /// Merge the two topmost hashes, the topmost one being on the right
+    Merge,
+}
+
+/// Proof of several members at once
+///
+/// Every branch on the path of a proven leaf is visited once, so siblings
+/// shared by several paths appear once, and siblings that are themselves
+/// on a proven path are not part of the proof at all.
+///
+/// Leaves are proven as keys whose value is the key itself, like the bound
+/// leaves and the members of the Aiken library, so that a leaf hash commits
+/// its key. Proofs of insertions and removals also reveal the leaves next
+/// to each member as neighbours, which ties the members to their place in
+/// key order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiProof {
+    ops: Vec<ProofOp>,
+}
+
+impl MultiProof {
+    pub fn new(ops: Vec<ProofOp>) -> Self {
+        MultiProof { ops }
+    }
+
+    /// Destruct the structure, useful for serialization
+    pub fn take(self) -> Vec<ProofOp> {
+        self.ops
+    }
+
+    /// Encode the proof as read by the Aiken `multi_blake256` module: one
+    /// byte per step, followed by the sibling hash for sibling steps and by
+    /// the key for neighbour steps
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let hashes = self.siblings_count() + self.neighbours_count();
+        let mut bytes = Vec::with_capacity(self.ops.len() + 32 * hashes);
+        for op in &self.ops {
+            match op {
+                ProofOp::Leaf => bytes.push(OP_LEAF),
+                ProofOp::Merge => bytes.push(OP_MERGE),
+                ProofOp::Neighbour(key) => {
+                    bytes.push(OP_NEIGHBOUR);
+                    bytes.extend_from_slice(key.as_slice());
+                }
+                ProofOp::Sibling(Side::Left(sibling)) => {
+                    bytes.push(OP_LEFT_SIBLING);
+                    bytes.extend_from_slice(sibling.hash().as_slice());
+                }
+                ProofOp::Sibling(Side::Right(sibling)) => {
+                    bytes.push(OP_RIGHT_SIBLING);
+                    bytes.extend_from_slice(sibling.hash().as_slice());
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Decode a proof encoded by `to_bytes`
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let mut ops = Vec::new();
+        while let Some((&code, rest)) = bytes.split_first() {
+            bytes = rest;
+            let op = match code {
+                OP_LEAF => ProofOp::Leaf,
+                OP_MERGE => ProofOp::Merge,
+                OP_LEFT_SIBLING | OP_RIGHT_SIBLING | OP_NEIGHBOUR => {
+                    let (hash, rest) = bytes.split_at(bytes.len().min(32));
+                    bytes = rest;
+                    let hash = H256::try_from(hash)?;
+                    match code {
+                        OP_LEFT_SIBLING => {
+                            ProofOp::Sibling(Side::Left(MergeValue::from_h256(hash)))
+                        }
+                        OP_RIGHT_SIBLING => {
+                            ProofOp::Sibling(Side::Right(MergeValue::from_h256(hash)))
+                        }
+                        _ => ProofOp::Neighbour(hash),
+                    }
+                }
+                code => return Err(Error::InvalidCode(code)),
+            };
+            ops.push(op);
+        }
+        Ok(MultiProof::new(ops))
+    }
+
+    /// Steps of the proof, in execution order
+    pub fn ops(&self) -> &[ProofOp] {
+        &self.ops
+    }
+
+    /// Number of leaves the proof expects
+    pub fn leaves_count(&self) -> usize {
+        self.ops.iter().filter(|op| **op == ProofOp::Leaf).count()
+    }
+
+    /// Number of sibling hashes carried by the proof
+    pub fn siblings_count(&self) -> usize {
+        self.ops
+            .iter()
+            .filter(|op| matches!(op, ProofOp::Sibling(_)))
+            .count()
+    }
+
+    /// Number of neighbouring leaves revealed by the proof
+    pub fn neighbours_count(&self) -> usize {
+        self.ops
+            .iter()
+            .filter(|op| matches!(op, ProofOp::Neighbour(_)))
+            .count()
+    }
+
+    /// Compute the root of the tree holding keys
+    ///
+    /// Keys are the proven members, taken in key order whatever their order
+    /// in keys. The leaves the proof reveals must come in key order.
+    pub fn compute_root<H: Hasher + Default>(&self, keys: Vec<H256>) -> Result<H256> {
+        let (root, _) = self.run::<H>(keys, false)?;
+        Ok(root)
+    }
+
+    /// Compute the roots of the tree holding keys and of the same tree
+    /// without them
+    ///
+    /// Dropping a leaf drops the branch above it, its sibling taking the
+    /// place of the branch: this hashes the same as merging the sibling with
+    /// a zero hash, so both roots are computed from the same proof. Every
+    /// member must be next to revealed leaves only, so that the tree without
+    /// the members holds no leaf between their neighbours.
+    pub fn compute_roots<H: Hasher + Default>(&self, keys: Vec<H256>) -> Result<(H256, H256)> {
+        self.run::<H>(keys, true)
+    }
+
+    /// Check that keys are members of the tree at root
+    pub fn verify<H: Hasher + Default>(&self, root: &H256, keys: Vec<H256>) -> Result<bool> {
+        Ok(&self.compute_root::<H>(keys)? == root)
+    }
+
+    /// Check that inserting keys moves the tree from old_root to new_root,
+    /// the proof being made in the tree at new_root
+    ///
+    /// The tree at old_root holds the neighbours of every member next to
+    /// each other, so it can not hold the members already.
+    ///
+    /// Branches hash no height, so the proof does not bind where a member
+    /// forks from its neighbours: a member may be merged with either of them,
+    /// giving a new_root whose tree is not the one updates build. Only the
+    /// members and old_root are checked, new_root must be compared with the
+    /// root returned by `modify_root_multi_proof` or reached by `update`.
+    pub fn verify_insertion<H: Hasher + Default>(
+        &self,
+        old_root: &H256,
+        new_root: &H256,
+        keys: Vec<H256>,
+    ) -> Result<bool> {
+        let (with_keys, without_keys) = self.compute_roots::<H>(keys)?;
+        Ok(&with_keys == new_root && &without_keys == old_root)
+    }
+
+    /// Check that removing keys moves the tree from old_root to new_root,
+    /// the proof being made in the tree at old_root
+    ///
+    /// Like `verify_insertion`, the shape of the tree at old_root is not
+    /// checked: a tree holding the members where updates would not put them
+    /// is accepted, so old_root must be a root the tree reached.
+    pub fn verify_deletion<H: Hasher + Default>(
+        &self,
+        old_root: &H256,
+        new_root: &H256,
+        keys: Vec<H256>,
+    ) -> Result<bool> {
+        let (with_keys, without_keys) = self.compute_roots::<H>(keys)?;
+        Ok(&with_keys == old_root && &without_keys == new_root)
+    }
+
+    /// Run the steps over the proven keys, return the roots with and without
+    /// them
+    ///
+    /// Revealed leaves must come in strictly increasing key order. With
+    /// bounded, no member may be next to a hidden subtree or at an edge of
+    /// the tree.
+    fn run<H: Hasher + Default>(&self, mut keys: Vec<H256>, bounded: bool) -> Result<(H256, H256)> {
+        if self.ops.is_empty() {
+            return Err(Error::EmptyProof);
+        }
+        let expected = self.leaves_count();
+        if keys.len() != expected {
+            return Err(Error::IncorrectNumberOfLeaves {
+                expected,
+                actual: keys.len(),
+            });
+        }
+        keys.sort_unstable();
+
+        let check = |edge: &Edge, other: &Edge| match (edge, other) {
+            (Edge::Member(key), Edge::Hidden) if bounded => Err(Error::MissingNeighbour(*key)),
+            _ => Ok(()),
+        };
+        let mut keys = keys.into_iter();
+        let mut previous = None;
+        let mut stack: Vec<ProofNode> = Vec::new();
+        for op in &self.ops {
+            let node = match op {
+                ProofOp::Leaf | ProofOp::Neighbour(_) => {
+                    let (key, edge) = match op {
+                        ProofOp::Neighbour(key) => (*key, Edge::Neighbour),
+                        _ => {
+                            let key = keys.next().ok_or(Error::CorruptedProof)?;
+                            (key, Edge::Member(key))
+                        }
+                    };
+                    if previous.is_some_and(|previous| previous >= key) {
+                        return Err(Error::MisplacedLeaf(key));
+                    }
+                    previous = Some(key);
+                    let leaf = MergeValue::from_h256(key.to_h256::<H>());
+                    ProofNode {
+                        without: match edge {
+                            Edge::Member(_) => MergeValue::zero(),
+                            _ => leaf.clone(),
+                        },
+                        with: leaf,
+                        first: edge,
+                        last: edge,
+                    }
+                }
+                ProofOp::Sibling(sibling) => {
+                    let node = stack.pop().ok_or(Error::CorruptedStack)?;
+                    match sibling {
+                        Side::Left(left) => {
+                            check(&node.first, &Edge::Hidden)?;
+                            ProofNode {
+                                with: merge::<H>(left, &node.with),
+                                without: merge::<H>(left, &node.without),
+                                first: Edge::Hidden,
+                                last: node.last,
+                            }
+                        }
+                        Side::Right(right) => {
+                            check(&node.last, &Edge::Hidden)?;
+                            ProofNode {
+                                with: merge::<H>(&node.with, right),
+                                without: merge::<H>(&node.without, right),
+                                first: node.first,
+                                last: Edge::Hidden,
+                            }
+                        }
+                    }
+                }
+                ProofOp::Merge => {
+                    let right = stack.pop().ok_or(Error::CorruptedStack)?;
+                    let left = stack.pop().ok_or(Error::CorruptedStack)?;
+                    check(&left.last, &right.first)?;
+                    check(&right.first, &left.last)?;
+                    ProofNode {
+                        with: merge::<H>(&left.with, &right.with),
+                        without: merge::<H>(&left.without, &right.without),
+                        first: left.first,
+                        last: right.last,
+                    }
+                }
+            };
+            stack.push(node);
+        }
+
+        match (stack.pop(), stack.is_empty()) {
+            (Some(root), true) => {
+                // the bound leaves are at the edges of every tree
+                check(&root.first, &Edge::Hidden)?;
+                check(&root.last, &Edge::Hidden)?;
+                Ok((root.with.hash(), root.without.hash()))
+            }
+            _ => Err(Error::CorruptedStack),
+        }
+    }
+}
+
+/// Leaf at an edge of a `ProofNode`
+#[derive(Debug, Clone, Copy)]
+enum Edge {
+    /// A proven member
+    Member(H256),
+    /// A revealed neighbour
+    Neighbour,
+    /// A leaf below a sibling hash
+    Hidden,
+}
+
+/// Subtree computed by `MultiProof::run`
+struct ProofNode {
+    /// Hash of the subtree
+    with: MergeValue,
+    /// Hash of the subtree without the members, zero if it only holds members
+    without: MergeValue,
+    /// Leftmost leaf of the subtree
+    first: Edge,
+    /// Rightmost leaf of the subtree
+    last: Edge,
+}
+
+/// Multi-member proofs hash every leaf from its key, so they are only made
+/// over trees whose leaves hold their own key
+impl<H: Hasher + Default, S: StoreReadOps<H256>> SparseMerkleTree<H, H256, S> {
+    /// Generate a single proof of every member of keys, every leaf revealed
+    /// must hold its key as value
+    pub fn multi_proof(&self, keys: Vec<H256>) -> Result<MultiProof> {
+        #[cfg(feature = "tracing")]
+        let span = StoreSpan::open(store_span!("multi_proof", keys = keys.len()), self.store());
+        let proof = self.build_multi_proof(keys, false);
+        #[cfg(feature = "tracing")]
+        span.close(self.store());
+        proof
+    }
+
+    /// Generate the proof of keys, revealing the neighbours of every member
+    /// with neighbours
+    fn build_multi_proof(&self, mut keys: Vec<H256>, neighbours: bool) -> Result<MultiProof> {
+        if keys.is_empty() {
+            return Err(Error::EmptyKeys);
+        }
+        if let Some(key) = keys.iter().find(|key| is_bound_key(key)) {
+            return Err(Error::ReservedKey(*key));
+        }
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut revealed = keys.clone();
+        if neighbours {
+            revealed.extend(self.neighbour_keys(&keys)?);
+            revealed.sort_unstable();
+        }
+        let mut ops = Vec::new();
+        let root = ChildKey::Branch(BranchKey::root());
+        self.prove_subtree(&root, &revealed, &keys, &mut ops)?;
+        Ok(MultiProof::new(ops))
+    }
+
+    /// Keys of the leaves right before and right after each of keys, but
+    /// keys themselves
+    fn neighbour_keys(&self, keys: &[H256]) -> Result<BTreeSet<H256>> {
+        let mut neighbours = BTreeSet::new();
+        for key in keys {
+            // the closest subtrees on each side of the path to key
+            let (mut before, mut after) = (None, None);
+            let mut child = ChildKey::Branch(BranchKey::root());
+            while let ChildKey::Branch(branch_key) = child {
+                let branch = self.get_proof_branch(&branch_key)?;
+                if *key <= branch.left.1.highest_key() {
+                    after = Some(branch.right.1);
+                    child = branch.left.1;
+                } else {
+                    before = Some(branch.left.1);
+                    child = branch.right.1;
+                }
+            }
+            if child != ChildKey::Leaf(*key) {
+                return Err(Error::LeafNotFound(*key));
+            }
+            if let Some(before) = before {
+                neighbours.insert(self.edge_leaf(before, true)?);
+            }
+            if let Some(after) = after {
+                neighbours.insert(self.edge_leaf(after, false)?);
+            }
+        }
+        neighbours.retain(|neighbour| keys.binary_search(neighbour).is_err());
+        Ok(neighbours)
+    }
+
+    /// Key of the last leaf below child, or of the first one
+    fn edge_leaf(&self, mut child: ChildKey, last: bool) -> Result<H256> {
+        loop {
+            match child {
+                ChildKey::Leaf(key) => return Ok(key),
+                ChildKey::Branch(branch_key) => {
+                    let branch = self.get_proof_branch(&branch_key)?;
+                    child = if last { branch.right.1 } else { branch.left.1 };
+                }
+            }
+        }
+    }
+
+    fn get_proof_branch(&self, branch_key: &BranchKey) -> Result<BranchNode> {
+        self.store()
+            .get_branch(branch_key)?
+            .ok_or(Error::MissingBranch(branch_key.height, branch_key.node_key))
+    }
+
+    /// Push the steps revealing keys, all of them below child, the ones not
+    /// in members being revealed as neighbours
+    fn prove_subtree(
+        &self,
+        child: &ChildKey,
+        keys: &[H256],
+        members: &[H256],
+        ops: &mut Vec<ProofOp>,
+    ) -> Result<()> {
+        let branch_key = match child {
+            ChildKey::Leaf(leaf_key) => {
+                if let Some(key) = keys.iter().find(|key| *key != leaf_key) {
+                    return Err(Error::LeafNotFound(*key));
+                }
+                // the verifier hashes the key in place of the value
+                if self.store().get_leaf(leaf_key)? != Some(*leaf_key) {
+                    return Err(Error::LeafNotKey(*leaf_key));
+                }
+                if members.binary_search(leaf_key).is_ok() {
+                    ops.push(ProofOp::Leaf);
+                } else {
+                    ops.push(ProofOp::Neighbour(*leaf_key));
+                }
+                return Ok(());
+            }
+            ChildKey::Branch(branch_key) => branch_key,
+        };
+        let branch = self.get_proof_branch(branch_key)?;
+
+        let left_highest = branch.left.1.highest_key();
+        let split = keys.partition_point(|key| key <= &left_highest);
+        match (&keys[..split], &keys[split..]) {
+            (left, []) => {
+                self.prove_subtree(&branch.left.1, left, members, ops)?;
+                ops.push(ProofOp::Sibling(Side::Right(branch.right.0)));
+            }
+            ([], right) => {
+                self.prove_subtree(&branch.right.1, right, members, ops)?;
+                ops.push(ProofOp::Sibling(Side::Left(branch.left.0)));
+            }
+            (left, right) => {
+                self.prove_subtree(&branch.left.1, left, members, ops)?;
+                self.prove_subtree(&branch.right.1, right, members, ops)?;
+                ops.push(ProofOp::Merge);
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate a single proof that all keys are inserted into the tree, or
+    /// all removed from it, along with the root the tree moves to
+    ///
+    /// Inserted leaves hold their key as value. The proof is a `MultiProof`
+    /// of the keys in the tree holding them, revealing their neighbours, to
+    /// be checked with `verify_insertion` or `verify_deletion`. The tree
+    /// itself is left untouched.
+    pub fn modify_root_multi_proof(
+        &self,
+        keys: Vec<H256>,
+        insertion: bool,
+    ) -> Result<(H256, MultiProof)> {
+        if keys.is_empty() {
+            return Err(Error::EmptyKeys);
+        }
+        let mut overlay = self.overlay();
+        if insertion {
+            for key in &keys {
+                overlay.update(*key, *key, true)?;
+            }
+            let proof = overlay.build_multi_proof(keys, true)?;
+            Ok((*overlay.root(), proof))
+        } else {
+            let proof = self.build_multi_proof(keys.clone(), true)?;
+            for key in keys {
+                overlay.update(key, H256::zero(), false)?;
+            }
+            Ok((*overlay.root(), proof))
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    instrumented_store::StoreMetrics,
+    traits::{Hasher, StoreReadOps, StoreWriteOps, WriteBatch},
+    tree::{BranchKey, BranchNode, SparseMerkleTree, TreeCounts},
+};
+
+/// In memory writes layered over a read only base store
+///
+/// Reads see the writes made through the overlay first, then the base store,
+/// which is never modified. The writes can be dropped with the overlay, or
+/// taken as a `WriteBatch` and applied to the base store once it is no longer
+/// borrowed.
+#[derive(Debug, Clone)]
+pub struct OverlayStore<'a, V, S> {
+    base: &'a S,
+    // root of the tree held by base when the overlay was opened
+    base_root: H256,
+    batch: WriteBatch<V>,
+}
+
+impl<'a, V, S> OverlayStore<'a, V, S> {
+    /// Layer an empty overlay over base, holding the tree at base_root
+    pub fn new(base: &'a S, base_root: H256) -> Self {
+        OverlayStore {
+            base,
+            base_root,
+            batch: WriteBatch::default(),
+        }
+    }
+
+    /// Get the base store
+    pub fn base(&self) -> &'a S {
+        self.base
+    }
+
+    /// Root of the tree held by the base store
+    pub fn base_root(&self) -> &H256 {
+        &self.base_root
+    }
+
+    /// Writes made through the overlay
+    pub fn batch(&self) -> &WriteBatch<V> {
+        &self.batch
+    }
+
+    /// Drop every write made through the overlay
+    pub fn discard(&mut self) {
+        self.batch = WriteBatch::default();
+    }
+
+    /// Take the writes made through the overlay
+    pub fn into_batch(self) -> WriteBatch<V> {
+        self.batch
+    }
+}
+
+impl<'a, V: Clone, S: StoreReadOps<V>> StoreReadOps<V> for OverlayStore<'a, V, S> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        match self.batch.branches.get(branch_key) {
+            Some(branch) => Ok(branch.clone()),
+            None => self.base.get_branch(branch_key),
+        }
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        match self.batch.leaves.get(leaf_key) {
+            Some(leaf) => Ok(leaf.clone()),
+            None => self.base.get_leaf(leaf_key),
+        }
+    }
+    fn get_counts(&self, root: &H256) -> Result<Option<TreeCounts>> {
+        self.base.get_counts(root)
+    }
+    fn store_metrics(&self) -> Option<StoreMetrics> {
+        self.base.store_metrics()
+    }
+}
+
+impl<'a, V, S> StoreWriteOps<V> for OverlayStore<'a, V, S> {
+    fn insert_branch(&mut self, branch_key: BranchKey, branch: BranchNode) -> Result<()> {
+        self.batch.insert_branch(branch_key, branch)
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<()> {
+        self.batch.insert_leaf(leaf_key, leaf)
+    }
+    fn remove_branch(&mut self, branch_key: &BranchKey) -> Result<()> {
+        self.batch.remove_branch(branch_key)
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<()> {
+        self.batch.remove_leaf(leaf_key)
+    }
+}
+
+impl<H, V, S> SparseMerkleTree<H, V, S> {
+    /// Open a tree over an overlay of this one, to update it speculatively
+    pub fn overlay(&self) -> SparseMerkleTree<H, V, OverlayStore<'_, V, S>> {
+        SparseMerkleTree::with_counts(
+            *self.root(),
+            OverlayStore::new(self.store(), *self.root()),
+            self.counts().cloned(),
+        )
+    }
+}
+
+/// Writes of an overlay tree, with the roots they lead from and to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StagedBatch<V> {
+    /// Root of the tree the overlay was opened over
+    pub base_root: H256,
+    /// Root of the overlay tree
+    pub root: H256,
+    /// Counts of the overlay tree
+    pub counts: Option<TreeCounts>,
+    /// Writes leading from base_root to root
+    pub batch: WriteBatch<V>,
+}
+
+impl<'a, H, V, S> SparseMerkleTree<H, V, OverlayStore<'a, V, S>> {
+    /// Take the root of the overlay tree along with the writes that lead to it
+    pub fn into_batch(self) -> StagedBatch<V> {
+        let root = *self.root();
+        let counts = self.counts().cloned();
+        let store = self.take_store();
+        StagedBatch {
+            base_root: *store.base_root(),
+            root,
+            counts,
+            batch: store.into_batch(),
+        }
+    }
+}
+
+impl<H: Hasher + Default, V, S: StoreWriteOps<V>> SparseMerkleTree<H, V, S> {
+    /// Commit the writes of an overlay tree taken with `into_batch`
+    /// the tree must still be at the root the overlay was opened at
+    pub fn apply_batch(&mut self, staged: StagedBatch<V>) -> Result<&H256> {
+        if staged.base_root != *self.root() {
+            return Err(Error::StaleBatch {
+                base_root: staged.base_root,
+                root: *self.root(),
+            });
+        }
+        self.commit_staged(staged)?;
+        Ok(self.root())
+    }
+
+    /// Write the batch of staged and move the tree to its root, the root it
+    /// was staged over is not checked
+    pub(crate) fn commit_staged(&mut self, staged: StagedBatch<V>) -> Result<()> {
+        self.store_mut().write_batch(staged.batch)?;
+        if let Some(counts) = &staged.counts {
+            self.store_mut().commit_counts(&staged.root, counts)?;
+        }
+        self.store_mut().commit_root(&staged.root)?;
+        self.set_root(staged.root, staged.counts);
+        Ok(())
+    }
+}
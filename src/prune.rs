@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    merge::merge,
+    traits::{Hasher, StoreKeysOps, StoreReadOps, StoreWriteOps},
+    tree::{BranchKey, BranchNode, ChildKey},
+};
+
+/// Space reclaimed by a prune
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    /// Number of roots that can no longer be opened
+    pub roots_removed: usize,
+    /// Number of branch entries removed
+    pub branches_removed: usize,
+    /// Number of leaf entries removed
+    pub leaves_removed: usize,
+}
+
+/// Nodes reachable from the roots kept by a prune
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reachable {
+    branches: HashSet<BranchKey>,
+    leaves: HashSet<H256>,
+}
+
+impl Reachable {
+    /// Whether a kept tree has a branch at branch_key
+    pub fn contains_branch(&self, branch_key: &BranchKey) -> bool {
+        self.branches.contains(branch_key)
+    }
+
+    /// Whether a kept tree has a leaf at leaf_key
+    pub fn contains_leaf(&self, leaf_key: &H256) -> bool {
+        self.leaves.contains(leaf_key)
+    }
+
+    /// Mark every node of the tree whose branches get_branch reads
+    fn mark(
+        &mut self,
+        get_branch: impl Fn(&BranchKey) -> Result<Option<BranchNode>>,
+    ) -> Result<()> {
+        let mut stack = vec![ChildKey::Branch(BranchKey::root())];
+        while let Some(child) = stack.pop() {
+            match child {
+                ChildKey::Leaf(key) => {
+                    self.leaves.insert(key);
+                }
+                ChildKey::Branch(branch_key) => {
+                    let branch = get_branch(&branch_key)?
+                        .ok_or(Error::MissingBranch(branch_key.height, branch_key.node_key))?;
+                    stack.push(branch.left.1);
+                    stack.push(branch.right.1);
+                    self.branches.insert(branch_key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Root of the tree held by store
+fn current_root<H: Hasher + Default, V, S: StoreReadOps<V>>(store: &S) -> Result<H256> {
+    Ok(match store.get_branch(&BranchKey::root())? {
+        Some(branch) => merge::<H>(&branch.left.0, &branch.right.0).hash(),
+        None => H256::zero(),
+    })
+}
+
+/// Drop every root retained by store but keep_roots, along with the nodes
+/// that only the dropped roots reach
+///
+/// The nodes reachable from each kept root, and from the current one which
+/// is always kept, are marked, then the store releases the other roots and
+/// every node it held for them alone. The nodes the store still lists that
+/// no kept tree reaches are then swept, so stores holding a single tree, which
+/// retain no other root and release nothing, drop their unreachable nodes
+/// too.
+pub fn prune<H, V, S>(store: &mut S, keep_roots: &[H256]) -> Result<PruneReport>
+where
+    H: Hasher + Default,
+    S: StoreReadOps<V> + StoreWriteOps<V> + StoreKeysOps,
+{
+    let root = current_root::<H, V, S>(store)?;
+    let retained = store.retained_roots()?;
+    if let Some(missing) = keep_roots
+        .iter()
+        .find(|kept| **kept != root && !retained.contains(kept))
+    {
+        return Err(Error::MissingRoot(*missing));
+    }
+
+    let mut reachable = Reachable::default();
+    if !root.is_zero() {
+        reachable.mark(|branch_key| store.get_branch(branch_key))?;
+    }
+    let mut marked = HashSet::from([root]);
+    for kept in keep_roots {
+        if !kept.is_zero() && marked.insert(*kept) {
+            reachable.mark(|branch_key| store.get_branch_at(kept, branch_key))?;
+        }
+    }
+    let mut report = store.release_roots(keep_roots, &reachable)?;
+    remove_unreachable(store, &reachable, &mut report)?;
+    Ok(report)
+}
+
+/// Remove every branch and leaf not reachable from the current root of store
+///
+/// Nodes left behind by an interrupted write or by a foreign writer are only
+/// found by listing the keys of the store. Through a `JournalStore` each
+/// removal is journaled like the writes of an update, and is undone by a
+/// rollback.
+pub fn sweep<H, V, S>(store: &mut S) -> Result<PruneReport>
+where
+    H: Hasher + Default,
+    S: StoreReadOps<V> + StoreWriteOps<V> + StoreKeysOps,
+{
+    let mut reachable = Reachable::default();
+    if !current_root::<H, V, S>(store)?.is_zero() {
+        reachable.mark(|branch_key| store.get_branch(branch_key))?;
+    }
+
+    let mut report = PruneReport::default();
+    remove_unreachable(store, &reachable, &mut report)?;
+    Ok(report)
+}
+
+/// Remove every node listed by store that reachable does not hold
+fn remove_unreachable<V, S: StoreWriteOps<V> + StoreKeysOps>(
+    store: &mut S,
+    reachable: &Reachable,
+    report: &mut PruneReport,
+) -> Result<()> {
+    for branch_key in store.branch_keys()? {
+        if !reachable.contains_branch(&branch_key) {
+            store.remove_branch(&branch_key)?;
+            report.branches_removed += 1;
+        }
+    }
+    for leaf_key in store.leaf_keys()? {
+        if !reachable.contains_leaf(&leaf_key) {
+            store.remove_leaf(&leaf_key)?;
+            report.leaves_removed += 1;
+        }
+    }
+    Ok(())
+}
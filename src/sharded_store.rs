@@ -0,0 +1,178 @@
+use core::ops::Add;
+
+use crate::{
+    error::{Error, Result},
+    h256::H256,
+    instrumented_store::StoreMetrics,
+    traits::{StoreKeysOps, StoreReadOps, StoreWriteOps, WriteBatch},
+    tree::{BranchKey, BranchNode, TreeCounts},
+};
+
+/// Store spreading the tree over several child stores by key prefix
+///
+/// With `2^bits` shards, a leaf goes to the shard picked by the top `bits`
+/// bits of its key, and a branch to the shard of the keys it spans. The
+/// branches spanning several shards, the highest ones read by every update
+/// and proof, are kept in a dedicated top store. Batches are split per
+/// store, each written with its own `write_batch`, so an update is only
+/// atomic within each store.
+#[derive(Debug, Clone)]
+pub struct ShardedStore<S> {
+    top: S,
+    shards: Vec<S>,
+    bits: u8,
+}
+
+impl<S> ShardedStore<S> {
+    /// Route the high branches to top and the rest to shards, whose number
+    /// must be a power of two no greater than 256
+    pub fn new(top: S, shards: Vec<S>) -> Result<Self> {
+        if !shards.len().is_power_of_two() || shards.len() > 256 {
+            return Err(Error::Store(format!(
+                "Invalid number of shards {}",
+                shards.len()
+            )));
+        }
+        let bits = shards.len().trailing_zeros() as u8;
+        Ok(ShardedStore { top, shards, bits })
+    }
+
+    /// Get the store holding the branches spanning several shards
+    pub fn top(&self) -> &S {
+        &self.top
+    }
+
+    /// Get the shards, in key order
+    pub fn shards(&self) -> &[S] {
+        &self.shards
+    }
+
+    /// Take the top store and the shards
+    pub fn into_parts(self) -> (S, Vec<S>) {
+        (self.top, self.shards)
+    }
+
+    /// Shard of the keys starting with the top bits of key
+    pub fn shard_of_leaf(&self, key: &H256) -> usize {
+        if self.bits == 0 {
+            0
+        } else {
+            usize::from(key.as_slice()[0] >> (8 - self.bits))
+        }
+    }
+
+    /// Shard of the keys spanned by a branch, None if it spans several
+    pub fn shard_of_branch(&self, branch_key: &BranchKey) -> Option<usize> {
+        if u16::from(branch_key.height) + u16::from(self.bits) > 255 {
+            None
+        } else {
+            Some(self.shard_of_leaf(&branch_key.key_range().0))
+        }
+    }
+
+    fn branch_store(&self, branch_key: &BranchKey) -> &S {
+        match self.shard_of_branch(branch_key) {
+            Some(shard) => &self.shards[shard],
+            None => &self.top,
+        }
+    }
+
+    fn branch_store_mut(&mut self, branch_key: &BranchKey) -> &mut S {
+        match self.shard_of_branch(branch_key) {
+            Some(shard) => &mut self.shards[shard],
+            None => &mut self.top,
+        }
+    }
+}
+
+impl<V, S: StoreReadOps<V>> StoreReadOps<V> for ShardedStore<S> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        self.branch_store(branch_key).get_branch(branch_key)
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        self.shards[self.shard_of_leaf(leaf_key)].get_leaf(leaf_key)
+    }
+    fn get_counts(&self, root: &H256) -> Result<Option<TreeCounts>> {
+        self.top.get_counts(root)
+    }
+    fn store_metrics(&self) -> Option<StoreMetrics> {
+        // the calls made to every store that counts them
+        core::iter::once(&self.top)
+            .chain(&self.shards)
+            .filter_map(StoreReadOps::<V>::store_metrics)
+            .reduce(Add::add)
+    }
+}
+
+impl<V, S: StoreWriteOps<V>> StoreWriteOps<V> for ShardedStore<S> {
+    fn insert_branch(&mut self, branch_key: BranchKey, branch: BranchNode) -> Result<()> {
+        self.branch_store_mut(&branch_key)
+            .insert_branch(branch_key, branch)
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<()> {
+        let shard = self.shard_of_leaf(&leaf_key);
+        self.shards[shard].insert_leaf(leaf_key, leaf)
+    }
+    fn remove_branch(&mut self, branch_key: &BranchKey) -> Result<()> {
+        self.branch_store_mut(branch_key).remove_branch(branch_key)
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<()> {
+        let shard = self.shard_of_leaf(leaf_key);
+        self.shards[shard].remove_leaf(leaf_key)
+    }
+    fn write_batch(&mut self, batch: WriteBatch<V>) -> Result<()> {
+        let mut top = WriteBatch::default();
+        let mut shards = (0..self.shards.len())
+            .map(|_| WriteBatch::default())
+            .collect::<Vec<_>>();
+        for (branch_key, branch) in batch.branches {
+            let split = match self.shard_of_branch(&branch_key) {
+                Some(shard) => &mut shards[shard],
+                None => &mut top,
+            };
+            split.branches.insert(branch_key, branch);
+        }
+        for (leaf_key, leaf) in batch.leaves {
+            shards[self.shard_of_leaf(&leaf_key)]
+                .leaves
+                .insert(leaf_key, leaf);
+        }
+
+        // the shards first, the top store then points at complete subtrees
+        for (store, split) in self.shards.iter_mut().zip(shards) {
+            if !split.is_empty() {
+                store.write_batch(split)?;
+            }
+        }
+        if !top.is_empty() {
+            self.top.write_batch(top)?;
+        }
+        Ok(())
+    }
+    fn commit_counts(&mut self, root: &H256, counts: &TreeCounts) -> Result<()> {
+        self.top.commit_counts(root, counts)
+    }
+    fn commit_root(&mut self, root: &H256) -> Result<()> {
+        for store in &mut self.shards {
+            store.commit_root(root)?;
+        }
+        self.top.commit_root(root)
+    }
+}
+
+impl<S: StoreKeysOps> StoreKeysOps for ShardedStore<S> {
+    fn branch_keys(&self) -> Result<Vec<BranchKey>> {
+        let mut keys = self.top.branch_keys()?;
+        for store in &self.shards {
+            keys.extend(store.branch_keys()?);
+        }
+        Ok(keys)
+    }
+    fn leaf_keys(&self) -> Result<Vec<H256>> {
+        let mut keys = Vec::new();
+        for store in &self.shards {
+            keys.extend(store.leaf_keys()?);
+        }
+        Ok(keys)
+    }
+}
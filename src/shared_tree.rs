@@ -0,0 +1,119 @@
+use core::fmt::Debug;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{
+    error::Result,
+    h256::H256,
+    merkle_proof::Side,
+    traits::{Hasher, StoreReadOps, StoreWriteOps, Value},
+    tree::{ModifyProof, SparseMerkleTree},
+    versioned_store::VersionedStore,
+};
+
+/// Tree shared between threads, many readers alongside a single writer
+///
+/// Readers lock the tree for reading, so every read made under one guard sees
+/// the same root. Updates are computed in an overlay while readers go on, and
+/// the tree is only locked for writing to apply the resulting batch. Writers
+/// are serialized, so the overlay is never applied over a newer root. Over a
+/// `VersionedStore`, proofs can also be made at any retained root.
+#[derive(Debug)]
+pub struct SharedTree<H, V, S> {
+    tree: RwLock<SparseMerkleTree<H, V, S>>,
+    writer: Mutex<()>,
+}
+
+impl<H, V, S> SharedTree<H, V, S> {
+    pub fn new(tree: SparseMerkleTree<H, V, S>) -> Self {
+        SharedTree {
+            tree: RwLock::new(tree),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Lock the tree for reading, the root stays the same until the guard is
+    /// dropped
+    pub fn read(&self) -> RwLockReadGuard<'_, SparseMerkleTree<H, V, S>> {
+        self.tree.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Current root of the tree
+    pub fn root(&self) -> H256 {
+        *self.read().root()
+    }
+
+    /// Retake the tree
+    pub fn into_inner(self) -> SparseMerkleTree<H, V, S> {
+        self.tree
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, SparseMerkleTree<H, V, S>> {
+        self.tree.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn writer(&self) -> MutexGuard<'_, ()> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<H: Hasher + Default, V: Value, S: StoreReadOps<V>> SharedTree<H, V, S> {
+    /// Generate merkle proof along with the root it was made at
+    #[allow(clippy::type_complexity)]
+    pub fn member_proof(&self, keys: Vec<H256>) -> Result<(H256, Vec<(Vec<Side>, H256)>)> {
+        let tree = self.read();
+        Ok((*tree.root(), tree.member_proof(keys)?))
+    }
+
+    /// Generate modify root proof along with the root it was made at
+    pub fn modify_root_proof(&self, keys: Vec<H256>) -> Result<(H256, Vec<ModifyProof>)> {
+        let tree = self.read();
+        Ok((*tree.root(), tree.modify_root_proof(keys)?))
+    }
+}
+
+impl<H: Hasher + Default, V: Value + Clone + Debug, S: StoreReadOps<V> + StoreWriteOps<V>>
+    SharedTree<H, V, S>
+{
+    /// Update a leaf, return new merkle root
+    ///
+    /// Readers are only held back while the update is written to the store.
+    pub fn update(&self, key: H256, value: V, insertion: bool) -> Result<H256> {
+        let _writer = self.writer();
+        let staged = {
+            let tree = self.read();
+            let mut overlay = tree.overlay();
+            overlay.update(key, value, insertion)?;
+            overlay.into_batch()
+        };
+
+        let root = staged.root;
+        self.write().commit_staged(staged)?;
+        Ok(root)
+    }
+}
+
+impl<H: Hasher + Default, V: Value + Clone> SharedTree<H, V, VersionedStore<V>> {
+    /// Generate merkle proof at any root retained by the store
+    #[allow(clippy::type_complexity)]
+    pub fn member_proof_at(&self, root: &H256, keys: Vec<H256>) -> Result<Vec<(Vec<Side>, H256)>> {
+        self.read().snapshot(root)?.member_proof(keys)
+    }
+
+    /// Generate modify root proof at any root retained by the store
+    pub fn modify_root_proof_at(&self, root: &H256, keys: Vec<H256>) -> Result<Vec<ModifyProof>> {
+        self.read().snapshot(root)?.modify_root_proof(keys)
+    }
+
+    /// Get a leaf value at any root retained by the store
+    pub fn get_at(&self, root: &H256, key: &H256) -> Result<V> {
+        self.read().snapshot(root)?.get(key)
+    }
+}
+
+impl<H, V, S> From<SparseMerkleTree<H, V, S>> for SharedTree<H, V, S> {
+    fn from(tree: SparseMerkleTree<H, V, S>) -> Self {
+        SharedTree::new(tree)
+    }
+}
@@ -0,0 +1,236 @@
+use core::marker::PhantomData;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    codec::{
+        decode_branch, decode_branch_key, decode_counts, encode_branch, encode_branch_key,
+        encode_counts, ValueCodec,
+    },
+    error::{Error, Result},
+    h256::H256,
+    traits::{StoreKeysOps, StoreReadOps, StoreWriteOps, WriteBatch},
+    tree::{BranchKey, BranchNode, TreeCounts},
+};
+
+/// Name of the tables used by `SqliteStore::new`
+pub const DEFAULT_TABLE_NAME: &str = "smt";
+
+fn store_error(err: rusqlite::Error) -> Error {
+    Error::Store(err.to_string())
+}
+
+/// Store persisting the tree in two tables of a SQLite database
+///
+/// Branches are kept in `<name>_branches` and leaves in `<name>_leaves`, so
+/// several trees can share a database, the counts of the last committed root
+/// in `<name>_counts`. The store only borrows the connection:
+/// built over a `rusqlite::Transaction` its writes belong to the caller's
+/// transaction. Each `write_batch` runs in a savepoint and is rolled back
+/// entirely if any write fails.
+pub struct SqliteStore<'c, V> {
+    conn: &'c Connection,
+    branches: String,
+    leaves: String,
+    counts: String,
+    phantom: PhantomData<V>,
+}
+
+impl<'c, V> SqliteStore<'c, V> {
+    /// Open the store kept in the default tables, creating them if needed
+    pub fn new(conn: &'c Connection) -> Result<Self> {
+        Self::with_table_name(conn, DEFAULT_TABLE_NAME)
+    }
+
+    /// Open the store kept in the tables named after name, creating them if
+    /// needed, name may only hold ASCII letters, digits and underscores
+    pub fn with_table_name(conn: &'c Connection, name: &str) -> Result<Self> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(Error::Store(format!("Invalid table name {:?}", name)));
+        }
+        let store = SqliteStore {
+            conn,
+            branches: format!("{}_branches", name),
+            leaves: format!("{}_leaves", name),
+            counts: format!("{}_counts", name),
+            phantom: PhantomData,
+        };
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                branch_key BLOB PRIMARY KEY NOT NULL,
+                branch BLOB NOT NULL
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS {} (
+                leaf_key BLOB PRIMARY KEY NOT NULL,
+                leaf BLOB NOT NULL
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS {} (
+                root BLOB PRIMARY KEY NOT NULL,
+                counts BLOB NOT NULL
+            ) WITHOUT ROWID;",
+            store.branches, store.leaves, store.counts
+        ))
+        .map_err(store_error)?;
+        Ok(store)
+    }
+
+    /// Get the connection
+    pub fn connection(&self) -> &'c Connection {
+        self.conn
+    }
+}
+
+impl<'c, V: ValueCodec> StoreReadOps<V> for SqliteStore<'c, V> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        let sql = format!("SELECT branch FROM {} WHERE branch_key = ?1", self.branches);
+        let bytes: Option<Vec<u8>> = self
+            .conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_row(params![&encode_branch_key(branch_key)[..]], |row| {
+                    row.get(0)
+                })
+                .optional()
+            })
+            .map_err(store_error)?;
+        bytes.map(|bytes| decode_branch(&bytes)).transpose()
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        let sql = format!("SELECT leaf FROM {} WHERE leaf_key = ?1", self.leaves);
+        let bytes: Option<Vec<u8>> = self
+            .conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_row(params![leaf_key.as_slice()], |row| row.get(0))
+                    .optional()
+            })
+            .map_err(store_error)?;
+        bytes.map(|bytes| V::decode(&bytes)).transpose()
+    }
+    fn get_counts(&self, root: &H256) -> Result<Option<TreeCounts>> {
+        let sql = format!("SELECT counts FROM {} WHERE root = ?1", self.counts);
+        let bytes: Option<Vec<u8>> = self
+            .conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_row(params![root.as_slice()], |row| row.get(0))
+                    .optional()
+            })
+            .map_err(store_error)?;
+        bytes.map(|bytes| decode_counts(&bytes)).transpose()
+    }
+}
+
+impl<'c, V: ValueCodec> StoreWriteOps<V> for SqliteStore<'c, V> {
+    fn insert_branch(&mut self, branch_key: BranchKey, branch: BranchNode) -> Result<()> {
+        let sql = format!(
+            "INSERT OR REPLACE INTO {} (branch_key, branch) VALUES (?1, ?2)",
+            self.branches
+        );
+        self.conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| {
+                stmt.execute(params![
+                    &encode_branch_key(&branch_key)[..],
+                    &encode_branch(&branch)[..]
+                ])
+            })
+            .map_err(store_error)?;
+        Ok(())
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<()> {
+        let sql = format!(
+            "INSERT OR REPLACE INTO {} (leaf_key, leaf) VALUES (?1, ?2)",
+            self.leaves
+        );
+        self.conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| stmt.execute(params![leaf_key.as_slice(), leaf.encode()]))
+            .map_err(store_error)?;
+        Ok(())
+    }
+    fn remove_branch(&mut self, branch_key: &BranchKey) -> Result<()> {
+        let sql = format!("DELETE FROM {} WHERE branch_key = ?1", self.branches);
+        self.conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| stmt.execute(params![&encode_branch_key(branch_key)[..]]))
+            .map_err(store_error)?;
+        Ok(())
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<()> {
+        let sql = format!("DELETE FROM {} WHERE leaf_key = ?1", self.leaves);
+        self.conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| stmt.execute(params![leaf_key.as_slice()]))
+            .map_err(store_error)?;
+        Ok(())
+    }
+    fn write_batch(&mut self, batch: WriteBatch<V>) -> Result<()> {
+        self.conn
+            .execute_batch("SAVEPOINT smt_write_batch")
+            .map_err(store_error)?;
+        let written = (|| {
+            for (branch_key, branch) in batch.branches {
+                match branch {
+                    Some(branch) => self.insert_branch(branch_key, branch)?,
+                    None => self.remove_branch(&branch_key)?,
+                }
+            }
+            for (leaf_key, leaf) in batch.leaves {
+                match leaf {
+                    Some(leaf) => self.insert_leaf(leaf_key, leaf)?,
+                    None => self.remove_leaf(&leaf_key)?,
+                }
+            }
+            Ok(())
+        })();
+        let end = if written.is_ok() {
+            "RELEASE smt_write_batch"
+        } else {
+            "ROLLBACK TO smt_write_batch; RELEASE smt_write_batch"
+        };
+        self.conn.execute_batch(end).map_err(store_error)?;
+        written
+    }
+    fn commit_counts(&mut self, root: &H256, counts: &TreeCounts) -> Result<()> {
+        // only the counts of the last committed root are kept
+        self.conn
+            .execute(&format!("DELETE FROM {}", self.counts), [])
+            .map_err(store_error)?;
+        let sql = format!("INSERT INTO {} (root, counts) VALUES (?1, ?2)", self.counts);
+        self.conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| stmt.execute(params![root.as_slice(), &encode_counts(counts)[..]]))
+            .map_err(store_error)?;
+        Ok(())
+    }
+}
+
+impl<'c, V> StoreKeysOps for SqliteStore<'c, V> {
+    fn branch_keys(&self) -> Result<Vec<BranchKey>> {
+        let sql = format!("SELECT branch_key FROM {}", self.branches);
+        let keys = self
+            .conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get::<_, Vec<u8>>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .map_err(store_error)?;
+        keys.iter().map(|bytes| decode_branch_key(bytes)).collect()
+    }
+    fn leaf_keys(&self) -> Result<Vec<H256>> {
+        let sql = format!("SELECT leaf_key FROM {}", self.leaves);
+        let keys = self
+            .conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get::<_, Vec<u8>>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .map_err(store_error)?;
+        keys.iter()
+            .map(|bytes| H256::try_from(&bytes[..]))
+            .collect()
+    }
+}
@@ -0,0 +1,185 @@
+use core::future::Future;
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use super::random_keys;
+use crate::{
+    async_store::{AsyncStoreReadOps, AsyncStoreWriteOps},
+    blake2b::Blake2bHasher,
+    default_store::DefaultStore,
+    error::{Error, Result},
+    h256::H256,
+    traits::{StoreReadOps, StoreWriteOps, WriteBatch},
+    tree::{BranchKey, BranchNode, SparseMerkleTree},
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+type AsyncSMT = SparseMerkleTree<Blake2bHasher, H256, AsyncStore>;
+
+/// In memory store answering every read after yielding once
+#[derive(Default)]
+struct AsyncStore {
+    store: DefaultStore<H256>,
+    roots: Vec<H256>,
+    // batches fetched and nodes read through them
+    batches: AtomicUsize,
+    reads: AtomicUsize,
+}
+
+/// Future pending on its first poll
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+impl AsyncStoreReadOps<H256> for AsyncStore {
+    async fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        YieldNow(false).await;
+        self.store.get_branch(branch_key)
+    }
+    async fn get_leaf(&self, leaf_key: &H256) -> Result<Option<H256>> {
+        YieldNow(false).await;
+        self.store.get_leaf(leaf_key)
+    }
+    async fn get_branches(&self, branch_keys: &[BranchKey]) -> Result<Vec<Option<BranchNode>>> {
+        YieldNow(false).await;
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.reads.fetch_add(branch_keys.len(), Ordering::Relaxed);
+        branch_keys
+            .iter()
+            .map(|branch_key| self.store.get_branch(branch_key))
+            .collect()
+    }
+    async fn get_leaves(&self, leaf_keys: &[H256]) -> Result<Vec<Option<H256>>> {
+        YieldNow(false).await;
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.reads.fetch_add(leaf_keys.len(), Ordering::Relaxed);
+        leaf_keys
+            .iter()
+            .map(|leaf_key| self.store.get_leaf(leaf_key))
+            .collect()
+    }
+}
+
+impl AsyncStoreWriteOps<H256> for AsyncStore {
+    async fn write_batch(&mut self, batch: WriteBatch<H256>) -> Result<()> {
+        YieldNow(false).await;
+        self.store.write_batch(batch)
+    }
+    async fn commit_root(&mut self, root: &H256) -> Result<()> {
+        self.roots.push(*root);
+        Ok(())
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+fn assert_send<T: Send>(_: &T) {}
+
+#[test]
+fn test_async_tree() {
+    let keys = random_keys(50);
+    let mut tree = SMT::default();
+    let mut async_tree = AsyncSMT::new(
+        *tree.root(),
+        AsyncStore {
+            store: tree.store().clone(),
+            ..AsyncStore::default()
+        },
+    );
+
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+        let update = async_tree.update_async(*key, *key, true);
+        assert_send(&update);
+        assert_eq!(block_on(update).copied(), Ok(*tree.root()));
+    }
+    for key in &keys[..10] {
+        tree.update(*key, *key, false).unwrap();
+        block_on(async_tree.update_async(*key, *key, false)).unwrap();
+    }
+    assert_eq!(async_tree.root(), tree.root());
+    assert_eq!(async_tree.store().roots.len(), 60);
+    assert_eq!(async_tree.store().roots.last(), Some(tree.root()));
+    assert_eq!(
+        async_tree.store().store.branches_map(),
+        tree.store().branches_map()
+    );
+    assert_eq!(
+        async_tree.store().store.leaves_map(),
+        tree.store().leaves_map()
+    );
+
+    assert_eq!(block_on(async_tree.get_async(&keys[20])), Ok(keys[20]));
+    assert_eq!(block_on(async_tree.get_async(&keys[5])), Ok(H256::zero()));
+    assert_eq!(
+        block_on(async_tree.member_proof_async(keys[10..20].to_vec())),
+        tree.member_proof(keys[10..20].to_vec())
+    );
+    assert_eq!(
+        block_on(async_tree.modify_root_proof_async(vec![keys[30]])),
+        tree.modify_root_proof(vec![keys[30]])
+    );
+
+    // errors of the walk are returned as they are
+    assert_eq!(
+        block_on(async_tree.update_async(keys[30], keys[30], true)).map(|_| ()),
+        Err(Error::LeafExists(keys[30]))
+    );
+}
+
+#[test]
+fn test_async_reads_are_batched() {
+    let keys = random_keys(500);
+    let mut tree = SMT::default();
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+    }
+    let async_tree = AsyncSMT::new(
+        *tree.root(),
+        AsyncStore {
+            store: tree.store().clone(),
+            ..AsyncStore::default()
+        },
+    );
+    let stats = tree.stats().unwrap();
+
+    // one batch per level of the paths, then the leaves and the few nodes
+    // read off the paths
+    for proof_keys in [vec![keys[7]], keys[..40].to_vec()] {
+        let store = async_tree.store();
+        store.batches.store(0, Ordering::Relaxed);
+        store.reads.store(0, Ordering::Relaxed);
+        assert_eq!(
+            block_on(async_tree.modify_root_proof_async(proof_keys.clone())),
+            tree.modify_root_proof(proof_keys.clone())
+        );
+        let batches = store.batches.load(Ordering::Relaxed);
+        let reads = store.reads.load(Ordering::Relaxed);
+        assert!(batches <= 2 * (stats.max_path_length + 2));
+        assert!(reads <= stats.branches + stats.leaves + 2);
+        if proof_keys.len() > 1 {
+            assert!(batches * 4 < reads);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use super::{build_tree, random_keys};
+use crate::{
+    blake2b::Blake2bHasher,
+    budget::{Budget, BudgetReport, MemberProof, ModifyMultiProof, ProofBudget, ProofKind},
+    default_store::DefaultStore,
+    error::Error,
+    h256::H256,
+    merge::MergeValue,
+    merkle_proof::Side,
+    multi_proof::{MultiProof, ProofOp},
+    tree::SparseMerkleTree,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+
+fn member_proof(steps: usize) -> (Vec<Side>, H256) {
+    let side = Side::Left(MergeValue::zero());
+    (vec![side; steps], H256::zero())
+}
+
+#[test]
+fn test_member_budget_cbor_chunks() {
+    // no step, a single step, then a byte string split in 64 bytes chunks
+    for (steps, cbor_size) in [(0, 1), (1, 36), (2, 73), (32, 1 + 17 * 66 + 1)] {
+        assert_eq!(
+            MemberProof(&member_proof(steps)).budget(),
+            Ok(Budget {
+                proofs: 1,
+                cbor_size,
+                blake2b_calls: 1 + steps,
+            })
+        );
+    }
+}
+
+#[test]
+fn test_proof_budgets() {
+    let keys = random_keys(200);
+    let tree: SMT = build_tree(&keys);
+
+    for proof in tree.member_proof(keys[..10].to_vec()).unwrap() {
+        let member = MemberProof(&proof);
+        assert_eq!(member.kind(), ProofKind::Member);
+        assert_eq!(member.budget().unwrap().blake2b_calls, 1 + proof.0.len());
+    }
+
+    for proof in tree.modify_root_proof(keys[..10].to_vec()).unwrap() {
+        let (remaining, left, continuing, right, _, _) = &proof;
+        let budget = proof.budget().unwrap();
+        assert_eq!(proof.kind(), ProofKind::Modify);
+        assert_eq!(
+            budget.blake2b_calls,
+            7 + left.len() - 1 + right.len() - 1 + 2 * continuing.len() + 2 * remaining.len()
+        );
+        // constructor, both leaves and both heights at the least
+        assert!(budget.cbor_size >= 4 + 2 * 34 + 3 + 2 * 2);
+    }
+
+    let proof = tree.multi_proof(keys[..10].to_vec()).unwrap();
+    let budget = proof.budget().unwrap();
+    assert_eq!(proof.kind(), ProofKind::Members);
+    assert_eq!(
+        budget.blake2b_calls,
+        2 * proof.leaves_count() + proof.siblings_count() + proof.leaves_count() - 1
+    );
+    assert!(budget.cbor_size > proof.to_bytes().len());
+
+    // a modification hashes the tree without the members on top
+    let modify = ModifyMultiProof(&proof);
+    assert_eq!(modify.kind(), ProofKind::ModifyMembers);
+    assert_eq!(modify.budget().unwrap().cbor_size, budget.cbor_size);
+    assert!(modify.budget().unwrap().blake2b_calls > budget.blake2b_calls);
+}
+
+#[test]
+fn test_modify_multi_proof_budget_single_member() {
+    let keys = random_keys(50);
+    let tree: SMT = build_tree(&keys);
+
+    // the sibling of the member replaces its branch without it, every step
+    // above is hashed twice
+    let proof = tree.multi_proof(vec![keys[0]]).unwrap();
+    let members = proof.budget().unwrap();
+    assert_eq!(
+        ModifyMultiProof(&proof).budget(),
+        Ok(Budget {
+            blake2b_calls: members.blake2b_calls + proof.siblings_count() - 1,
+            ..members
+        })
+    );
+
+    // without the members, at most the sibling and merge steps are hashed
+    // once more
+    let (_, proof) = tree
+        .modify_root_multi_proof(vec![keys[0], keys[1]], false)
+        .unwrap();
+    let (_, single) = tree.modify_root_multi_proof(vec![keys[0]], false).unwrap();
+    assert!(
+        ModifyMultiProof(&proof).budget().unwrap().blake2b_calls
+            - proof.budget().unwrap().blake2b_calls
+            <= proof.ops().len() - proof.leaves_count() - proof.neighbours_count()
+    );
+    assert!(
+        ModifyMultiProof(&single).budget().unwrap().blake2b_calls
+            > single.budget().unwrap().blake2b_calls
+    );
+}
+
+#[test]
+fn test_modify_multi_proof_budget_vector() {
+    // a member between a neighbour on its left and a sibling on its right
+    let sibling = Side::Right(MergeValue::zero());
+    let proof = MultiProof::new(vec![
+        ProofOp::Neighbour(H256::zero()),
+        ProofOp::Leaf,
+        ProofOp::Merge,
+        ProofOp::Sibling(sibling.clone()),
+    ]);
+    // 68 bytes: a 64 bytes chunk, then a 4 bytes one
+    let members = Budget {
+        proofs: 1,
+        cbor_size: 1 + (2 + 64) + (1 + 4) + 1,
+        // the member, its leaf, the neighbour leaf, the merge and the sibling
+        blake2b_calls: 5,
+    };
+    assert_eq!(proof.budget(), Ok(members));
+    // without the member, the merge is the neighbour leaf alone, only the
+    // sibling is hashed once more
+    assert_eq!(
+        ModifyMultiProof(&proof).budget(),
+        Ok(Budget {
+            blake2b_calls: 6,
+            ..members
+        })
+    );
+
+    // a step missing its operands, leaves left unmerged or no leaf at all
+    for ops in [
+        vec![ProofOp::Leaf, ProofOp::Merge],
+        vec![ProofOp::Sibling(sibling)],
+        vec![ProofOp::Leaf, ProofOp::Leaf],
+        vec![ProofOp::Neighbour(H256::zero()), ProofOp::Leaf],
+        vec![],
+    ] {
+        let proof = MultiProof::new(ops);
+        assert_eq!(proof.budget(), Err(Error::CorruptedStack));
+        assert_eq!(
+            ModifyMultiProof(&proof).budget(),
+            Err(Error::CorruptedStack)
+        );
+    }
+}
+
+#[test]
+fn test_budget_report() {
+    let keys = random_keys(100);
+    let tree: SMT = build_tree(&keys);
+    let members = tree.member_proof(keys[..3].to_vec()).unwrap();
+    let modified = tree.modify_root_proof(keys[3..5].to_vec()).unwrap();
+    let multi = tree.multi_proof(keys[..5].to_vec()).unwrap();
+
+    let mut report = BudgetReport::default();
+    for proof in &members {
+        report.add(&MemberProof(proof)).unwrap();
+    }
+    for proof in &modified {
+        report.add(proof).unwrap();
+    }
+    report.add(&multi).unwrap();
+
+    // a malformed proof is left out of the report
+    let malformed = MultiProof::new(vec![ProofOp::Merge]);
+    assert_eq!(
+        report.add(&ModifyMultiProof(&malformed)),
+        Err(Error::CorruptedStack)
+    );
+    assert_eq!(report.add(&malformed), Err(Error::CorruptedStack));
+
+    assert_eq!(
+        report.get(ProofKind::Member),
+        members
+            .iter()
+            .map(|proof| MemberProof(proof).budget().unwrap())
+            .sum()
+    );
+    assert_eq!(report.get(ProofKind::Member).proofs, 3);
+    assert_eq!(report.get(ProofKind::Modify).proofs, 2);
+    assert_eq!(report.get(ProofKind::Members), multi.budget().unwrap());
+    assert_eq!(report.get(ProofKind::ModifyMembers), Budget::default());
+    assert_eq!(
+        report.kinds().map(|(kind, _)| kind).collect::<Vec<_>>(),
+        vec![ProofKind::Member, ProofKind::Modify, ProofKind::Members]
+    );
+    assert_eq!(
+        report.total(),
+        report.kinds().map(|(_, budget)| budget).sum()
+    );
+    assert_eq!(report.total().proofs, 6);
+}
@@ -0,0 +1,94 @@
+use super::{build_tree, random_keys};
+use crate::{
+    blake2b::Blake2bHasher, default_store::DefaultStore, error::Error, h256::H256,
+    tree::SparseMerkleTree, tree::MERKLE_UPPER_BOUND,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+
+fn assert_same_tree(built: &SMT, tree: &SMT) {
+    assert_eq!(built.root(), tree.root());
+    assert_eq!(built.store().branches_map(), tree.store().branches_map());
+    assert_eq!(built.store().leaves_map(), tree.store().leaves_map());
+    assert_eq!(built.stats().unwrap(), tree.stats().unwrap());
+}
+
+#[test]
+fn test_from_leaves_matches_updates() {
+    for count in [0, 1, 2, 100, 1000] {
+        let keys = random_keys(count);
+        let built = SMT::from_leaves(
+            DefaultStore::default(),
+            keys.iter().map(|key| (*key, *key)).collect(),
+        )
+        .unwrap();
+        assert_same_tree(&built, &build_tree(&keys));
+    }
+}
+
+#[test]
+fn test_from_leaves_can_be_updated() {
+    let keys = random_keys(200);
+    let mut built = SMT::from_leaves(
+        DefaultStore::default(),
+        keys[..150].iter().map(|key| (*key, *key)).collect(),
+    )
+    .unwrap();
+    for key in &keys[150..] {
+        built.update(*key, *key, true).unwrap();
+    }
+    for key in &keys[..50] {
+        built.update(*key, *key, false).unwrap();
+    }
+    assert_same_tree(&built, &build_tree(&keys[50..]));
+}
+
+#[test]
+fn test_from_leaves_rejects_keys() {
+    let keys = random_keys(10);
+    let mut members = keys.iter().map(|key| (*key, *key)).collect::<Vec<_>>();
+    members.push((keys[3], keys[3]));
+    assert_eq!(
+        SMT::from_leaves(DefaultStore::default(), members).err(),
+        Some(Error::LeafExists(keys[3]))
+    );
+    assert_eq!(
+        SMT::from_leaves(DefaultStore::default(), vec![(MERKLE_UPPER_BOUND, keys[0])]).err(),
+        Some(Error::ReservedKey(MERKLE_UPPER_BOUND))
+    );
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_par_from_leaves_matches_updates() {
+    for count in [0, 100, 5000] {
+        let keys = random_keys(count);
+        let members = keys.iter().map(|key| (*key, *key)).collect::<Vec<_>>();
+        let built = SMT::par_from_leaves(DefaultStore::default(), members.clone()).unwrap();
+        assert_same_tree(&built, &build_tree(&keys));
+        assert_same_tree(
+            &built,
+            &SMT::from_leaves(DefaultStore::default(), members).unwrap(),
+        );
+    }
+}
+
+#[test]
+fn test_from_leaves_rejects_stored_tree() {
+    let keys = random_keys(10);
+    let members = keys.iter().map(|key| (*key, *key)).collect::<Vec<_>>();
+    let tree: SMT = build_tree(&keys[..5]);
+    assert_eq!(
+        SMT::from_leaves(tree.store().clone(), members.clone()).err(),
+        Some(Error::NonEmptyStore(*tree.root()))
+    );
+    // the empty tree is a tree as well
+    let empty = SMT::default().take_store();
+    assert!(SMT::from_leaves(empty.clone(), members.clone()).is_err());
+    #[cfg(feature = "rayon")]
+    assert_eq!(
+        SMT::par_from_leaves(empty, members).err(),
+        Some(Error::NonEmptyStore(*SMT::default().root()))
+    );
+}
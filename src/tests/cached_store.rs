@@ -0,0 +1,206 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher,
+    cached_store::{CachedStore, WritePolicy, DEFAULT_PINNED_LEVELS},
+    default_store::DefaultStore,
+    error::{Error, Result},
+    h256::H256,
+    traits::{StoreReadOps, StoreWriteOps},
+    tree::{BranchKey, BranchNode, SparseMerkleTree},
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+type CachedSMT = SparseMerkleTree<Blake2bHasher, H256, CachedStore<H256, DefaultStore<H256>>>;
+
+/// Store failing every write once its budget of writes is spent
+struct FailingStore {
+    store: DefaultStore<H256>,
+    writes_left: Rc<Cell<Option<usize>>>,
+}
+
+impl FailingStore {
+    fn write(&mut self) -> Result<()> {
+        match self.writes_left.get() {
+            Some(0) => Err(Error::Store("write failed".to_string())),
+            Some(left) => {
+                self.writes_left.set(Some(left - 1));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl StoreReadOps<H256> for FailingStore {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        self.store.get_branch(branch_key)
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<H256>> {
+        self.store.get_leaf(leaf_key)
+    }
+}
+
+impl StoreWriteOps<H256> for FailingStore {
+    fn insert_branch(&mut self, branch_key: BranchKey, branch: BranchNode) -> Result<()> {
+        self.write()?;
+        self.store.insert_branch(branch_key, branch)
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: H256) -> Result<()> {
+        self.write()?;
+        self.store.insert_leaf(leaf_key, leaf)
+    }
+    fn remove_branch(&mut self, branch_key: &BranchKey) -> Result<()> {
+        self.write()?;
+        self.store.remove_branch(branch_key)
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<()> {
+        self.write()?;
+        self.store.remove_leaf(leaf_key)
+    }
+}
+
+fn build_trees(keys: &[H256], policy: WritePolicy) -> (SMT, CachedSMT) {
+    let mut tree = SMT::default();
+    let mut cached =
+        CachedSMT::new_with_store(CachedStore::new(SMT::default().take_store(), 64, policy))
+            .unwrap();
+    for key in keys {
+        tree.update(*key, *key, true).unwrap();
+        cached.update(*key, *key, true).unwrap();
+    }
+    (tree, cached)
+}
+
+#[test]
+fn test_write_through() {
+    let keys = random_keys(100);
+    let (tree, cached) = build_trees(&keys, WritePolicy::WriteThrough);
+    assert_eq!(cached.root(), tree.root());
+    assert!(!cached.store().is_dirty());
+    assert_eq!(
+        cached.store().inner().branches_map(),
+        tree.store().branches_map()
+    );
+    assert_eq!(
+        cached.store().inner().leaves_map(),
+        tree.store().leaves_map()
+    );
+
+    // proofs read the branches near the root again and again
+    cached.store().reset_counters();
+    for key in &keys[..10] {
+        assert_eq!(
+            cached.member_proof(vec![*key]),
+            tree.member_proof(vec![*key])
+        );
+    }
+    assert!(cached.store().hits() > cached.store().misses());
+
+    cached.store().clear_cache();
+    cached.store().reset_counters();
+    cached.member_proof(vec![keys[0]]).unwrap();
+    assert_eq!(cached.store().hits(), 0);
+    assert!(cached.store().misses() > 0);
+}
+
+#[test]
+fn test_write_back() {
+    let keys = random_keys(100);
+    let (tree, mut cached) = build_trees(&keys, WritePolicy::WriteBack);
+    assert_eq!(cached.root(), tree.root());
+    assert!(cached.store().is_dirty());
+    // nothing reached the wrapped store besides the bound leaves
+    assert_eq!(cached.store().inner().leaves_map().len(), 2);
+    assert_eq!(cached.get(&keys[3]), Ok(keys[3]));
+
+    for key in &keys[..50] {
+        cached.update(*key, *key, false).unwrap();
+    }
+    cached.store_mut().flush().unwrap();
+    assert!(!cached.store().is_dirty());
+
+    let (tree, _) = build_trees(&keys[50..], WritePolicy::WriteThrough);
+    assert_eq!(cached.root(), tree.root());
+    let store = cached.take_store().into_inner().unwrap();
+    assert_eq!(store.branches_map(), tree.store().branches_map());
+    assert_eq!(store.leaves_map(), tree.store().leaves_map());
+}
+
+#[test]
+fn test_write_through_failure_invalidates_batch() {
+    let keys = random_keys(60);
+    let writes_left = Rc::new(Cell::new(None));
+    let store = FailingStore {
+        store: DefaultStore::default(),
+        writes_left: writes_left.clone(),
+    };
+    let mut cached = SparseMerkleTree::<Blake2bHasher, H256, _>::new_empty(CachedStore::new(
+        store,
+        1024,
+        WritePolicy::WriteThrough,
+    ))
+    .unwrap();
+    for key in &keys[..50] {
+        cached.update(*key, *key, true).unwrap();
+    }
+    let before = cached.store().inner().store.branches_map().clone();
+
+    // every branch is cached, then the next batch is only written in part
+    for key in &keys[..50] {
+        cached.member_proof(vec![*key]).unwrap();
+    }
+    for (i, key) in keys[50..].iter().enumerate() {
+        writes_left.set(Some(1 + i % 3));
+        assert!(cached.update(*key, *key, true).is_err());
+    }
+    writes_left.set(None);
+
+    let inner = cached.store().inner().store.branches_map().clone();
+    assert_ne!(inner, before);
+    for branch_key in before.keys().chain(inner.keys()) {
+        assert_eq!(
+            cached.store().get_branch(branch_key),
+            Ok(inner.get(branch_key).cloned())
+        );
+    }
+}
+
+#[test]
+fn test_pinned_branches_survive_scans() {
+    let keys = random_keys(500);
+    let (_, mut cached) = build_trees(&keys, WritePolicy::WriteThrough);
+    let top = cached
+        .store()
+        .inner()
+        .branches_map()
+        .keys()
+        .filter(|branch_key| branch_key.height > 255 - DEFAULT_PINNED_LEVELS)
+        .cloned()
+        .collect::<Vec<_>>();
+    assert!(top.len() > 64);
+
+    // proofs of every member go through far more branches than the LRU holds
+    for pinned_levels in [DEFAULT_PINNED_LEVELS, 0] {
+        cached.store_mut().set_pinned_levels(pinned_levels);
+        cached.store().clear_cache();
+        for key in &keys {
+            cached.member_proof(vec![*key]).unwrap();
+        }
+        cached.store().reset_counters();
+        for branch_key in &top {
+            cached.store().get_branch(branch_key).unwrap();
+        }
+        if pinned_levels == 0 {
+            assert!(cached.store().misses() > 0);
+            assert!(cached.store().cached_len() <= 64);
+        } else {
+            assert_eq!(cached.store().misses(), 0);
+            assert_eq!(cached.store().hits(), top.len());
+            assert!(cached.store().cached_len() > 64);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher,
+    codec::{
+        decode_branch, decode_branch_key, decode_counts, encode_branch, encode_branch_key,
+        encode_counts, ValueCodec, BRANCH_NODE_SIZE, COUNTS_SIZE,
+    },
+    default_store::DefaultStore,
+    error::Error,
+    h256::H256,
+    tree::SparseMerkleTree,
+};
+
+#[test]
+fn test_branch_roundtrip() {
+    let mut tree = SparseMerkleTree::<Blake2bHasher, H256, DefaultStore<H256>>::default();
+    for key in random_keys(100) {
+        tree.update(key, key, true).unwrap();
+    }
+    for (branch_key, branch) in tree.store().branches_map() {
+        assert_eq!(
+            decode_branch_key(&encode_branch_key(branch_key)).as_ref(),
+            Ok(branch_key)
+        );
+        assert_eq!(decode_branch(&encode_branch(branch)).as_ref(), Ok(branch));
+    }
+
+    let (_, branch) = tree.store().branches_map().iter().next().unwrap();
+    let mut bytes = encode_branch(branch);
+    bytes[32] = 7;
+    assert_eq!(decode_branch(&bytes), Err(Error::InvalidCode(7)));
+    assert_eq!(
+        decode_branch(&bytes[1..]),
+        Err(Error::InvalidLength {
+            expected: BRANCH_NODE_SIZE,
+            actual: BRANCH_NODE_SIZE - 1
+        })
+    );
+    assert_eq!(H256::decode(&H256::max().encode()), Ok(H256::max()));
+}
+
+#[test]
+fn test_counts_roundtrip() {
+    let mut tree = SparseMerkleTree::<Blake2bHasher, H256, DefaultStore<H256>>::default();
+    for key in random_keys(100) {
+        tree.update(key, key, true).unwrap();
+    }
+    let counts = tree.counts().unwrap();
+    let bytes = encode_counts(counts);
+    assert_eq!(decode_counts(&bytes).as_ref(), Ok(counts));
+
+    for len in [0, 8, COUNTS_SIZE - 1, COUNTS_SIZE + 8] {
+        let mut bytes = bytes.to_vec();
+        bytes.resize(len, 0);
+        assert_eq!(
+            decode_counts(&bytes),
+            Err(Error::InvalidLength {
+                expected: COUNTS_SIZE,
+                actual: len
+            })
+        );
+    }
+}
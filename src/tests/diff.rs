@@ -0,0 +1,118 @@
+use core::cell::Cell;
+
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher,
+    default_store::DefaultStore,
+    diff::Diff,
+    error::Result,
+    h256::H256,
+    traits::StoreReadOps,
+    tree::{BranchKey, BranchNode, SparseMerkleTree},
+    versioned_store::VersionedStore,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+type VersionedSMT = SparseMerkleTree<Blake2bHasher, H256, VersionedStore<H256>>;
+
+/// Store counting the branches read through it
+struct CountingStore<'a> {
+    store: &'a DefaultStore<H256>,
+    branch_reads: Cell<usize>,
+}
+
+impl<'a> StoreReadOps<H256> for CountingStore<'a> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        self.branch_reads.set(self.branch_reads.get() + 1);
+        self.store.get_branch(branch_key)
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<H256>> {
+        self.store.get_leaf(leaf_key)
+    }
+}
+
+fn counting(tree: &SMT) -> SparseMerkleTree<Blake2bHasher, H256, CountingStore<'_>> {
+    SparseMerkleTree::new(
+        *tree.root(),
+        CountingStore {
+            store: tree.store(),
+            branch_reads: Cell::new(0),
+        },
+    )
+}
+
+fn leaves(keys: &[H256]) -> Vec<(H256, H256)> {
+    let mut leaves = keys.iter().map(|key| (*key, *key)).collect::<Vec<_>>();
+    leaves.sort_unstable();
+    leaves
+}
+
+#[test]
+fn test_diff_trees() {
+    let keys = random_keys(80);
+    let mut a = SMT::default();
+    let mut b = SMT::default();
+    for key in &keys[..60] {
+        a.update(*key, *key, true).unwrap();
+    }
+    for key in &keys[20..] {
+        b.update(*key, *key, true).unwrap();
+    }
+    // same key holding another value
+    b.update(keys[30], keys[30], false).unwrap();
+    b.update(keys[30], H256::max(), true).unwrap();
+
+    let diff = a.diff(&b).unwrap();
+    let mut added = leaves(&keys[60..]);
+    added.push((keys[30], H256::max()));
+    added.sort_unstable();
+    let mut removed = leaves(&keys[..20]);
+    removed.push((keys[30], keys[30]));
+    removed.sort_unstable();
+    assert_eq!(diff, Diff { added, removed });
+
+    let reverse = b.diff(&a).unwrap();
+    assert_eq!((reverse.added, reverse.removed), (diff.removed, diff.added));
+    assert!(a.diff(&a).unwrap().is_empty());
+    assert_eq!(SMT::default().diff(&a).unwrap().added, leaves(&keys[..60]));
+}
+
+#[test]
+fn test_diff_versioned_roots() {
+    let keys = random_keys(40);
+    let mut tree = VersionedSMT::default();
+    let mut roots = vec![*tree.root()];
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+        roots.push(*tree.root());
+    }
+    for key in &keys[..10] {
+        tree.update(*key, *key, false).unwrap();
+        roots.push(*tree.root());
+    }
+
+    let diff = tree.store().diff(&roots[5], &roots[45]).unwrap();
+    assert_eq!(diff.added, leaves(&keys[5..40]));
+    assert_eq!(diff.removed, leaves(&keys[..5]));
+    assert!(tree.store().diff(&roots[7], &roots[7]).unwrap().is_empty());
+}
+
+#[test]
+fn test_diff_visits_changed_paths_only() {
+    let keys = random_keys(1000);
+    let mut a = SMT::default();
+    for key in &keys[..999] {
+        a.update(*key, *key, true).unwrap();
+    }
+    let mut b = SMT::new(*a.root(), a.store().clone());
+    b.update(keys[999], keys[999], true).unwrap();
+    let depth = b.stats().unwrap().max_path_length;
+
+    let (a, b) = (counting(&a), counting(&b));
+    let diff = a.diff(&b).unwrap();
+    assert_eq!(diff.added, leaves(&keys[999..]));
+    assert!(diff.removed.is_empty());
+    assert!(a.store().branch_reads.get() <= depth + 1);
+    assert!(b.store().branch_reads.get() <= depth + 1);
+}
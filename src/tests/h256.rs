@@ -0,0 +1,188 @@
+use crate::{
+    blake2b::Blake2bHasher,
+    error::Error,
+    h256::H256,
+    traits::Hasher,
+    tree::{BranchKey, ChildKey, Match},
+};
+
+fn sample_keys() -> Vec<H256> {
+    let mut keys: Vec<H256> = (0..8u8)
+        .map(|i| {
+            let mut hasher = Blake2bHasher::default();
+            hasher.write_byte(i);
+            hasher.finish()
+        })
+        .collect();
+
+    // keys that only differ from another key in a single bit
+    for bit in [0, 1, 63, 64, 127, 128, 191, 192, 254, 255] {
+        let mut key = keys[0];
+        key.set_bit(bit);
+        keys.push(key);
+    }
+    keys.push(H256::zero());
+    keys.push(H256::max());
+    keys
+}
+
+/// Bit by bit shift, used as reference for the word level implementation
+fn naive_shift_right(key: &H256, n: u32) -> H256 {
+    let mut shifted = H256::zero();
+    for i in 0..=u8::MAX {
+        let src = u32::from(i) + n;
+        if src <= 255 && key.get_bit(src as u8) {
+            shifted.set_bit(i);
+        }
+    }
+    shifted
+}
+
+/// Shift one bit at a time until both paths meet, as the tree used to do
+fn naive_intersecting_height(
+    child: &ChildKey,
+    other_key: H256,
+    max_height: u8,
+) -> Result<u8, Match> {
+    match child {
+        ChildKey::Leaf(key) if key == &other_key => Err(Match::Exact),
+        ChildKey::Leaf(key) => (0..max_height)
+            .find(|i| {
+                let n = u32::from(*i) + 1;
+                naive_shift_right(key, n) == naive_shift_right(&other_key, n)
+            })
+            .ok_or(Match::NoMatch),
+        ChildKey::Branch(branch_key) => (branch_key.height..max_height)
+            .find(|i| {
+                naive_shift_right(&branch_key.node_key, u32::from(i - branch_key.height))
+                    == naive_shift_right(&other_key, u32::from(*i) + 1)
+            })
+            .ok_or(Match::NoMatch),
+    }
+}
+
+#[test]
+fn test_shift_left() {
+    for key in sample_keys() {
+        for n in 0..=256 {
+            let mut expected = H256::zero();
+            for i in 0..=u8::MAX {
+                if u32::from(i) >= n && key.get_bit((u32::from(i) - n) as u8) {
+                    expected.set_bit(i);
+                }
+            }
+            assert_eq!(key.shift_left(n), expected, "key {:?} shift {}", key, n);
+        }
+    }
+}
+
+#[test]
+fn test_branch_key_range() {
+    for key in sample_keys() {
+        for height in [0, 1, 63, 64, 200, 255] {
+            let (lowest, highest) =
+                BranchKey::new(height, key.parent_path_by_height(height)).key_range();
+            assert!(lowest <= key && key <= highest);
+            assert_eq!(
+                lowest.parent_path_by_height(height),
+                key.parent_path_by_height(height)
+            );
+            assert_eq!(
+                highest.parent_path_by_height(height),
+                key.parent_path_by_height(height)
+            );
+            assert_eq!(lowest.fork_height(&highest), height);
+        }
+    }
+}
+
+#[test]
+fn test_parent_path_by_height() {
+    for key in sample_keys() {
+        for height in 0..=u8::MAX {
+            assert_eq!(
+                key.parent_path_by_height(height),
+                naive_shift_right(&key, u32::from(height) + 1),
+                "key {:?} height {}",
+                key,
+                height
+            );
+        }
+    }
+}
+
+#[test]
+fn test_fork_height() {
+    let keys = sample_keys();
+    for a in &keys {
+        for b in &keys {
+            let expected = (0..=u8::MAX)
+                .rev()
+                .find(|h| a.get_bit(*h) != b.get_bit(*h))
+                .unwrap_or(0);
+            assert_eq!(a.fork_height(b), expected);
+        }
+    }
+}
+
+#[test]
+fn test_intersecting_height() {
+    let keys = sample_keys();
+    for a in &keys {
+        for b in &keys {
+            for max_height in [0, 1, 64, 200, 255] {
+                let leaf = ChildKey::Leaf(*a);
+                assert_eq!(
+                    leaf.get_intersecting_height(*b, max_height),
+                    naive_intersecting_height(&leaf, *b, max_height)
+                );
+
+                for height in [0, 63, 64, 191, 254] {
+                    let branch =
+                        ChildKey::Branch(BranchKey::new(height, a.parent_path_by_height(height)));
+                    assert_eq!(
+                        branch.get_intersecting_height(*b, max_height),
+                        naive_intersecting_height(&branch, *b, max_height),
+                        "branch {:?} key {:?} max height {}",
+                        branch,
+                        b,
+                        max_height
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_hex_conversions() {
+    let hex_key = "037989aac4a85a30998d29e5041f8c6cf398d370f08b48ce258cdc376e5b8c8c";
+    let key: H256 = hex_key.parse().unwrap();
+
+    assert_eq!(key.to_string(), hex_key);
+    assert_eq!(format!("{:x}", key), hex_key);
+    assert_eq!(format!("{:#x}", key), format!("0x{}", hex_key));
+    assert_eq!(format!("0x{}", hex_key).parse::<H256>(), Ok(key));
+    assert_eq!(key.as_ref(), hex::decode(hex_key).unwrap().as_slice());
+    assert_eq!(H256::try_from(key.as_ref()), Ok(key));
+}
+
+#[test]
+fn test_invalid_conversions() {
+    assert_eq!(
+        H256::try_from(&[0u8; 31][..]),
+        Err(Error::InvalidLength {
+            expected: 32,
+            actual: 31
+        })
+    );
+    assert_eq!(
+        "00ff".parse::<H256>(),
+        Err(Error::InvalidLength {
+            expected: 32,
+            actual: 2
+        })
+    );
+    assert!(matches!("0xzz".parse::<H256>(), Err(Error::InvalidHex(_))));
+    assert!(matches!("abc".parse::<H256>(), Err(Error::InvalidHex(_))));
+}
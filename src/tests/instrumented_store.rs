@@ -0,0 +1,171 @@
+#[cfg(feature = "tracing")]
+use std::collections::HashMap;
+#[cfg(feature = "tracing")]
+use std::sync::{Arc, Mutex};
+
+use super::{build_tree, random_keys};
+use crate::{
+    blake2b::Blake2bHasher,
+    default_store::DefaultStore,
+    h256::H256,
+    instrumented_store::{InstrumentedStore, StoreMetrics},
+    tree::SparseMerkleTree,
+};
+
+type InstrumentedSMT = SparseMerkleTree<Blake2bHasher, H256, InstrumentedStore<DefaultStore<H256>>>;
+
+#[test]
+fn test_update_metrics() {
+    let keys = random_keys(100);
+    let mut tree: InstrumentedSMT = build_tree(&keys[..99]);
+    tree.store().take_metrics();
+
+    tree.update(keys[99], keys[99], true).unwrap();
+    let metrics = tree.store().take_metrics();
+    assert_eq!(metrics.batches, 1);
+    assert_eq!(metrics.leaf_writes, 1);
+    assert_eq!(metrics.leaf_removes, 0);
+    assert!(metrics.branch_reads > 0);
+    assert!(metrics.branch_writes > 0);
+    assert_eq!(tree.store().metrics(), StoreMetrics::default());
+
+    tree.update(keys[99], keys[99], false).unwrap();
+    let metrics = tree.store().metrics();
+    assert_eq!(metrics.batches, 1);
+    assert_eq!(metrics.leaf_writes, 0);
+    assert_eq!(metrics.leaf_removes, 1);
+    assert!(metrics.branch_removes > 0);
+}
+
+#[test]
+fn test_proof_metrics() {
+    let keys = random_keys(100);
+    let tree: InstrumentedSMT = build_tree(&keys);
+    tree.store().take_metrics();
+
+    tree.member_proof(vec![keys[0]]).unwrap();
+    let metrics = tree.store().take_metrics();
+    assert!(metrics.branch_reads > 0);
+    assert_eq!(metrics.batches, 0);
+    assert_eq!(metrics.branch_writes + metrics.leaf_writes, 0);
+
+    tree.modify_root_proof(vec![keys[0]]).unwrap();
+    let metrics = tree.store().take_metrics();
+    assert!(metrics.branch_reads > 0);
+    assert_eq!(metrics.branch_writes + metrics.branch_removes, 0);
+}
+
+#[test]
+fn test_forwards_to_inner_store() {
+    let keys = random_keys(50);
+    let tree: InstrumentedSMT = build_tree(&keys);
+    let mut plain = SparseMerkleTree::<Blake2bHasher, H256, DefaultStore<H256>>::default();
+    for key in &keys {
+        plain.update(*key, *key, true).unwrap();
+    }
+    assert_eq!(tree.root(), plain.root());
+    assert_eq!(
+        tree.store().inner().branches_map(),
+        plain.store().branches_map()
+    );
+}
+
+#[cfg(feature = "tracing")]
+type Fields = HashMap<&'static str, u64>;
+
+/// Subscriber keeping the integer fields of every span
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct SpanFields {
+    spans: Arc<Mutex<Vec<(&'static str, Fields)>>>,
+}
+
+#[cfg(feature = "tracing")]
+impl SpanFields {
+    fn span(&self, name: &str) -> Fields {
+        let spans = self.spans.lock().unwrap();
+        let (_, fields) = spans.iter().find(|(span, _)| *span == name).unwrap();
+        fields.clone()
+    }
+}
+
+#[cfg(feature = "tracing")]
+struct Visitor<'a>(&'a mut Fields);
+
+#[cfg(feature = "tracing")]
+impl<'a> tracing::field::Visit for Visitor<'a> {
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.0.insert(field.name(), value);
+    }
+    fn record_debug(&mut self, _field: &tracing::field::Field, _value: &dyn std::fmt::Debug) {}
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for SpanFields {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut fields = HashMap::new();
+        span.record(&mut Visitor(&mut fields));
+        let mut spans = self.spans.lock().unwrap();
+        spans.push((span.metadata().name(), fields));
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        let mut spans = self.spans.lock().unwrap();
+        let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+        values.record(&mut Visitor(fields));
+    }
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+    fn event(&self, _event: &tracing::Event<'_>) {}
+    fn enter(&self, _span: &tracing::span::Id) {}
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_spans_record_store_calls() {
+    let keys = random_keys(100);
+    let mut tree: InstrumentedSMT = build_tree(&keys[..99]);
+    tree.store().take_metrics();
+
+    let subscriber = SpanFields::default();
+    tracing::subscriber::with_default(subscriber.clone(), || {
+        tree.update(keys[99], keys[99], true).unwrap();
+    });
+    let metrics = tree.store().take_metrics();
+    let update = subscriber.span("update");
+    assert_eq!(update["reads"], metrics.branch_reads + metrics.leaf_reads);
+    assert_eq!(
+        update["writes"],
+        metrics.branch_writes + metrics.leaf_writes
+    );
+    assert_eq!(update["removes"], 0);
+    assert!(update.contains_key("elapsed_us"));
+
+    let subscriber = SpanFields::default();
+    tracing::subscriber::with_default(subscriber.clone(), || {
+        tree.update(keys[99], keys[99], false).unwrap();
+    });
+    let metrics = tree.store().take_metrics();
+    let update = subscriber.span("update");
+    assert_eq!(
+        update["writes"],
+        metrics.branch_writes + metrics.leaf_writes
+    );
+    assert_eq!(
+        update["removes"],
+        metrics.branch_removes + metrics.leaf_removes
+    );
+    assert!(update["removes"] > 0);
+
+    let subscriber = SpanFields::default();
+    tracing::subscriber::with_default(subscriber.clone(), || {
+        tree.multi_proof(keys[..10].to_vec()).unwrap();
+    });
+    let proof = subscriber.span("multi_proof");
+    assert_eq!(proof["keys"], 10);
+    assert!(proof["reads"] > 0);
+    assert_eq!(proof["writes"] + proof["removes"], 0);
+}
@@ -0,0 +1,89 @@
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher,
+    default_store::DefaultStore,
+    error::Error,
+    h256::H256,
+    journal_store::{JournalStore, DEFAULT_JOURNAL_DEPTH},
+    tree::SparseMerkleTree,
+};
+
+type JournalSMT = SparseMerkleTree<Blake2bHasher, H256, JournalStore<H256, DefaultStore<H256>>>;
+
+#[test]
+fn test_rollback_restores_store() {
+    let keys = random_keys(40);
+    let mut tree = JournalSMT::default();
+    for key in &keys[..20] {
+        tree.update(*key, *key, true).unwrap();
+    }
+    let root = *tree.root();
+    let store = tree.store().inner().clone();
+
+    for key in &keys[20..] {
+        tree.update(*key, *key, true).unwrap();
+    }
+    for key in &keys[..10] {
+        tree.update(*key, *key, false).unwrap();
+    }
+
+    tree.rollback_to(&root).unwrap();
+    assert_eq!(*tree.root(), root);
+    assert_eq!(tree.store().inner().branches_map(), store.branches_map());
+    assert_eq!(tree.store().inner().leaves_map(), store.leaves_map());
+    assert_eq!(tree.len(), Ok(20));
+
+    // the tree keeps working from the restored state
+    tree.update(keys[30], keys[30], true).unwrap();
+    assert_eq!(tree.len(), Ok(21));
+    tree.rollback_to(&root).unwrap();
+    assert_eq!(tree.store().inner().branches_map(), store.branches_map());
+}
+
+#[test]
+fn test_retention_depth_and_finalize() {
+    let keys = random_keys(10);
+    let mut tree = SparseMerkleTree::<Blake2bHasher, H256, _>::new_with_store(JournalStore::new(
+        SparseMerkleTree::<Blake2bHasher, H256, DefaultStore<H256>>::default().take_store(),
+        3,
+    ))
+    .unwrap();
+
+    let mut roots = vec![];
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+        roots.push(*tree.root());
+    }
+    assert_eq!(
+        tree.store().checkpoints().copied().collect::<Vec<_>>(),
+        roots[7..].to_vec()
+    );
+    assert_eq!(
+        tree.rollback_to(&roots[6]),
+        Err(Error::MissingRoot(roots[6]))
+    );
+
+    assert_eq!(tree.store_mut().finalize(&roots[8]), Ok(1));
+    assert_eq!(
+        tree.rollback_to(&roots[7]),
+        Err(Error::MissingRoot(roots[7]))
+    );
+    tree.rollback_to(&roots[8]).unwrap();
+    assert_eq!(tree.len(), Ok(9));
+}
+
+#[test]
+fn test_default_depth_is_bounded() {
+    let keys = random_keys(DEFAULT_JOURNAL_DEPTH as u16 + 10);
+    let mut tree = JournalSMT::default();
+    assert_eq!(tree.store().depth(), DEFAULT_JOURNAL_DEPTH);
+    let mut roots = Vec::new();
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+        roots.push(*tree.root());
+    }
+    assert_eq!(tree.store().checkpoints().count(), DEFAULT_JOURNAL_DEPTH);
+    assert!(tree.rollback_to(&roots[9]).is_err());
+    tree.rollback_to(&roots[10]).unwrap();
+    assert_eq!(tree.len(), Ok(11));
+}
@@ -0,0 +1,226 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher,
+    codec::encode_counts,
+    default_store::DefaultStore,
+    h256::H256,
+    log_store::{LogOptions, LogStore},
+    traits::StoreWriteOps,
+    tree::{BranchKey, SparseMerkleTree},
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+type LogSMT = SparseMerkleTree<Blake2bHasher, H256, LogStore<H256>>;
+
+fn small_segments() -> LogOptions {
+    LogOptions {
+        segment_size: 4096,
+        sync: false,
+    }
+}
+
+#[test]
+fn test_reopen_and_recover() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = random_keys(50);
+    let mut expected = SMT::default();
+
+    let mut tree = LogSMT::new_empty(LogStore::open(dir.path()).unwrap()).unwrap();
+    for key in &keys[..40] {
+        tree.update(*key, *key, true).unwrap();
+        expected.update(*key, *key, true).unwrap();
+    }
+    assert_eq!(tree.store().last_root(), Some(*expected.root()));
+
+    // an update cut short, followed by a torn record
+    let store = tree.store_mut();
+    store.insert_leaf(keys[45], keys[45]).unwrap();
+    store
+        .insert_branch(
+            BranchKey::new(7, keys[45]),
+            expected.store().branches_map()[&BranchKey::root()].clone(),
+        )
+        .unwrap();
+    store.remove_leaf(&keys[0]).unwrap();
+    let log_size = store.log_size().unwrap();
+    drop(tree);
+    let segment = std::fs::read_dir(dir.path())
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    OpenOptions::new()
+        .append(true)
+        .open(&segment)
+        .unwrap()
+        .write_all(&[1, 200, 0, 0, 0, 42])
+        .unwrap();
+
+    let tree = LogSMT::new_with_store(LogStore::open(dir.path()).unwrap()).unwrap();
+    assert_eq!(tree.root(), expected.root());
+    assert_eq!(tree.store().last_root(), Some(*expected.root()));
+    assert!(tree.store().log_size().unwrap() < log_size);
+    // the counts are read back with the root, without a walk
+    assert_eq!(tree.counts(), Some(&tree.walk_counts().unwrap()));
+    assert_eq!(tree.len(), Ok(40));
+    assert_eq!(tree.get(&keys[0]), Ok(keys[0]));
+    assert_eq!(tree.get(&keys[45]), Ok(H256::zero()));
+    assert_eq!(
+        tree.member_proof(keys[..10].to_vec()),
+        expected.member_proof(keys[..10].to_vec())
+    );
+}
+
+#[test]
+fn test_segments_and_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = random_keys(100);
+    let mut expected = SMT::default();
+
+    let mut tree =
+        LogSMT::new_empty(LogStore::open_with(dir.path(), small_segments()).unwrap()).unwrap();
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+        expected.update(*key, *key, true).unwrap();
+    }
+    for key in &keys[..60] {
+        tree.update(*key, *key, false).unwrap();
+        expected.update(*key, *key, false).unwrap();
+    }
+    let counts = encode_counts(tree.counts().unwrap()).len() as u64;
+    let store = tree.store_mut();
+    assert!(store.segment_count() > 1);
+    let log_size = store.log_size().unwrap();
+    assert!(store.live_size() < log_size);
+
+    let reclaimed = store.compact_blocking().unwrap();
+    assert!(reclaimed > 0);
+    // the live records, the counts and the commit of the root
+    assert_eq!(store.log_size(), Ok(log_size - reclaimed));
+    assert_eq!(store.log_size(), Ok(store.live_size() + 41 + counts + 41));
+    assert_eq!(store.segment_count(), 1);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    assert_eq!(tree.iter().count(), 40);
+    assert_eq!(
+        tree.member_proof(keys[60..].to_vec()),
+        expected.member_proof(keys[60..].to_vec())
+    );
+
+    // writing goes on after a compaction, which needs a committed state
+    tree.update(keys[0], keys[0], true).unwrap();
+    expected.update(keys[0], keys[0], true).unwrap();
+    tree.store_mut().insert_leaf(keys[1], keys[1]).unwrap();
+    assert!(tree.store_mut().compact_blocking().is_err());
+    drop(tree);
+
+    let tree = LogSMT::new_with_store(LogStore::open(dir.path()).unwrap()).unwrap();
+    assert_eq!(tree.root(), expected.root());
+    assert_eq!(tree.len(), Ok(41));
+}
+
+#[test]
+fn test_background_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = random_keys(150);
+    let mut expected = SMT::default();
+
+    let mut tree =
+        LogSMT::new_empty(LogStore::open_with(dir.path(), small_segments()).unwrap()).unwrap();
+    for key in &keys[..100] {
+        tree.update(*key, *key, true).unwrap();
+        expected.update(*key, *key, true).unwrap();
+    }
+    for key in &keys[..60] {
+        tree.update(*key, *key, false).unwrap();
+        expected.update(*key, *key, false).unwrap();
+    }
+    let sealed = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    tree.store_mut().start_compaction().unwrap();
+    assert!(tree.store_mut().start_compaction().is_err());
+    let saved = sealed
+        .iter()
+        .map(|path| (path.clone(), std::fs::read(path).unwrap()))
+        .collect::<Vec<_>>();
+
+    // writes go on while the sealed segments are rewritten, removing nodes
+    // the worker copies and sealing more segments
+    for key in &keys[100..] {
+        tree.update(*key, *key, true).unwrap();
+        expected.update(*key, *key, true).unwrap();
+    }
+    for key in &keys[60..80] {
+        tree.update(*key, *key, false).unwrap();
+        expected.update(*key, *key, false).unwrap();
+    }
+    let log_size = tree.store().log_size().unwrap();
+    let reclaimed = tree.store_mut().wait_compaction().unwrap();
+    assert!(reclaimed > 0);
+    assert_eq!(tree.store().log_size(), Ok(log_size - reclaimed));
+    assert_eq!(tree.store_mut().poll_compaction(), Ok(None));
+    for path in &sealed {
+        assert!(!path.exists());
+    }
+    assert_eq!(tree.root(), expected.root());
+    assert_eq!(tree.len(), Ok(70));
+    assert_eq!(
+        tree.member_proof(keys[80..90].to_vec()),
+        expected.member_proof(keys[80..90].to_vec())
+    );
+    tree.update(keys[0], keys[0], true).unwrap();
+    expected.update(keys[0], keys[0], true).unwrap();
+    drop(tree);
+
+    let tree = LogSMT::new_with_store(LogStore::open(dir.path()).unwrap()).unwrap();
+    assert_eq!(tree.root(), expected.root());
+    assert_eq!(tree.len(), Ok(71));
+    drop(tree);
+
+    // a crash before the sealed segments are deleted replays them first,
+    // then the compacted segment holding the state they lead to
+    for (path, data) in &saved {
+        std::fs::write(path, data).unwrap();
+    }
+    let tree = LogSMT::new_with_store(LogStore::open(dir.path()).unwrap()).unwrap();
+    assert_eq!(tree.root(), expected.root());
+    assert_eq!(tree.len(), Ok(71));
+    assert_eq!(tree.iter().count(), 71);
+}
+
+#[test]
+fn test_interrupted_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = random_keys(50);
+    let mut tree =
+        LogSMT::new_empty(LogStore::open_with(dir.path(), small_segments()).unwrap()).unwrap();
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+    }
+    let root = *tree.root();
+    let files = std::fs::read_dir(dir.path()).unwrap().count();
+    tree.store_mut().start_compaction().unwrap();
+    // the store goes away before the compacted segment is swapped in
+    drop(tree);
+
+    let tree = LogSMT::new_with_store(LogStore::open(dir.path()).unwrap()).unwrap();
+    assert_eq!(*tree.root(), root);
+    assert_eq!(tree.len(), Ok(50));
+    // the sealed segments and the new active one, the compacted segment
+    // being dropped
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), files + 1);
+    drop(tree);
+
+    // a compacted segment left by a crash is dropped on open
+    let leftover = dir.path().join(format!("{:016}.compact", 1000));
+    std::fs::write(&leftover, [5]).unwrap();
+    let tree = LogSMT::new_with_store(LogStore::open(dir.path()).unwrap()).unwrap();
+    assert_eq!(*tree.root(), root);
+    assert!(!leftover.exists());
+}
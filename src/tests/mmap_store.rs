@@ -0,0 +1,59 @@
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher, default_store::DefaultStore, h256::H256, mmap_store::MmapStore,
+    traits::StoreWriteOps, tree::SparseMerkleTree,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+type MmapSMT = SparseMerkleTree<Blake2bHasher, H256, MmapStore<H256>>;
+
+#[test]
+fn test_mmap_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tree.smt");
+    let keys = random_keys(200);
+    let mut tree = SMT::default();
+    for key in &keys[..150] {
+        tree.update(*key, *key, true).unwrap();
+    }
+    // a stale leaf is left behind
+    tree.store_mut().insert_leaf(keys[199], keys[199]).unwrap();
+
+    MmapStore::build(tree.store(), tree.root(), &path).unwrap();
+    let mapped = MmapSMT::new_with_store(MmapStore::open(&path).unwrap()).unwrap();
+    assert_eq!(mapped.root(), tree.root());
+    assert_eq!(mapped.store().root(), tree.root());
+    assert_eq!(
+        mapped.store().branch_count(),
+        tree.store().branches_map().len()
+    );
+    assert_eq!(mapped.store().leaf_count(), 152);
+
+    for key in &keys[..150] {
+        assert_eq!(mapped.get(key), Ok(*key));
+    }
+    assert_eq!(mapped.get(&keys[160]), Ok(H256::zero()));
+    assert_eq!(
+        mapped.member_proof(keys[..20].to_vec()),
+        tree.member_proof(keys[..20].to_vec())
+    );
+    assert_eq!(
+        mapped.modify_root_proof(vec![keys[7]]),
+        tree.modify_root_proof(vec![keys[7]])
+    );
+    assert_eq!(mapped.iter().count(), 150);
+
+    // rebuilding replaces the file while it is still mapped
+    tree.update(keys[150], keys[150], true).unwrap();
+    MmapStore::build(tree.store(), tree.root(), &path).unwrap();
+    let rebuilt = MmapSMT::new_with_store(MmapStore::open(&path).unwrap()).unwrap();
+    assert_eq!(rebuilt.root(), tree.root());
+    assert_eq!(mapped.get(&keys[10]), Ok(keys[10]));
+
+    // truncating a mapped file is undefined behaviour, unmap it first
+    drop(mapped);
+    drop(rebuilt);
+    std::fs::write(&path, b"not a tree").unwrap();
+    assert!(MmapStore::<H256>::open(&path).is_err());
+}
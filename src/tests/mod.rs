@@ -1 +1,51 @@
+use crate::{
+    blake2b::Blake2bHasher,
+    h256::H256,
+    traits::{Hasher, StoreReadOps, StoreWriteOps},
+    tree::SparseMerkleTree,
+};
+
+pub mod async_store;
+pub mod budget;
+pub mod bulk;
+pub mod cached_store;
+pub mod codec;
+pub mod diff;
+pub mod h256;
+pub mod instrumented_store;
+pub mod journal_store;
+pub mod log_store;
+#[cfg(feature = "mmap")]
+pub mod mmap_store;
+pub mod multi_proof;
+pub mod overlay_store;
+pub mod prune;
+pub mod sharded_store;
+pub mod shared_tree;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
 pub mod tree;
+pub mod versioned_store;
+
+/// Deterministic, well spread keys
+pub fn random_keys(count: u16) -> Vec<H256> {
+    (0..count)
+        .map(|i| {
+            let mut hasher = Blake2bHasher::default();
+            i.to_be_bytes().iter().for_each(|b| hasher.write_byte(*b));
+            hasher.finish()
+        })
+        .collect()
+}
+
+/// Tree over a default store holding keys, each key being its own value
+pub fn build_tree<S>(keys: &[H256]) -> SparseMerkleTree<Blake2bHasher, H256, S>
+where
+    S: StoreReadOps<H256> + StoreWriteOps<H256> + Default,
+{
+    let mut tree = SparseMerkleTree::default();
+    for key in keys {
+        tree.update(*key, *key, true).expect("update");
+    }
+    tree
+}
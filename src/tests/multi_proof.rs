@@ -0,0 +1,545 @@
+use hexlit::hex;
+
+use super::{build_tree, random_keys};
+use crate::{
+    blake2b::Blake2bHasher,
+    default_store::DefaultStore,
+    error::Error,
+    h256::H256,
+    merge::{merge, MergeValue},
+    merkle_proof::Side,
+    multi_proof::{MultiProof, ProofOp},
+    traits::{Hasher, Value},
+    tree::{SparseMerkleTree, MERKLE_LOWER_BOUND, MERKLE_UPPER_BOUND},
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+
+/// Tree of the single bytes 1 to 8, hashed into both the key and the value
+/// of their leaves
+fn byte_tree() -> (SMT, impl Fn(u8) -> H256) {
+    let hash = |member: u8| {
+        let mut hasher = Blake2bHasher::default();
+        hasher.write_byte(member);
+        hasher.finish()
+    };
+    (build_tree(&(1..=8).map(hash).collect::<Vec<_>>()), hash)
+}
+
+/// Bytes of a constant of the Aiken tests, which run the vectors below
+/// against the Aiken verifiers
+fn aiken_const(name: &str) -> Vec<u8> {
+    let source = include_str!("../../lib/aiken/sparse_merkle_tree_blake256_test.ak");
+    let (_, value) = source
+        .split_once(&format!("const {} =", name))
+        .expect("constant of the Aiken tests");
+    let (_, value) = value.split_once("#\"").expect("byte array constant");
+    let (value, _) = value.split_once('"').expect("byte array constant");
+    hex::decode(value).expect("hex byte array")
+}
+
+#[test]
+fn test_multi_proof_verifies() {
+    let keys = random_keys(300);
+    let tree: SMT = build_tree(&keys);
+
+    for proven in [&keys[..1], &keys[..2], &keys[100..150], &keys[..]] {
+        let proof = tree.multi_proof(proven.to_vec()).unwrap();
+        assert_eq!(proof.leaves_count(), proven.len());
+        assert_eq!(proof.neighbours_count(), 0);
+        assert_eq!(
+            proof.compute_root::<Blake2bHasher>(proven.to_vec()),
+            Ok(*tree.root())
+        );
+
+        // keys are taken in key order whatever the order given
+        let mut reversed = proven.to_vec();
+        reversed.reverse();
+        assert_eq!(
+            proof.verify::<Blake2bHasher>(tree.root(), reversed),
+            Ok(true)
+        );
+    }
+}
+
+#[test]
+fn test_single_multi_proof_matches_member_proof() {
+    let keys = random_keys(100);
+    let tree: SMT = build_tree(&keys);
+    let (sides, _) = tree.member_proof(vec![keys[7]]).unwrap().pop().unwrap();
+
+    let proof = tree.multi_proof(vec![keys[7]]).unwrap();
+    let mut expected = vec![ProofOp::Leaf];
+    expected.extend(sides.into_iter().map(ProofOp::Sibling));
+    assert_eq!(proof.take(), expected);
+}
+
+#[test]
+fn test_multi_proof_shares_siblings() {
+    let keys = random_keys(500);
+    let tree: SMT = build_tree(&keys);
+    let proven = keys[..100].to_vec();
+
+    let separate = tree
+        .member_proof(proven.clone())
+        .unwrap()
+        .iter()
+        .map(|(sides, _)| sides.len())
+        .sum::<usize>();
+    let proof = tree.multi_proof(proven).unwrap();
+    assert!(proof.siblings_count() * 2 < separate);
+}
+
+#[test]
+fn test_multi_proof_rejects_wrong_leaves() {
+    let keys = random_keys(50);
+    let tree: SMT = build_tree(&keys);
+    let proof = tree.multi_proof(keys[..10].to_vec()).unwrap();
+
+    let mut proven = keys[..10].to_vec();
+    proven[3] = keys[20];
+    assert_eq!(
+        proof.verify::<Blake2bHasher>(tree.root(), proven),
+        Ok(false)
+    );
+    assert_eq!(
+        proof.verify::<Blake2bHasher>(tree.root(), keys[..9].to_vec()),
+        Err(Error::IncorrectNumberOfLeaves {
+            expected: 10,
+            actual: 9
+        })
+    );
+}
+
+#[test]
+fn test_multi_proof_rejects_values_other_than_keys() {
+    let keys = random_keys(11);
+    let mut tree: SMT = build_tree(&keys[..10]);
+    let value = H256::from([9; 32]);
+    tree.update(keys[10], value, true).unwrap();
+
+    // a member proof hashes the stored value, a multi proof the key
+    assert!(tree.member_proof(vec![keys[10]]).is_ok());
+    assert_eq!(
+        tree.multi_proof(vec![keys[10]]),
+        Err(Error::LeafNotKey(keys[10]))
+    );
+    assert_eq!(
+        tree.multi_proof(keys.clone()),
+        Err(Error::LeafNotKey(keys[10]))
+    );
+    assert!(tree.multi_proof(keys[..10].to_vec()).is_ok());
+
+    // nor can such a leaf be revealed as a neighbour
+    let mut next = <[u8; 32]>::from(keys[10]);
+    next[31] ^= 1;
+    let next = H256::from(next);
+    assert_eq!(
+        tree.modify_root_multi_proof(vec![next], true),
+        Err(Error::LeafNotKey(keys[10]))
+    );
+}
+
+#[test]
+fn test_multi_proof_errors() {
+    let keys = random_keys(50);
+    let tree: SMT = build_tree(&keys[..40]);
+
+    assert_eq!(tree.multi_proof(vec![]), Err(Error::EmptyKeys));
+    assert_eq!(
+        tree.multi_proof(vec![keys[0], MERKLE_LOWER_BOUND]),
+        Err(Error::ReservedKey(MERKLE_LOWER_BOUND))
+    );
+    assert_eq!(
+        tree.multi_proof(vec![keys[0], keys[45]]),
+        Err(Error::LeafNotFound(keys[45]))
+    );
+
+    let compute = |ops| MultiProof::new(ops).compute_root::<Blake2bHasher>(vec![keys[0]]);
+    assert_eq!(compute(vec![]), Err(Error::EmptyProof));
+    assert_eq!(
+        compute(vec![ProofOp::Leaf, ProofOp::Merge]),
+        Err(Error::CorruptedStack)
+    );
+    assert_eq!(
+        compute(vec![
+            ProofOp::Sibling(Side::Left(MergeValue::from_h256(keys[1]))),
+            ProofOp::Leaf
+        ]),
+        Err(Error::CorruptedStack)
+    );
+    // revealed leaves come in key order
+    let (low, high) = (keys[0].min(keys[1]), keys[0].max(keys[1]));
+    assert_eq!(
+        MultiProof::new(vec![
+            ProofOp::Neighbour(high),
+            ProofOp::Neighbour(low),
+            ProofOp::Merge
+        ])
+        .compute_root::<Blake2bHasher>(vec![]),
+        Err(Error::MisplacedLeaf(low))
+    );
+}
+
+#[test]
+fn test_multi_proof_bytes() {
+    let keys = random_keys(100);
+    let tree: SMT = build_tree(&keys);
+    let proof = tree.multi_proof(keys[10..40].to_vec()).unwrap();
+
+    let bytes = proof.to_bytes();
+    assert_eq!(bytes.len(), proof.ops().len() + 32 * proof.siblings_count());
+    assert_eq!(MultiProof::from_bytes(&bytes), Ok(proof));
+
+    let (_, proof) = tree
+        .modify_root_multi_proof(keys[10..40].to_vec(), false)
+        .unwrap();
+    assert!(proof.neighbours_count() > 0);
+    let bytes = proof.to_bytes();
+    assert_eq!(
+        bytes.len(),
+        proof.ops().len() + 32 * (proof.siblings_count() + proof.neighbours_count())
+    );
+    assert_eq!(MultiProof::from_bytes(&bytes), Ok(proof));
+
+    assert_eq!(MultiProof::from_bytes(&[0, 5]), Err(Error::InvalidCode(5)));
+    assert_eq!(
+        MultiProof::from_bytes(&[0, 2, 1, 2, 3]),
+        Err(Error::InvalidLength {
+            expected: 32,
+            actual: 3
+        })
+    );
+}
+
+/// Members are the single bytes 1 to 8, hashed into both the key and the
+/// value of their leaves. Shared with `members_in_tree` tests of the Aiken
+/// library, which list the proven members in key order.
+#[test]
+fn test_multi_proof_vector() {
+    let (tree, hash) = byte_tree();
+    assert_eq!(
+        *tree.root(),
+        H256::from(hex!(
+            "db78690e1b04b87123554318201f5b7330b61b273a050a2b71704d81f5f70f86"
+        ))
+    );
+
+    let proven = [7, 2, 5].map(hash);
+    assert!(proven.windows(2).all(|pair| pair[0] < pair[1]));
+    let proof = tree.multi_proof(proven.to_vec()).unwrap();
+    assert_eq!(
+        hex::encode(proof.to_bytes()),
+        "000003644f2f61cbd5df0890de22ed92257a737c001801f56854e0f25a84aee1cf831d01\
+         0002c035bce6896d3157e4ceeb766ce1dd6015b36d400a9255195bf7ecac4955478203\
+         7876990493d5cbcf3103308f9785364996430fb0512cb4c43844f37d6942eecd02e31d\
+         6803e1c017580d487b7cdc80fa4be91f571d0747e636179483bcc9d0955301021ca8bb\
+         0e6edfa33ccb7967e00f6e74bdacb580b77eddb34d5bc335ba32dc22ea"
+    );
+    assert_eq!(tree.root().as_slice(), aiken_const("multi_root_hash"));
+    assert_eq!(proof.to_bytes(), aiken_const("multi_proof"));
+}
+
+#[test]
+fn test_batch_insertion_and_deletion_proofs() {
+    let keys = random_keys(120);
+    let mut tree: SMT = build_tree(&keys[..100]);
+    let old_root = *tree.root();
+    let inserted_keys = keys[100..].to_vec();
+
+    let (new_root, proof) = tree
+        .modify_root_multi_proof(inserted_keys.clone(), true)
+        .unwrap();
+    assert_eq!(*tree.root(), old_root);
+    for key in &inserted_keys {
+        tree.update(*key, *key, true).unwrap();
+    }
+    assert_eq!(*tree.root(), new_root);
+    assert_eq!(
+        proof.verify_insertion::<Blake2bHasher>(&old_root, &new_root, inserted_keys.clone()),
+        Ok(true)
+    );
+    assert_eq!(
+        proof.verify_deletion::<Blake2bHasher>(&old_root, &new_root, inserted_keys.clone()),
+        Ok(false)
+    );
+
+    // removing the same leaves is proven by the same proof
+    let (removed_root, removal) = tree
+        .modify_root_multi_proof(inserted_keys.clone(), false)
+        .unwrap();
+    assert_eq!(removed_root, old_root);
+    assert_eq!(removal, proof);
+    assert_eq!(
+        removal.verify_deletion::<Blake2bHasher>(&new_root, &old_root, inserted_keys.clone()),
+        Ok(true)
+    );
+
+    // one proof is far smaller than a proof per insertion
+    let separate = tree
+        .modify_root_proof(keys[100..].to_vec())
+        .unwrap()
+        .iter()
+        .map(|(sides, left, continuing, right, _, _)| {
+            sides.len() + left.len() + continuing.len() + right.len()
+        })
+        .sum::<usize>();
+    assert!(proof.siblings_count() * 2 < separate);
+
+    let mut wrong = inserted_keys;
+    wrong[0] = random_keys(121)[120];
+    assert_ne!(
+        proof.verify_insertion::<Blake2bHasher>(&old_root, &new_root, wrong),
+        Ok(true)
+    );
+}
+
+/// Roots with and without member computed from ops, with no check at all
+fn unchecked_roots(ops: &[ProofOp], member: H256) -> (H256, H256) {
+    let leaf = |key: H256| MergeValue::from_h256(key.to_h256::<Blake2bHasher>());
+    let mut stack = Vec::new();
+    for op in ops {
+        let node = match op {
+            ProofOp::Leaf => (leaf(member), MergeValue::zero()),
+            ProofOp::Neighbour(key) => (leaf(*key), leaf(*key)),
+            ProofOp::Sibling(Side::Left(left)) => {
+                let (with, without) = stack.pop().unwrap();
+                (
+                    merge::<Blake2bHasher>(left, &with),
+                    merge::<Blake2bHasher>(left, &without),
+                )
+            }
+            ProofOp::Sibling(Side::Right(right)) => {
+                let (with, without) = stack.pop().unwrap();
+                (
+                    merge::<Blake2bHasher>(&with, right),
+                    merge::<Blake2bHasher>(&without, right),
+                )
+            }
+            ProofOp::Merge => {
+                let (right, right_without) = stack.pop().unwrap();
+                let (left, left_without) = stack.pop().unwrap();
+                (
+                    merge::<Blake2bHasher>(&left, &right),
+                    merge::<Blake2bHasher>(&left_without, &right_without),
+                )
+            }
+        };
+        stack.push(node);
+    }
+    let (with, without) = stack.pop().unwrap();
+    (with.hash(), without.hash())
+}
+
+#[test]
+fn test_batch_insertion_rejects_existing_member() {
+    let keys = random_keys(50);
+    let tree: SMT = build_tree(&keys);
+    let old_root = *tree.root();
+
+    // insert a member once more, right before itself: the forged tree has it
+    // twice, and dropping the new leaf gives back the tree at old_root
+    let member = keys[20];
+    let previous = tree
+        .iter()
+        .map(|leaf| leaf.unwrap().0)
+        .take_while(|key| *key < member)
+        .last()
+        .unwrap();
+    let ops = tree
+        .multi_proof(vec![previous, member])
+        .unwrap()
+        .take()
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, op)| match (i, op) {
+            (0, ProofOp::Leaf) => vec![ProofOp::Neighbour(previous), ProofOp::Leaf, ProofOp::Merge],
+            (_, ProofOp::Leaf) => vec![ProofOp::Neighbour(member)],
+            (_, op) => vec![op],
+        })
+        .collect::<Vec<_>>();
+    let (forged_root, without) = unchecked_roots(&ops, member);
+    assert_eq!(without, old_root);
+    assert_eq!(
+        MultiProof::new(ops).verify_insertion::<Blake2bHasher>(
+            &old_root,
+            &forged_root,
+            vec![member]
+        ),
+        Err(Error::MisplacedLeaf(member))
+    );
+
+    // leaving the leaves after it hidden does not help either
+    let hidden = tree
+        .multi_proof(vec![previous])
+        .unwrap()
+        .take()
+        .into_iter()
+        .flat_map(|op| match op {
+            ProofOp::Leaf => vec![ProofOp::Neighbour(previous), ProofOp::Leaf, ProofOp::Merge],
+            op => vec![op],
+        })
+        .collect::<Vec<_>>();
+    let (forged_root, without) = unchecked_roots(&hidden, member);
+    assert_eq!(without, old_root);
+    assert_eq!(
+        MultiProof::new(hidden).verify_insertion::<Blake2bHasher>(
+            &old_root,
+            &forged_root,
+            vec![member]
+        ),
+        Err(Error::MissingNeighbour(member))
+    );
+}
+
+#[test]
+fn test_batch_proof_errors() {
+    let keys = random_keys(20);
+    let tree: SMT = build_tree(&keys[..10]);
+
+    assert_eq!(
+        tree.modify_root_multi_proof(vec![], true),
+        Err(Error::EmptyKeys)
+    );
+    assert_eq!(
+        tree.modify_root_multi_proof(keys[5..15].to_vec(), true),
+        Err(Error::LeafExists(keys[5]))
+    );
+    assert_eq!(
+        tree.modify_root_multi_proof(keys[5..15].to_vec(), false),
+        Err(Error::LeafNotFound(keys[10]))
+    );
+}
+
+/// Inserts the single bytes 9 to 11 into the tree of `test_multi_proof_vector`,
+/// shared with the `verify_added_members` tests of the Aiken library
+#[test]
+fn test_batch_insertion_vector() {
+    let (tree, hash) = byte_tree();
+    let inserted = (9..=11).map(&hash).collect();
+    let (new_root, proof) = tree.modify_root_multi_proof(inserted, true).unwrap();
+    assert_eq!(
+        new_root,
+        H256::from(hex!(
+            "c394f27541835c92271927472d5340cda6ece7c07670a2b738736dbc765df14a"
+        ))
+    );
+    assert_eq!(
+        hex::encode(proof.to_bytes()),
+        "040000000000000000000000000000000000000000000000000000000000000000000001\
+         04642206314f534b29ad297d82440a5f9f210e30ca5ced805a587ca402de927342010104\
+         ee155ace9c40292074cb6aff8c9ccdd273c81648ff1149ef36bcea6ebb8a3e25024638d1\
+         34d0f4d74cbcc0dbc4a8008c9da44c93ba748e43deae8d5fd8a7df29750004fadd2180bd\
+         6b1cfa73a67e7892d878521ef69918995040fb8661647d321e0c550394a0f4d3b425656d\
+         aa31b56b4fbf0c03033632f63de400af66b26491a4a296a4037876990493d5cbcf310330\
+         8f9785364996430fb0512cb4c43844f37d6942eecd010102064570132036543c08a65c97\
+         554d3e55682359c53fece352dd292be7feedcab601"
+    );
+    assert_eq!(new_root.as_slice(), aiken_const("added_root_hash"));
+    assert_eq!(proof.to_bytes(), aiken_const("added_proof"));
+    assert_eq!(
+        proof.verify_insertion::<Blake2bHasher>(
+            tree.root(),
+            &new_root,
+            (9..=11).map(hash).collect()
+        ),
+        Ok(true)
+    );
+}
+
+/// Forges the insertion of the single byte 5 into the tree of
+/// `test_multi_proof_vector`, which already holds it, shared with the
+/// `verify_added_members` tests of the Aiken library
+#[test]
+fn test_existing_member_vector() {
+    let (tree, hash) = byte_tree();
+    let member = hash(5);
+    let previous = tree
+        .iter()
+        .map(|leaf| leaf.unwrap().0)
+        .take_while(|key| *key < member)
+        .last()
+        .unwrap();
+    let forged = MultiProof::new(
+        tree.multi_proof(vec![previous])
+            .unwrap()
+            .take()
+            .into_iter()
+            .flat_map(|op| match op {
+                ProofOp::Leaf => vec![ProofOp::Neighbour(previous), ProofOp::Leaf, ProofOp::Merge],
+                op => vec![op],
+            })
+            .collect(),
+    );
+    let (forged_root, without) = unchecked_roots(forged.ops(), member);
+    assert_eq!(without, *tree.root());
+    assert_eq!(
+        forged_root,
+        H256::from(hex!(
+            "450f712140fa0d6d8d523637b57e6d2d3b46e79eb2e2b76852cd80b2bdfd494a"
+        ))
+    );
+    assert_eq!(
+        hex::encode(forged.to_bytes()),
+        "04fadd2180bd6b1cfa73a67e7892d878521ef69918995040fb8661647d321e0c550001\
+         0394a0f4d3b425656daa31b56b4fbf0c03033632f63de400af66b26491a4a296a40378\
+         76990493d5cbcf3103308f9785364996430fb0512cb4c43844f37d6942eecd02e31d68\
+         03e1c017580d487b7cdc80fa4be91f571d0747e636179483bcc9d09553020645701320\
+         36543c08a65c97554d3e55682359c53fece352dd292be7feedcab6021ca8bb0e6edfa3\
+         3ccb7967e00f6e74bdacb580b77eddb34d5bc335ba32dc22ea"
+    );
+    assert_eq!(forged_root.as_slice(), aiken_const("existing_root_hash"));
+    assert_eq!(forged.to_bytes(), aiken_const("existing_proof"));
+    assert_eq!(
+        forged.verify_insertion::<Blake2bHasher>(tree.root(), &forged_root, vec![member]),
+        Err(Error::MissingNeighbour(member))
+    );
+}
+
+/// Heights are not hashed, so a proof may merge a member with the other one
+/// of its neighbours: the verifiers accept it, and only the new root tells
+/// the forged tree apart
+#[test]
+fn test_forged_shape_is_detected_by_root() {
+    let tree = SMT::default();
+    let member = H256::from([1; 32]);
+    let (new_root, proof) = tree.modify_root_multi_proof(vec![member], true).unwrap();
+    // the member forks from the upper bound first, next to the lower one
+    let canonical = MultiProof::new(vec![
+        ProofOp::Neighbour(MERKLE_LOWER_BOUND),
+        ProofOp::Leaf,
+        ProofOp::Merge,
+        ProofOp::Neighbour(MERKLE_UPPER_BOUND),
+        ProofOp::Merge,
+    ]);
+    assert_eq!(proof, canonical);
+
+    let forged = MultiProof::new(vec![
+        ProofOp::Neighbour(MERKLE_LOWER_BOUND),
+        ProofOp::Leaf,
+        ProofOp::Neighbour(MERKLE_UPPER_BOUND),
+        ProofOp::Merge,
+        ProofOp::Merge,
+    ]);
+    let (forged_root, old_root) = forged.compute_roots::<Blake2bHasher>(vec![member]).unwrap();
+    assert_eq!(&old_root, tree.root());
+    assert_eq!(
+        forged.verify_insertion::<Blake2bHasher>(tree.root(), &forged_root, vec![member]),
+        Ok(true)
+    );
+    assert_eq!(
+        forged.verify_deletion::<Blake2bHasher>(&forged_root, tree.root(), vec![member]),
+        Ok(true)
+    );
+
+    // the forged root is not the root the tree reaches
+    let mut updated = SMT::default();
+    updated.update(member, member, true).unwrap();
+    assert_eq!(updated.root(), &new_root);
+    assert_ne!(forged_root, new_root);
+    assert_eq!(
+        forged.verify_insertion::<Blake2bHasher>(tree.root(), &new_root, vec![member]),
+        Ok(false)
+    );
+}
@@ -0,0 +1,94 @@
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher, default_store::DefaultStore, error::Error, h256::H256,
+    tree::SparseMerkleTree,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+
+#[test]
+fn test_speculative_update() {
+    let keys = random_keys(60);
+    let mut tree = SMT::default();
+    for key in &keys[..50] {
+        tree.update(*key, *key, true).unwrap();
+    }
+    let root = *tree.root();
+    let store = tree.store().clone();
+
+    // the same updates made on a copy of the tree
+    let mut expected = SMT::new(root, store.clone());
+    for key in &keys[50..] {
+        expected.update(*key, *key, true).unwrap();
+    }
+    expected.update(keys[0], keys[0], false).unwrap();
+
+    let mut overlay = tree.overlay();
+    for key in &keys[50..] {
+        overlay.update(*key, *key, true).unwrap();
+    }
+    overlay.update(keys[0], keys[0], false).unwrap();
+    assert_eq!(overlay.root(), expected.root());
+    assert_eq!(overlay.get(&keys[55]), Ok(keys[55]));
+    assert_eq!(
+        overlay.modify_root_proof(vec![keys[55]]),
+        expected.modify_root_proof(vec![keys[55]])
+    );
+    assert_eq!(
+        overlay.member_proof(keys[50..].to_vec()),
+        expected.member_proof(keys[50..].to_vec())
+    );
+    assert_eq!(tree.store().branches_map(), store.branches_map());
+    assert_eq!(tree.store().leaves_map(), store.leaves_map());
+
+    // discarding the writes brings the base state back
+    let mut discarded = tree.overlay();
+    discarded.update(keys[50], keys[50], true).unwrap();
+    discarded.store_mut().discard();
+    assert!(discarded.store().batch().is_empty());
+
+    let staged = overlay.into_batch();
+    assert_eq!(staged.base_root, root);
+    assert_eq!(&staged.root, expected.root());
+    tree.apply_batch(staged).unwrap();
+    assert_eq!(tree.root(), expected.root());
+    assert_eq!(tree.store().branches_map(), expected.store().branches_map());
+    assert_eq!(tree.store().leaves_map(), expected.store().leaves_map());
+    assert_eq!(tree.len(), Ok(59));
+}
+
+#[test]
+fn test_apply_batch_to_moved_tree() {
+    let keys = random_keys(30);
+    let mut tree = SMT::default();
+    for key in &keys[..20] {
+        tree.update(*key, *key, true).unwrap();
+    }
+    let mut overlay = tree.overlay();
+    overlay.update(keys[20], keys[20], true).unwrap();
+    let staged = overlay.into_batch();
+
+    // the tree moves on after the overlay was taken
+    tree.update(keys[21], keys[21], true).unwrap();
+    let root = *tree.root();
+    let store = tree.store().clone();
+    assert_eq!(
+        tree.apply_batch(staged.clone()),
+        Err(Error::StaleBatch {
+            base_root: staged.base_root,
+            root,
+        })
+    );
+    assert_eq!(*tree.root(), root);
+    assert_eq!(tree.store().branches_map(), store.branches_map());
+    assert_eq!(tree.store().leaves_map(), store.leaves_map());
+    assert_eq!(tree.get(&keys[20]), Ok(H256::zero()));
+
+    // staged again over the current root, it applies
+    let mut overlay = tree.overlay();
+    overlay.update(keys[20], keys[20], true).unwrap();
+    tree.apply_batch(overlay.into_batch()).unwrap();
+    assert_eq!(tree.len(), Ok(22));
+    assert_eq!(tree.counts(), Some(&tree.walk_counts().unwrap()));
+}
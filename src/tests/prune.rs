@@ -0,0 +1,217 @@
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher,
+    default_store::DefaultStore,
+    error::Error,
+    h256::H256,
+    journal_store::JournalStore,
+    prune::{prune, sweep, PruneReport},
+    traits::StoreWriteOps,
+    tree::{BranchKey, SparseMerkleTree},
+    versioned_store::VersionedStore,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+type VersionedSMT = SparseMerkleTree<Blake2bHasher, H256, VersionedStore<H256>>;
+type JournalSMT = SparseMerkleTree<Blake2bHasher, H256, JournalStore<H256, DefaultStore<H256>>>;
+
+/// Leave nodes of a write that never completed next to the tree
+fn add_stale_nodes(tree: &mut SMT, keys: &[H256]) {
+    let stale = tree
+        .store()
+        .branches_map()
+        .values()
+        .next()
+        .cloned()
+        .unwrap();
+    for key in keys {
+        tree.store_mut().insert_leaf(*key, *key).unwrap();
+        tree.store_mut()
+            .insert_branch(BranchKey::new(3, *key), stale.clone())
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_prune_default_store() {
+    let keys = random_keys(30);
+    let mut tree = SMT::default();
+    for key in &keys[..20] {
+        tree.update(*key, *key, true).unwrap();
+    }
+    let root = *tree.root();
+    let store = tree.store().clone();
+    add_stale_nodes(&mut tree, &keys[20..]);
+
+    // a store holding a single tree retains no other root
+    assert_eq!(
+        prune::<Blake2bHasher, H256, _>(tree.store_mut(), &[H256::zero()]),
+        Err(Error::MissingRoot(H256::zero()))
+    );
+    // but the nodes its current tree does not reach are swept
+    assert_eq!(
+        prune::<Blake2bHasher, H256, _>(tree.store_mut(), &[root]),
+        Ok(PruneReport {
+            roots_removed: 0,
+            branches_removed: 10,
+            leaves_removed: 10,
+        })
+    );
+    assert_eq!(tree.store().branches_map(), store.branches_map());
+    assert_eq!(tree.store().leaves_map(), store.leaves_map());
+    assert_eq!(
+        prune::<Blake2bHasher, H256, _>(tree.store_mut(), &[]),
+        Ok(PruneReport::default())
+    );
+    assert_eq!(tree.store().branches_map(), store.branches_map());
+}
+
+#[test]
+fn test_sweep_unreachable_nodes() {
+    let keys = random_keys(30);
+    let mut tree = SMT::default();
+    for key in &keys[..20] {
+        tree.update(*key, *key, true).unwrap();
+    }
+    let store = tree.store().clone();
+    add_stale_nodes(&mut tree, &keys[20..]);
+
+    assert_eq!(
+        sweep::<Blake2bHasher, H256, _>(tree.store_mut()),
+        Ok(PruneReport {
+            roots_removed: 0,
+            branches_removed: 10,
+            leaves_removed: 10,
+        })
+    );
+    assert_eq!(tree.store().branches_map(), store.branches_map());
+    assert_eq!(tree.store().leaves_map(), store.leaves_map());
+}
+
+#[test]
+fn test_prune_versioned_store() {
+    let keys = random_keys(40);
+    let mut tree = VersionedSMT::default();
+    let mut roots = vec![];
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+        roots.push(*tree.root());
+    }
+    for key in &keys[..20] {
+        tree.update(*key, *key, false).unwrap();
+        roots.push(*tree.root());
+    }
+
+    let kept = [roots[4], roots[59]];
+    let members = kept.map(|root| {
+        let snapshot = tree.snapshot(&root).unwrap();
+        snapshot.iter().collect::<Result<Vec<_>, _>>().unwrap()
+    });
+
+    let report = tree.store_mut().prune(&kept).unwrap();
+    // the default root and every root but the kept ones
+    assert_eq!(report.roots_removed, roots.len() + 1 - kept.len());
+    assert!(report.branches_removed > 0);
+    // both entries of the members added after the first kept root and
+    // removed before the second one
+    assert_eq!(report.leaves_removed, 30);
+    assert_eq!(tree.store_mut().prune(&kept), Ok(PruneReport::default()));
+
+    for (root, members) in kept.iter().zip(members) {
+        let snapshot = tree.snapshot(root).unwrap();
+        assert_eq!(snapshot.iter().collect::<Result<Vec<_>, _>>(), Ok(members));
+    }
+    assert_eq!(
+        tree.snapshot(&roots[39]).map(|_| ()),
+        Err(Error::MissingRoot(roots[39]))
+    );
+
+    // the current tree is untouched
+    assert_eq!(tree.len(), Ok(20));
+    for key in &keys[20..] {
+        assert_eq!(tree.get(key), Ok(*key));
+    }
+    tree.update(keys[0], keys[0], true).unwrap();
+    assert_eq!(tree.snapshot(&roots[4]).unwrap().len(), Ok(5));
+}
+
+#[test]
+fn test_prune_versioned_store_through_trait() {
+    let keys = random_keys(20);
+    let mut tree = VersionedSMT::default();
+    let mut roots = vec![];
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+        roots.push(*tree.root());
+    }
+    let mut expected = tree.store().clone();
+
+    let kept = [roots[3], roots[12]];
+    assert_eq!(
+        prune::<Blake2bHasher, H256, _>(tree.store_mut(), &kept),
+        expected.prune(&kept)
+    );
+    assert_eq!(tree.store().roots(), expected.roots());
+    assert_eq!(tree.snapshot(&roots[3]).unwrap().len(), Ok(4));
+}
+
+#[test]
+fn test_prune_journal_store() {
+    let keys = random_keys(60);
+    let mut tree = JournalSMT::default();
+    let mut expected = SMT::default();
+    let mut history = vec![expected.store().clone()];
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+        expected.update(*key, *key, true).unwrap();
+        history.push(expected.store().clone());
+    }
+    for key in &keys[..20] {
+        tree.update(*key, *key, false).unwrap();
+        expected.update(*key, *key, false).unwrap();
+        history.push(expected.store().clone());
+    }
+    let roots = tree.store().checkpoints().copied().collect::<Vec<_>>();
+    assert_eq!(roots.len(), history.len());
+
+    let unknown = random_keys(61)[60];
+    assert_eq!(
+        prune::<Blake2bHasher, H256, _>(tree.store_mut(), &[unknown]),
+        Err(Error::MissingRoot(unknown))
+    );
+
+    // every checkpoint but two older roots and the current one goes, along
+    // with the undo records only the dropped roots needed
+    let kept = [roots[10], roots[45]];
+    let report = prune::<Blake2bHasher, H256, _>(tree.store_mut(), &kept).unwrap();
+    assert_eq!(report.roots_removed, roots.len() - 3);
+    assert!(report.branches_removed > 0);
+    assert!(report.leaves_removed > 0);
+    assert_eq!(
+        tree.store().checkpoints().collect::<Vec<_>>(),
+        vec![&roots[10], &roots[45], roots.last().unwrap()]
+    );
+    assert_eq!(tree.len(), Ok(40));
+    assert_eq!(
+        prune::<Blake2bHasher, H256, _>(tree.store_mut(), &kept),
+        Ok(PruneReport::default())
+    );
+
+    // the kept roots still roll back to the exact trees
+    assert_eq!(
+        tree.rollback_to(&roots[30]),
+        Err(Error::MissingRoot(roots[30]))
+    );
+    for i in [45, 10] {
+        tree.rollback_to(&roots[i]).unwrap();
+        assert_eq!(*tree.root(), roots[i]);
+        assert_eq!(
+            tree.store().inner().branches_map(),
+            history[i].branches_map()
+        );
+        assert_eq!(tree.store().inner().leaves_map(), history[i].leaves_map());
+        assert_eq!(tree.iter().count(), i);
+        assert_eq!(tree.len(), Ok(i));
+    }
+}
@@ -0,0 +1,99 @@
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher, default_store::DefaultStore, h256::H256,
+    instrumented_store::InstrumentedStore, prune::sweep, sharded_store::ShardedStore,
+    traits::StoreReadOps, tree::SparseMerkleTree,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+type ShardedSMT = SparseMerkleTree<Blake2bHasher, H256, ShardedStore<DefaultStore<H256>>>;
+
+#[test]
+fn test_sharded_store() {
+    let keys = random_keys(200);
+    let mut expected = SMT::default();
+    let store = ShardedStore::new(DefaultStore::default(), vec![DefaultStore::default(); 4]);
+    let mut tree = ShardedSMT::new_empty(store.unwrap()).unwrap();
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+        expected.update(*key, *key, true).unwrap();
+    }
+    for key in &keys[..50] {
+        tree.update(*key, *key, false).unwrap();
+        expected.update(*key, *key, false).unwrap();
+    }
+    assert_eq!(tree.root(), expected.root());
+    assert_eq!(
+        tree.member_proof(keys[50..60].to_vec()),
+        expected.member_proof(keys[50..60].to_vec())
+    );
+
+    let store = tree.store();
+    for (i, shard) in store.shards().iter().enumerate() {
+        assert!(!shard.leaves_map().is_empty());
+        for key in shard.leaves_map().keys() {
+            assert_eq!(usize::from(key.as_slice()[0] >> 6), i);
+        }
+        for branch_key in shard.branches_map().keys() {
+            assert!(branch_key.height < 254);
+            assert_eq!(store.shard_of_branch(branch_key), Some(i));
+        }
+    }
+    // the root, and the branch splitting each half if there is one
+    assert!(store.top().leaves_map().is_empty());
+    assert!((1..=3).contains(&store.top().branches_map().len()));
+
+    let branches = store
+        .shards()
+        .iter()
+        .map(|shard| shard.branches_map().len());
+    assert_eq!(
+        branches.sum::<usize>() + store.top().branches_map().len(),
+        expected.store().branches_map().len()
+    );
+
+    let report = sweep::<Blake2bHasher, H256, _>(tree.store_mut()).unwrap();
+    assert_eq!((report.branches_removed, report.leaves_removed), (0, 0));
+
+    assert!(ShardedStore::new(DefaultStore::<H256>::default(), vec![]).is_err());
+    assert!(ShardedStore::new(
+        DefaultStore::<H256>::default(),
+        vec![DefaultStore::default(); 3]
+    )
+    .is_err());
+}
+
+#[test]
+fn test_sharded_store_metrics() {
+    let keys = random_keys(50);
+    let instrumented = || InstrumentedStore::new(DefaultStore::<H256>::default());
+    let store = ShardedStore::new(instrumented(), (0..4).map(|_| instrumented()).collect());
+    let mut tree = SparseMerkleTree::<Blake2bHasher, H256, _>::new_empty(store.unwrap()).unwrap();
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+    }
+
+    // the metrics of every store add up
+    let store = tree.store();
+    let metrics = StoreReadOps::<H256>::store_metrics(store).unwrap();
+    let parts = core::iter::once(store.top())
+        .chain(store.shards())
+        .map(InstrumentedStore::metrics);
+    assert_eq!(
+        metrics.leaf_writes,
+        parts.clone().map(|m| m.leaf_writes).sum::<u64>()
+    );
+    assert_eq!(
+        metrics.branch_reads,
+        parts.map(|m| m.branch_reads).sum::<u64>()
+    );
+    assert!(metrics.leaf_writes >= keys.len() as u64);
+
+    // a store that counts nothing has no metrics
+    let plain = ShardedStore::new(
+        DefaultStore::<H256>::default(),
+        vec![DefaultStore::default()],
+    );
+    assert_eq!(StoreReadOps::<H256>::store_metrics(&plain.unwrap()), None);
+}
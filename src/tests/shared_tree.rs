@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher, default_store::DefaultStore, h256::H256, shared_tree::SharedTree,
+    tree::SparseMerkleTree, versioned_store::VersionedStore,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+type VersionedSMT = SparseMerkleTree<Blake2bHasher, H256, VersionedStore<H256>>;
+
+#[test]
+fn test_readers_see_consistent_roots() {
+    let keys = random_keys(200);
+
+    // proof of the first key at every root the writer goes through
+    let mut expected = HashMap::new();
+    let mut tree = SMT::default();
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+        expected.insert(*tree.root(), tree.member_proof(vec![keys[0]]).unwrap());
+    }
+    let expected = Arc::new(expected);
+
+    let mut first = SMT::default();
+    first.update(keys[0], keys[0], true).unwrap();
+    let shared = Arc::new(SharedTree::new(first));
+
+    let readers = (0..4)
+        .map(|_| {
+            let shared = Arc::clone(&shared);
+            let expected = Arc::clone(&expected);
+            let first = keys[0];
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    let (root, proof) = shared.member_proof(vec![first]).unwrap();
+                    assert_eq!(expected.get(&root), Some(&proof));
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for key in &keys[1..] {
+        shared.update(*key, *key, true).unwrap();
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    assert_eq!(shared.root(), *tree.root());
+    let shared = match Arc::try_unwrap(shared) {
+        Ok(shared) => shared.into_inner(),
+        Err(_) => panic!("readers are done"),
+    };
+    assert_eq!(shared.stats().unwrap(), tree.stats().unwrap());
+    assert_eq!(shared.store().branches_map(), tree.store().branches_map());
+}
+
+#[test]
+fn test_failed_update_keeps_tree() {
+    let keys = random_keys(10);
+    let shared = SharedTree::from(SMT::default());
+    for key in &keys {
+        shared.update(*key, *key, true).unwrap();
+    }
+    let root = shared.root();
+    assert!(shared.update(keys[0], keys[0], true).is_err());
+    assert_eq!(shared.root(), root);
+}
+
+#[test]
+fn test_proofs_at_retained_roots() {
+    let keys = random_keys(50);
+    let shared = SharedTree::new(VersionedSMT::default());
+    let mut roots = Vec::new();
+    for key in &keys {
+        roots.push(shared.update(*key, *key, true).unwrap());
+    }
+
+    // snapshots own their handle on the versions, so the read guard is
+    // dropped before the _at methods lock the tree again
+    let snapshots = {
+        let tree = shared.read();
+        roots
+            .iter()
+            .map(|root| tree.snapshot(root).unwrap())
+            .collect::<Vec<_>>()
+    };
+    for (i, (root, snapshot)) in roots.iter().zip(&snapshots).enumerate() {
+        assert_eq!(
+            shared.member_proof_at(root, vec![keys[i]]).unwrap(),
+            snapshot.member_proof(vec![keys[i]]).unwrap()
+        );
+        assert_eq!(
+            shared.modify_root_proof_at(root, vec![keys[0]]).unwrap(),
+            snapshot.modify_root_proof(vec![keys[0]]).unwrap()
+        );
+        assert_eq!(shared.get_at(root, &keys[i]).unwrap(), keys[i]);
+    }
+}
@@ -0,0 +1,62 @@
+use rusqlite::Connection;
+
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher, default_store::DefaultStore, error::Error, h256::H256,
+    sqlite_store::SqliteStore, tree::SparseMerkleTree,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+type SMT = SparseMerkleTree<Blake2bHasher, H256, DefaultStore<H256>>;
+type SqliteSMT<'c> = SparseMerkleTree<Blake2bHasher, H256, SqliteStore<'c, H256>>;
+
+#[test]
+fn test_sqlite_store() {
+    let keys = random_keys(60);
+    let mut conn = Connection::open_in_memory().unwrap();
+    let mut expected = SMT::default();
+
+    {
+        let tx = conn.transaction().unwrap();
+        let mut tree = SqliteSMT::new_empty(SqliteStore::new(&tx).unwrap()).unwrap();
+        for key in &keys[..40] {
+            tree.update(*key, *key, true).unwrap();
+            expected.update(*key, *key, true).unwrap();
+        }
+        assert_eq!(tree.root(), expected.root());
+        drop(tree);
+        tx.commit().unwrap();
+    }
+
+    // the writes of a transaction rolled back are gone
+    {
+        let tx = conn.transaction().unwrap();
+        let mut tree = SqliteSMT::new_with_store(SqliteStore::new(&tx).unwrap()).unwrap();
+        for key in &keys[40..] {
+            tree.update(*key, *key, true).unwrap();
+        }
+        drop(tree);
+        tx.rollback().unwrap();
+    }
+
+    // the tree already stored is never overwritten by an empty one
+    assert_eq!(
+        SqliteSMT::new_empty(SqliteStore::new(&conn).unwrap()).map(|_| ()),
+        Err(Error::NonEmptyStore(*expected.root()))
+    );
+    let tree = SqliteSMT::new_with_store(SqliteStore::new(&conn).unwrap()).unwrap();
+    assert_eq!(tree.root(), expected.root());
+    assert_eq!(tree.len(), Ok(40));
+    assert_eq!(tree.get(&keys[10]), Ok(keys[10]));
+    assert_eq!(tree.get(&keys[50]), Ok(H256::zero()));
+    assert_eq!(
+        tree.member_proof(keys[..5].to_vec()),
+        expected.member_proof(keys[..5].to_vec())
+    );
+
+    // trees in other tables are independent
+    let other =
+        SqliteSMT::new_with_store(SqliteStore::with_table_name(&conn, "other").unwrap()).unwrap();
+    assert!(other.root().is_zero());
+    assert!(SqliteStore::<H256>::with_table_name(&conn, "bad name").is_err());
+}
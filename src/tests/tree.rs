@@ -1,13 +1,17 @@
 use hexlit::hex;
 use itertools::Itertools;
 
+use super::{build_tree, random_keys};
+
 use crate::{
     blake2b::Blake2bHasher,
     default_store::DefaultStore,
+    error::Error,
     h256::H256,
+    instrumented_store::InstrumentedStore,
     merge::{merge, MergeValue},
-    traits::Value,
-    tree::SparseMerkleTree,
+    traits::{StoreWriteOps, Value},
+    tree::{SparseMerkleTree, MERKLE_LOWER_BOUND, MERKLE_UPPER_BOUND},
 };
 
 #[allow(clippy::upper_case_acronyms)]
@@ -261,3 +265,283 @@ fn test_contiguous() {
         hex!("0000000000000000000000000000000000000000000000000000000000000003"),
     );
 }
+
+#[test]
+fn test_iter() {
+    let mut keys = random_keys(100);
+    let mut tree: SMT = build_tree(&keys);
+    keys.sort_unstable();
+
+    let leaves = tree.iter().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(leaves, keys.iter().map(|k| (*k, *k)).collect::<Vec<_>>());
+
+    let removed = keys.remove(42);
+    tree.update(removed, removed, false).unwrap();
+    let leaves = tree.iter().map(|x| x.unwrap().0).collect::<Vec<_>>();
+    assert_eq!(leaves, keys);
+
+    assert_eq!(SMT::default().iter().count(), 0);
+    assert_eq!(
+        SMT::new(H256::zero(), DefaultStore::default())
+            .iter()
+            .count(),
+        0
+    );
+}
+
+#[test]
+fn test_range() {
+    let mut keys = random_keys(100);
+    let tree: SMT = build_tree(&keys);
+    keys.sort_unstable();
+
+    let range = |start: usize, end: usize| keys[start..end].to_vec();
+    let collect = |iter: crate::iter::Iter<'_, H256, DefaultStore<H256>>| {
+        iter.map(|x| x.unwrap().0).collect::<Vec<_>>()
+    };
+
+    assert_eq!(collect(tree.range(keys[10]..keys[20])), range(10, 20));
+    assert_eq!(collect(tree.range(keys[10]..=keys[20])), range(10, 21));
+    assert_eq!(collect(tree.range(..keys[5])), range(0, 5));
+    assert_eq!(collect(tree.range(keys[95]..)), range(95, 100));
+    assert_eq!(collect(tree.range(keys[30]..keys[30])), vec![]);
+
+    // bounds that are not members of the tree
+    let mut start = keys[50];
+    start.as_mut_slice()[31] = start.as_slice()[31].wrapping_add(1);
+    assert_eq!(collect(tree.range(start..keys[60])), range(51, 60));
+}
+
+#[test]
+fn test_bound_keys_are_reserved() {
+    let keys = random_keys(10);
+    let mut tree: SMT = build_tree(&keys);
+    let root = *tree.root();
+
+    for bound in [MERKLE_LOWER_BOUND, MERKLE_UPPER_BOUND] {
+        assert_eq!(
+            tree.update(bound, bound, true),
+            Err(Error::ReservedKey(bound))
+        );
+        assert_eq!(
+            tree.update(bound, bound, false),
+            Err(Error::ReservedKey(bound))
+        );
+        assert_eq!(tree.get(&bound), Ok(H256::zero()));
+        assert_eq!(
+            tree.member_proof(vec![keys[0], bound]),
+            Err(Error::ReservedKey(bound))
+        );
+        assert_eq!(
+            tree.modify_root_proof(vec![bound]),
+            Err(Error::ReservedKey(bound))
+        );
+    }
+
+    assert_eq!(*tree.root(), root);
+    assert_eq!(tree.get(&keys[0]), Ok(keys[0]));
+    assert_eq!(tree.iter().count(), keys.len());
+}
+
+#[test]
+fn test_len_and_stats() {
+    let mut tree = SMT::default();
+    assert!(tree.is_empty());
+    assert_eq!(tree.len(), Ok(0));
+
+    let keys = random_keys(200);
+    for key in &keys {
+        tree.update(*key, *key, true).unwrap();
+    }
+    assert!(!tree.is_empty());
+    assert_eq!(tree.len(), Ok(200));
+
+    let stats = tree.stats().unwrap();
+    assert_eq!(stats.leaves, 200);
+    // a binary tree over the members and the two bound leaves
+    assert_eq!(stats.branches, 201);
+    assert!(stats.max_path_length >= 8);
+    assert!(stats.average_path_length <= stats.max_path_length as f64);
+
+    // proofs have one step per branch on the path
+    let (proof, _) = tree.member_proof(vec![keys[0]]).unwrap().pop().unwrap();
+    assert!(proof.len() <= stats.max_path_length);
+
+    for key in &keys[..150] {
+        tree.update(*key, *key, false).unwrap();
+    }
+    assert_eq!(tree.len(), Ok(50));
+    assert_eq!(tree.stats().unwrap().branches, 51);
+
+    // counts of a reopened store are read back with its root
+    let reopened = SMT::new_with_store(tree.take_store()).unwrap();
+    assert_eq!(reopened.counts(), Some(&reopened.walk_counts().unwrap()));
+    assert_eq!(reopened.len(), Ok(50));
+    assert_eq!(reopened.stats().unwrap().leaves, 50);
+}
+
+#[test]
+fn test_new_empty_rejects_stored_tree() {
+    let keys = random_keys(10);
+    let tree: SMT = build_tree(&keys);
+    let root = *tree.root();
+    let store = tree.store().clone();
+
+    assert_eq!(
+        SMT::new_empty(store.clone()).map(|_| ()),
+        Err(Error::NonEmptyStore(root))
+    );
+    // the empty tree is a tree as well
+    let empty = SMT::default().take_store();
+    assert!(SMT::new_empty(empty).is_err());
+    assert_eq!(SMT::new_with_store(store).unwrap().root(), &root);
+    assert!(SMT::new_empty(DefaultStore::default()).is_ok());
+}
+
+#[test]
+fn test_counts_read_only_the_path() {
+    let keys = random_keys(400);
+    let (low, high): (Vec<H256>, Vec<H256>) = keys
+        .into_iter()
+        .filter(|key| key.as_slice()[0] >> 7 == 0)
+        .partition(|key| key.as_slice()[0] >> 6 == 0);
+    let store = InstrumentedStore::new(DefaultStore::<H256>::default());
+    let mut tree = SparseMerkleTree::<Blake2bHasher, H256, _>::new_empty(store).unwrap();
+    for key in &low {
+        tree.update(*key, *key, true).unwrap();
+    }
+
+    // the sibling of the member holds every other member, yet only the
+    // branches above the member are read
+    let member = high[0];
+    assert!(low.len() > 50);
+    for insertion in [true, false] {
+        tree.store().take_metrics();
+        tree.update(member, member, insertion).unwrap();
+        assert!(tree.store().take_metrics().branch_reads <= 3);
+        assert_eq!(tree.counts(), Some(&tree.walk_counts().unwrap()));
+    }
+}
+
+#[test]
+fn test_counts_match_walk() {
+    let mut tree = SMT::default();
+    let keys = random_keys(300);
+    for (i, key) in keys.iter().enumerate() {
+        tree.update(*key, *key, true).unwrap();
+        // removing members as we go, failed updates leave the counts alone
+        if i % 3 == 2 {
+            tree.update(keys[i / 2], keys[i / 2], false).unwrap();
+            tree.update(*key, *key, true).unwrap_err();
+        }
+        if i % 25 == 0 {
+            assert_eq!(tree.counts(), Some(&tree.walk_counts().unwrap()));
+        }
+    }
+    let counts = tree.walk_counts().unwrap();
+    assert_eq!(tree.counts(), Some(&counts));
+    assert_eq!(tree.len(), Ok(counts.leaves));
+    assert_eq!(tree.stats(), Ok(counts.stats()));
+    assert_eq!(tree.iter().count(), counts.leaves);
+}
+
+#[test]
+fn test_update_existing_and_missing_leaves() {
+    let keys = random_keys(10);
+    let mut tree: SMT = build_tree(&keys[..5]);
+    let root = *tree.root();
+
+    assert_eq!(
+        tree.update(keys[0], keys[0], true),
+        Err(Error::LeafExists(keys[0]))
+    );
+    assert_eq!(
+        tree.update(keys[7], keys[7], false),
+        Err(Error::LeafNotFound(keys[7]))
+    );
+    assert_eq!(*tree.root(), root);
+    assert_eq!(tree.len(), Ok(5));
+}
+
+#[test]
+fn test_failed_update_leaves_store_untouched() {
+    let keys = random_keys(20);
+    let mut tree: SMT = build_tree(&keys);
+
+    // lose the leaf of a member, its neighbour at height 0 can no longer be
+    // inserted since the tree reads the lost leaf to hash their branch
+    let lost = keys[7];
+    tree.store_mut().remove_leaf(&lost).unwrap();
+    let root = *tree.root();
+    let store = tree.store().clone();
+
+    let mut neighbour = lost;
+    if neighbour.get_bit(0) {
+        neighbour.clear_bit(0);
+    } else {
+        neighbour.set_bit(0);
+    }
+    assert_eq!(
+        tree.update(neighbour, neighbour, true),
+        Err(Error::MissingLeaf(lost))
+    );
+    assert_eq!(*tree.root(), root);
+    assert_eq!(tree.store().branches_map(), store.branches_map());
+    assert_eq!(tree.store().leaves_map(), store.leaves_map());
+}
+
+#[test]
+fn test_update_with_proof() {
+    let keys = random_keys(100);
+    let mut tree: SMT = build_tree(&keys[..99]);
+    let mut expected: SMT = build_tree(&keys[..99]);
+
+    let root = *tree.root();
+    let update = tree.insert_with_proof(keys[99], keys[99]).unwrap();
+    expected.update(keys[99], keys[99], true).unwrap();
+    assert_eq!(update.old_root, root);
+    assert_eq!(update.leaf, keys[99]);
+    assert_eq!(update.new_root, *expected.root());
+    assert_eq!(*tree.root(), update.new_root);
+    assert_eq!(tree.len(), Ok(100));
+    // the insertion is proven in the tree holding the leaf
+    assert_eq!(
+        vec![update.proof],
+        expected.modify_root_proof(vec![keys[99]]).unwrap()
+    );
+
+    let root = *tree.root();
+    let removed = tree.modify_root_proof(vec![keys[0]]).unwrap();
+    let update = tree.remove_with_proof(keys[0]).unwrap();
+    expected.update(keys[0], H256::zero(), false).unwrap();
+    assert_eq!(update.old_root, root);
+    assert_eq!(update.leaf, keys[0]);
+    assert_eq!(update.new_root, *expected.root());
+    assert_eq!(*tree.root(), update.new_root);
+    assert_eq!(tree.len(), Ok(99));
+    // the removal is proven in the tree still holding the leaf
+    assert_eq!(vec![update.proof], removed);
+    assert_eq!(tree.get(&keys[0]), Ok(H256::zero()));
+}
+
+#[test]
+fn test_failed_update_with_proof() {
+    let keys = random_keys(50);
+    let mut tree: SMT = build_tree(&keys[..49]);
+    let root = *tree.root();
+
+    assert_eq!(
+        tree.insert_with_proof(keys[0], keys[0]),
+        Err(Error::LeafExists(keys[0]))
+    );
+    assert_eq!(
+        tree.remove_with_proof(keys[49]),
+        Err(Error::LeafNotFound(keys[49]))
+    );
+    assert_eq!(
+        tree.insert_with_proof(MERKLE_UPPER_BOUND, H256::zero()),
+        Err(Error::ReservedKey(MERKLE_UPPER_BOUND))
+    );
+    assert_eq!(*tree.root(), root);
+    assert_eq!(tree.len(), Ok(49));
+}
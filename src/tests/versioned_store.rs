@@ -0,0 +1,92 @@
+use super::random_keys;
+use crate::{
+    blake2b::Blake2bHasher, error::Error, h256::H256, tree::SparseMerkleTree,
+    versioned_store::VersionedStore,
+};
+
+type VersionedSMT = SparseMerkleTree<Blake2bHasher, H256, VersionedStore<H256>>;
+
+#[test]
+fn test_snapshots_at_old_roots() {
+    let keys = random_keys(50);
+    let mut tree = VersionedSMT::default();
+    let mut history = vec![(*tree.root(), vec![])];
+
+    for (i, key) in keys.iter().enumerate() {
+        tree.update(*key, *key, true).unwrap();
+        history.push((*tree.root(), keys[..=i].to_vec()));
+    }
+    for (i, key) in keys[..20].iter().enumerate() {
+        tree.update(*key, *key, false).unwrap();
+        history.push((*tree.root(), keys[i + 1..].to_vec()));
+    }
+    assert_eq!(tree.store().roots().len(), history.len());
+
+    for (root, mut members) in history {
+        let snapshot = tree.snapshot(&root).unwrap();
+        members.sort_unstable();
+
+        let leaves = snapshot.iter().map(|x| x.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(leaves, members);
+        assert_eq!(snapshot.len(), Ok(members.len()));
+
+        // the snapshot rebuilds the root it was opened at
+        let rebuilt = SparseMerkleTree::<Blake2bHasher, H256, _>::new_with_store(
+            tree.store().snapshot(&root).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(*rebuilt.root(), root);
+
+        if let Some(member) = members.first() {
+            assert!(snapshot.member_proof(vec![*member]).is_ok());
+        }
+    }
+}
+
+#[test]
+fn test_snapshot_of_unknown_root() {
+    let mut tree = VersionedSMT::default();
+    let key = random_keys(1)[0];
+    tree.update(key, key, true).unwrap();
+
+    assert_eq!(tree.snapshot(&key).err(), Some(Error::MissingRoot(key)));
+}
+
+#[test]
+fn test_snapshot_held_across_updates() {
+    let keys = random_keys(40);
+    let mut tree = VersionedSMT::default();
+    for key in &keys[..20] {
+        tree.update(*key, *key, true).unwrap();
+    }
+    let root = *tree.root();
+    let snapshot = tree.snapshot(&root).unwrap();
+    let proof = snapshot.member_proof(vec![keys[0]]).unwrap();
+    let mut members = keys[..20].to_vec();
+    members.sort_unstable();
+
+    // the snapshot owns its handle on the versions, the tree moves on
+    for key in &keys[20..] {
+        tree.update(*key, *key, true).unwrap();
+    }
+    for key in &keys[..10] {
+        tree.update(*key, *key, false).unwrap();
+    }
+    let leaves = snapshot.iter().map(|x| x.unwrap().0).collect::<Vec<_>>();
+    assert_eq!(leaves, members);
+    assert_eq!(snapshot.len(), Ok(20));
+    assert_eq!(snapshot.get(&keys[0]), Ok(keys[0]));
+
+    // pruning its root away keeps the version the snapshot reads
+    let report = tree.store_mut().prune(&[]).unwrap();
+    assert!(report.roots_removed > 0);
+    assert_eq!(snapshot.iter().count(), 20);
+    assert_eq!(snapshot.member_proof(vec![keys[0]]), Ok(proof));
+    assert_eq!(tree.store().roots(), vec![root]);
+
+    // once it is dropped the version goes with the next prune
+    drop(snapshot);
+    tree.store_mut().prune(&[]).unwrap();
+    assert!(tree.store().roots().is_empty());
+    assert_eq!(tree.len(), Ok(30));
+}
@@ -1,9 +1,11 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use crate::{
     error::Error,
     h256::{H256, LEAF_BYTE},
-    tree::{BranchKey, BranchNode},
+    instrumented_store::StoreMetrics,
+    prune::{PruneReport, Reachable},
+    tree::{BranchKey, BranchNode, TreeCounts},
 };
 
 /// Trait for customize hash function
@@ -39,8 +41,27 @@ impl Value for H256 {
 pub trait StoreReadOps<V> {
     fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, Error>;
     fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>, Error>;
-    fn branches_map(&self) -> &HashMap<BranchKey, BranchNode>;
-    fn leaves_map(&self) -> &HashMap<H256, V>;
+    /// Counts committed along with root, stores not keeping them return None
+    fn get_counts(&self, _root: &H256) -> Result<Option<TreeCounts>, Error> {
+        Ok(None)
+    }
+    /// Roots besides the current one whose trees the store still holds,
+    /// stores keeping a single tree hold none
+    fn retained_roots(&self) -> Result<Vec<H256>, Error> {
+        Ok(Vec::new())
+    }
+    /// Branch at branch_key in the tree at one of the retained roots
+    fn get_branch_at(
+        &self,
+        root: &H256,
+        _branch_key: &BranchKey,
+    ) -> Result<Option<BranchNode>, Error> {
+        Err(Error::MissingRoot(*root))
+    }
+    /// Calls counted so far, stores not counting them return None
+    fn store_metrics(&self) -> Option<StoreMetrics> {
+        None
+    }
 }
 
 pub trait StoreWriteOps<V> {
@@ -48,4 +69,110 @@ pub trait StoreWriteOps<V> {
     fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<(), Error>;
     fn remove_branch(&mut self, node_key: &BranchKey) -> Result<(), Error>;
     fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error>;
+    /// Apply every write of batch
+    /// backends able to write atomically should override it, so that a
+    /// failing batch leaves nothing behind
+    fn write_batch(&mut self, batch: WriteBatch<V>) -> Result<(), Error> {
+        for (branch_key, branch) in batch.branches {
+            match branch {
+                Some(branch) => self.insert_branch(branch_key, branch)?,
+                None => self.remove_branch(&branch_key)?,
+            }
+        }
+        for (leaf_key, leaf) in batch.leaves {
+            match leaf {
+                Some(leaf) => self.insert_leaf(leaf_key, leaf)?,
+                None => self.remove_leaf(&leaf_key)?,
+            }
+        }
+        Ok(())
+    }
+    /// Called by the tree before `commit_root`, with the counts of the tree
+    /// at root
+    fn commit_counts(&mut self, _root: &H256, _counts: &TreeCounts) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Called by the tree once an update is fully written, with the new root
+    fn commit_root(&mut self, _root: &H256) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Called by `prune`, stop holding the retained roots not in keep_roots
+    /// along with the nodes that no tree of reachable needs
+    fn release_roots(
+        &mut self,
+        _keep_roots: &[H256],
+        _reachable: &Reachable,
+    ) -> Result<PruneReport, Error> {
+        Ok(PruneReport::default())
+    }
+}
+
+/// Traits for backend storage able to list the nodes it holds
+pub trait StoreKeysOps {
+    fn branch_keys(&self) -> Result<Vec<BranchKey>, Error>;
+    fn leaf_keys(&self) -> Result<Vec<H256>, Error>;
+}
+
+/// Writes staged by an update, None marks a removal
+///
+/// The tree stages every write of an update in a batch and hands it over to
+/// `StoreWriteOps::write_batch` only once the update succeeded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteBatch<V> {
+    pub(crate) branches: BTreeMap<BranchKey, Option<BranchNode>>,
+    pub(crate) leaves: BTreeMap<H256, Option<V>>,
+}
+
+impl<V> Default for WriteBatch<V> {
+    fn default() -> Self {
+        WriteBatch {
+            branches: BTreeMap::new(),
+            leaves: BTreeMap::new(),
+        }
+    }
+}
+
+impl<V> WriteBatch<V> {
+    /// Check whether the batch holds no write
+    pub fn is_empty(&self) -> bool {
+        self.branches.is_empty() && self.leaves.is_empty()
+    }
+
+    /// Number of staged writes
+    pub fn len(&self) -> usize {
+        self.branches.len() + self.leaves.len()
+    }
+
+    /// Staged branches in key order
+    pub fn branches(&self) -> impl Iterator<Item = (&BranchKey, Option<&BranchNode>)> {
+        self.branches
+            .iter()
+            .map(|(branch_key, branch)| (branch_key, branch.as_ref()))
+    }
+
+    /// Staged leaves in key order
+    pub fn leaves(&self) -> impl Iterator<Item = (&H256, Option<&V>)> {
+        self.leaves
+            .iter()
+            .map(|(leaf_key, leaf)| (leaf_key, leaf.as_ref()))
+    }
+}
+
+impl<V> StoreWriteOps<V> for WriteBatch<V> {
+    fn insert_branch(&mut self, branch_key: BranchKey, branch: BranchNode) -> Result<(), Error> {
+        self.branches.insert(branch_key, Some(branch));
+        Ok(())
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<(), Error> {
+        self.leaves.insert(leaf_key, Some(leaf));
+        Ok(())
+    }
+    fn remove_branch(&mut self, branch_key: &BranchKey) -> Result<(), Error> {
+        self.branches.insert(branch_key.clone(), None);
+        Ok(())
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error> {
+        self.leaves.insert(*leaf_key, None);
+        Ok(())
+    }
 }
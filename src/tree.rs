@@ -1,15 +1,32 @@
-use bitvec::{order::Msb0, slice::BitSlice};
-
+#[cfg(feature = "tracing")]
+use crate::instrumented_store::{store_span, StoreSpan};
 use crate::{
     error::{Error, Result},
     h256::H256,
+    iter::Iter,
     merge::{merge, MergeValue},
     merkle_proof::Side,
+    overlay_store::{OverlayStore, StagedBatch},
     traits::{Hasher, StoreReadOps, StoreWriteOps, Value},
 };
 use core::cmp::Ordering;
 use core::marker::PhantomData;
+use core::ops::RangeBounds;
 use std::fmt::Debug;
+
+/// Leaf inserted by `SparseMerkleTree::default` below every member,
+/// matches `merkle_lower_bound` of the Aiken library
+pub const MERKLE_LOWER_BOUND: H256 = H256::zero();
+
+/// Leaf inserted by `SparseMerkleTree::default` above every member,
+/// matches `merkle_upper_bound` of the Aiken library
+pub const MERKLE_UPPER_BOUND: H256 = H256::max();
+
+/// Return true if key is one of the reserved bound leaves
+pub fn is_bound_key(key: &H256) -> bool {
+    key == &MERKLE_LOWER_BOUND || key == &MERKLE_UPPER_BOUND
+}
+
 /// The branch key
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct BranchKey {
@@ -18,9 +35,26 @@ pub struct BranchKey {
 }
 
 impl BranchKey {
-    pub fn new(height: u8, node_key: H256) -> BranchKey {
+    pub const fn new(height: u8, node_key: H256) -> BranchKey {
         BranchKey { height, node_key }
     }
+
+    /// Key of the root branch, which always spans the whole key space
+    pub const fn root() -> BranchKey {
+        BranchKey::new(u8::MAX, H256::zero())
+    }
+
+    /// Return the lowest and highest leaf keys this branch can contain
+    pub fn key_range(&self) -> (H256, H256) {
+        let shift = u32::from(self.height) + 1;
+        let lowest = self.node_key.shift_left(shift);
+        let mask = H256::max().shift_right(256 - shift);
+        let mut highest = lowest;
+        for (byte, mask) in highest.as_mut_slice().iter_mut().zip(mask.as_slice()) {
+            *byte |= mask;
+        }
+        (lowest, highest)
+    }
 }
 
 impl PartialOrd for BranchKey {
@@ -50,86 +84,151 @@ pub enum Match {
 }
 
 impl ChildKey {
-    fn get_intersecting_height(
+    /// Highest key that can be found below this child
+    pub(crate) fn highest_key(&self) -> H256 {
+        match self {
+            ChildKey::Leaf(key) => *key,
+            ChildKey::Branch(branch_key) => branch_key.key_range().1,
+        }
+    }
+
+    /// Return the lowest height below max_height at which other_key joins the
+    /// path of this child, or Match::Exact if other_key is this leaf
+    pub(crate) fn get_intersecting_height(
         &self,
-        mut other_key: H256,
+        other_key: H256,
         max_height: u8,
     ) -> core::result::Result<u8, Match> {
-        match self {
-            ChildKey::Leaf(key) => {
-                if key == &other_key {
-                    return Err(Match::Exact);
-                }
-                let mut key = *key;
-
-                let key = key.as_mut_slice();
-
-                let other_key = other_key.as_mut_slice();
-
-                let key: &mut BitSlice<_, Msb0> = BitSlice::from_slice_mut(key);
-                let other_key: &mut BitSlice<_, Msb0> = BitSlice::from_slice_mut(other_key);
-
-                for i in 0..max_height {
-                    key.shift_right(1);
-                    other_key.shift_right(1);
-
-                    if key == other_key {
-                        return Ok(i);
-                    }
-                }
-                Err(Match::NoMatch)
-            }
+        let height = match self {
+            ChildKey::Leaf(key) => match key.highest_differing_bit(&other_key) {
+                Some(bit) => u16::from(bit),
+                None => return Err(Match::Exact),
+            },
             ChildKey::Branch(key) => {
-                let branch_height = key.height;
-
-                let mut key = key.node_key;
+                let other_key = other_key.parent_path_by_height(key.height);
+                let fork = key
+                    .node_key
+                    .highest_differing_bit(&other_key)
+                    .map_or(0, |bit| u16::from(bit) + 1);
+                u16::from(key.height) + fork
+            }
+        };
 
-                let key = key.as_mut_slice();
+        if height < u16::from(max_height) {
+            Ok(height as u8)
+        } else {
+            Err(Match::NoMatch)
+        }
+    }
+}
 
-                let other_key = other_key.as_mut_slice();
+/// A branch in the SMT, with the counts of the members below each child
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct BranchNode {
+    pub left: (MergeValue, ChildKey),
+    pub right: (MergeValue, ChildKey),
+    pub left_counts: SubtreeCounts,
+    pub right_counts: SubtreeCounts,
+}
 
-                let key: &mut BitSlice<_, Msb0> = BitSlice::from_slice_mut(key);
-                let other_key: &mut BitSlice<_, Msb0> = BitSlice::from_slice_mut(other_key);
+impl BranchNode {
+    pub(crate) fn new((left, left_counts): Child, (right, right_counts): Child) -> Self {
+        BranchNode {
+            left,
+            right,
+            left_counts,
+            right_counts,
+        }
+    }
 
-                for i in 0..max_height {
-                    match branch_height.cmp(&i) {
-                        Ordering::Less => {
-                            key.shift_right(1);
+    /// Counts of the members below the branch
+    pub fn counts(&self) -> SubtreeCounts {
+        SubtreeCounts::branch(&self.left_counts, &self.right_counts)
+    }
+}
 
-                            other_key.shift_right(1);
+/// Child of a branch along with the counts of the members below it
+pub(crate) type Child = ((MergeValue, ChildKey), SubtreeCounts);
+
+/// Members below a node, with the sum and the longest of their path lengths
+/// from the node, in branches
+///
+/// Every branch keeps the counts of its children, so an update recounts the
+/// branches on its path only.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubtreeCounts {
+    /// Number of members
+    pub members: usize,
+    /// Sum of the path lengths of the members
+    pub total_path_length: usize,
+    /// Longest path length of a member, 0 without members
+    pub max_path_length: usize,
+}
 
-                            if key == other_key {
-                                return Ok(i);
-                            }
-                        }
-                        Ordering::Equal => {
-                            other_key.shift_right(1);
+impl SubtreeCounts {
+    /// Counts of the leaf at key, the bound leaves are not members
+    pub(crate) fn leaf(key: &H256) -> Self {
+        SubtreeCounts {
+            members: usize::from(!is_bound_key(key)),
+            ..SubtreeCounts::default()
+        }
+    }
 
-                            if key == other_key {
-                                return Ok(i);
-                            }
-                        }
-                        Ordering::Greater => {
-                            other_key.shift_right(1);
-                        }
-                    }
-                }
-                Err(Match::NoMatch)
-            }
+    /// Counts of a branch over children counted by left and right, whose
+    /// members are one branch further from it
+    pub(crate) fn branch(left: &Self, right: &Self) -> Self {
+        let max_path_length = [left, right]
+            .iter()
+            .filter(|counts| counts.members > 0)
+            .map(|counts| counts.max_path_length + 1)
+            .max()
+            .unwrap_or_default();
+        SubtreeCounts {
+            members: left.members + right.members,
+            total_path_length: left.total_path_length
+                + left.members
+                + right.total_path_length
+                + right.members,
+            max_path_length,
         }
     }
 }
 
-/// A branch in the SMT
-#[derive(Debug, Eq, PartialEq, Clone)]
-pub struct BranchNode {
-    pub left: (MergeValue, ChildKey),
-    pub right: (MergeValue, ChildKey),
+/// Count the members and branches below child, child being at path_length
+fn walk_subtree<V, S: StoreReadOps<V>>(
+    store: &S,
+    child: ChildKey,
+    path_length: usize,
+    counts: &mut TreeCounts,
+) -> Result<()> {
+    let mut stack = vec![(child, path_length)];
+    while let Some((child, path_length)) = stack.pop() {
+        match child {
+            ChildKey::Leaf(key) if is_bound_key(&key) => {}
+            ChildKey::Leaf(_) => counts.add_member(path_length),
+            ChildKey::Branch(branch_key) => {
+                let branch = store
+                    .get_branch(&branch_key)?
+                    .ok_or(Error::MissingBranch(branch_key.height, branch_key.node_key))?;
+                counts.branches += 1;
+                stack.push((branch.left.1, path_length + 1));
+                stack.push((branch.right.1, path_length + 1));
+            }
+        }
+    }
+    Ok(())
 }
 
-impl BranchNode {
-    fn new(left: (MergeValue, ChildKey), right: (MergeValue, ChildKey)) -> Self {
-        BranchNode { left, right }
+/// Fail with the root of the tree store holds, if it holds one
+pub(crate) fn check_empty_store<H: Hasher + Default, V, S: StoreReadOps<V>>(
+    store: &S,
+) -> Result<()> {
+    match store.get_branch(&BranchKey::root())? {
+        Some(branch) => {
+            let root = merge::<H>(&branch.left.0, &branch.right.0).hash();
+            Err(Error::NonEmptyStore(root))
+        }
+        None => Ok(()),
     }
 }
 
@@ -138,41 +237,166 @@ impl BranchNode {
 pub struct SparseMerkleTree<H, V, S> {
     store: S,
     root: H256,
+    // member and branch counts, None until they have been computed
+    counts: Option<TreeCounts>,
     phantom: PhantomData<(H, V)>,
 }
 
+/// Member and branch counts of a tree, read from its root branch after every
+/// update and committed to the store along with the root
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeCounts {
+    /// Number of members
+    pub leaves: usize,
+    /// Number of branches, including the root
+    pub branches: usize,
+    /// Sum of the path lengths of the members, in branches
+    pub total_path_length: usize,
+    /// Longest path length of a member, in branches
+    pub max_path_length: usize,
+}
+
+impl TreeCounts {
+    pub(crate) fn add_member(&mut self, path_length: usize) {
+        self.leaves += 1;
+        self.total_path_length += path_length;
+        self.max_path_length = self.max_path_length.max(path_length);
+    }
+
+    /// Size and shape of the tree
+    pub fn stats(&self) -> TreeStats {
+        let max_path_length = self.max_path_length;
+        let average_path_length = if self.leaves == 0 {
+            0.0
+        } else {
+            self.total_path_length as f64 / self.leaves as f64
+        };
+        TreeStats {
+            leaves: self.leaves,
+            branches: self.branches,
+            max_path_length,
+            average_path_length,
+            max_proof_size: max_path_length * PROOF_STEP_SIZE,
+            average_proof_size: average_path_length * PROOF_STEP_SIZE as f64,
+        }
+    }
+}
+
+impl From<SubtreeCounts> for TreeCounts {
+    /// Counts of the tree whose root branch holds the members of counts, a
+    /// binary tree over the members and the two bound leaves
+    fn from(counts: SubtreeCounts) -> Self {
+        TreeCounts {
+            leaves: counts.members,
+            branches: counts.members + 1,
+            total_path_length: counts.total_path_length,
+            max_path_length: counts.max_path_length,
+        }
+    }
+}
+
+/// Size and shape of a tree, the bound leaves are not counted as members
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TreeStats {
+    /// Number of members
+    pub leaves: usize,
+    /// Number of branches, including the root
+    pub branches: usize,
+    /// Longest path from the root to a member, in branches
+    pub max_path_length: usize,
+    /// Average path from the root to a member, in branches
+    pub average_path_length: f64,
+    /// Size in bytes of the largest member proof, one hash and side per branch
+    pub max_proof_size: usize,
+    /// Average size in bytes of a member proof
+    pub average_proof_size: f64,
+}
+
+/// Proof of a member produced by `SparseMerkleTree::modify_root_proof`
+pub type ModifyProof = (
+    Vec<Side>,
+    Vec<MergeValue>,
+    Vec<MergeValue>,
+    Vec<MergeValue>,
+    bool,
+    H256,
+);
+
+/// Single update produced by `SparseMerkleTree::insert_with_proof` or
+/// `remove_with_proof`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateProof<V> {
+    /// Root before the update
+    pub old_root: H256,
+    /// Root after the update
+    pub new_root: H256,
+    /// Leaf inserted or removed
+    pub leaf: V,
+    /// Proof of the member, made in the tree holding it
+    pub proof: ModifyProof,
+}
+
+/// Encoded size of a proof step: the sibling hash and a side byte
+pub const PROOF_STEP_SIZE: usize = 33;
+
+/// Root of a tree holding only the bound leaves
+pub fn empty_root<H: Hasher + Default, V: Value>() -> H256 {
+    merge::<H>(
+        &MergeValue::from_h256(V::zero().to_h256::<H>()),
+        &MergeValue::from_h256(V::max().to_h256::<H>()),
+    )
+    .hash()
+}
+
 impl<H: Hasher + Default, V: Value + Debug, S: StoreReadOps<V> + StoreWriteOps<V> + Default> Default
     for SparseMerkleTree<H, V, S>
 {
     fn default() -> Self {
-        let mut store = S::default();
-        store.insert_leaf(H256::zero(), V::zero()).unwrap();
-        store.insert_leaf(H256::max(), V::max()).unwrap();
-        store
-            .insert_branch(
-                BranchKey::new(u8::MAX, H256::zero()),
-                BranchNode::new(
-                    (
-                        MergeValue::from_h256(V::zero().to_h256::<H>()),
-                        ChildKey::Leaf(H256::zero()),
-                    ),
-                    (
-                        MergeValue::from_h256(V::max().to_h256::<H>()),
-                        ChildKey::Leaf(H256::max()),
-                    ),
+        SparseMerkleTree::new_empty(S::default()).unwrap()
+    }
+}
+
+impl<H: Hasher + Default, V: Value + Debug, S: StoreReadOps<V> + StoreWriteOps<V>>
+    SparseMerkleTree<H, V, S>
+{
+    /// Build an empty tree in store, writing the bound leaves and the root
+    /// branch holding them
+    /// a store already holding a tree is rejected, `new_with_store` opens it
+    pub fn new_empty(mut store: S) -> Result<SparseMerkleTree<H, V, S>> {
+        check_empty_store::<H, V, _>(&store)?;
+        store.insert_leaf(MERKLE_LOWER_BOUND, V::zero())?;
+        store.insert_leaf(MERKLE_UPPER_BOUND, V::max())?;
+        let root_branch = BranchNode::new(
+            (
+                (
+                    MergeValue::from_h256(V::zero().to_h256::<H>()),
+                    ChildKey::Leaf(MERKLE_LOWER_BOUND),
                 ),
-            )
-            .unwrap();
-        let root_branch_key = BranchKey::new(u8::MAX, H256::zero());
+                SubtreeCounts::default(),
+            ),
+            (
+                (
+                    MergeValue::from_h256(V::max().to_h256::<H>()),
+                    ChildKey::Leaf(MERKLE_UPPER_BOUND),
+                ),
+                SubtreeCounts::default(),
+            ),
+        );
+        // the root branch over the two bound leaves
+        let counts = TreeCounts::from(root_branch.counts());
+        store.insert_branch(BranchKey::root(), root_branch)?;
         store
-            .get_branch(&root_branch_key)
+            .get_branch(&BranchKey::root())
             .map(|branch_node| {
                 branch_node
                     .map(|n| merge::<H>(&n.left.0, &n.right.0).hash())
                     .unwrap_or_default()
             })
-            .map(|root| SparseMerkleTree::new(root, store))
-            .unwrap()
+            .and_then(|root| {
+                store.commit_counts(&root, &counts)?;
+                store.commit_root(&root)?;
+                Ok(SparseMerkleTree::with_counts(root, store, Some(counts)))
+            })
     }
 }
 
@@ -182,6 +406,7 @@ impl<H, V, S> SparseMerkleTree<H, V, S> {
         SparseMerkleTree {
             root,
             store,
+            counts: None,
             phantom: PhantomData,
         }
     }
@@ -191,11 +416,6 @@ impl<H, V, S> SparseMerkleTree<H, V, S> {
         &self.root
     }
 
-    /// Check empty of the tree
-    pub fn is_empty(&self) -> bool {
-        self.root.is_zero()
-    }
-
     /// Destroy current tree and retake store
     pub fn take_store(self) -> S {
         self.store
@@ -210,79 +430,114 @@ impl<H, V, S> SparseMerkleTree<H, V, S> {
     pub fn store_mut(&mut self) -> &mut S {
         &mut self.store
     }
+
+    /// Member and branch counts, None until they have been computed
+    pub fn counts(&self) -> Option<&TreeCounts> {
+        self.counts.as_ref()
+    }
+
+    /// Build a tree over store, with the counts of the tree at root if known
+    pub(crate) fn with_counts(root: H256, store: S, counts: Option<TreeCounts>) -> Self {
+        SparseMerkleTree {
+            counts,
+            ..SparseMerkleTree::new(root, store)
+        }
+    }
+
+    /// Move the tree to the root of an update written to the store
+    pub(crate) fn set_root(&mut self, root: H256, counts: Option<TreeCounts>) {
+        self.root = root;
+        self.counts = counts;
+    }
 }
 
 impl<H: Hasher + Default, V, S: StoreReadOps<V>> SparseMerkleTree<H, V, S> {
     /// Build a merkle tree from store, the root will be calculated automatically
     pub fn new_with_store(store: S) -> Result<SparseMerkleTree<H, V, S>> {
-        let root_branch_key = BranchKey::new(u8::MAX, H256::zero());
         store
-            .get_branch(&root_branch_key)
+            .get_branch(&BranchKey::root())
             .map(|branch_node| {
                 branch_node
                     .map(|n| merge::<H>(&n.left.0, &n.right.0).hash())
                     .unwrap_or_default()
             })
-            .map(|root| SparseMerkleTree::new(root, store))
+            .and_then(|root| {
+                let counts = store.get_counts(&root)?;
+                Ok(SparseMerkleTree::with_counts(root, store, counts))
+            })
     }
 }
 
-impl<H: Hasher + Default, V: Value + Debug, S: StoreReadOps<V> + StoreWriteOps<V>>
+impl<H: Hasher + Default, V: Value + Clone + Debug, S: StoreReadOps<V> + StoreWriteOps<V>>
     SparseMerkleTree<H, V, S>
 {
-    fn recurse_tree(
-        &mut self,
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(height = current_height))
+    )]
+    fn recurse_tree<T: StoreReadOps<V> + StoreWriteOps<V>>(
+        store: &mut T,
         current_node: MergeValue,
         current_key: H256,
         intersection_branch: ChildKey,
         current_height: u8,
         insertion: bool,
-    ) -> Result<(MergeValue, ChildKey)> {
+    ) -> Result<Child> {
+        let current_child = (
+            (current_node.clone(), ChildKey::Leaf(current_key)),
+            SubtreeCounts::leaf(&current_key),
+        );
         match intersection_branch {
             ChildKey::Leaf(x) if insertion => {
                 let parent_key = x.parent_path_by_height(current_height);
                 let parent_branch_key = BranchKey::new(current_height, parent_key);
 
-                let x_value =
-                    MergeValue::from_h256(self.store.get_leaf(&x)?.unwrap().to_h256::<H>());
-
-                if x.le(&current_key) {
-                    let merge_value = merge::<H>(&x_value, &current_node);
-                    self.store.insert_branch(
-                        parent_branch_key.clone(),
-                        BranchNode {
-                            left: (x_value, intersection_branch.clone()),
-                            right: (current_node, ChildKey::Leaf(current_key)),
-                        },
-                    )?;
-
-                    Ok((merge_value, ChildKey::Branch(parent_branch_key)))
+                let x_value = MergeValue::from_h256(
+                    store
+                        .get_leaf(&x)?
+                        .ok_or(Error::MissingLeaf(x))?
+                        .to_h256::<H>(),
+                );
+                let x_child = (
+                    (x_value.clone(), intersection_branch.clone()),
+                    SubtreeCounts::leaf(&x),
+                );
+
+                let (merge_value, branch) = if x.le(&current_key) {
+                    (
+                        merge::<H>(&x_value, &current_node),
+                        BranchNode::new(x_child, current_child),
+                    )
                 } else {
-                    let merge_value = merge::<H>(&current_node, &x_value);
-                    self.store.insert_branch(
-                        parent_branch_key.clone(),
-                        BranchNode {
-                            left: (current_node, ChildKey::Leaf(current_key)),
-                            right: (x_value, intersection_branch.clone()),
-                        },
-                    )?;
-
-                    Ok((merge_value, ChildKey::Branch(parent_branch_key)))
-                }
+                    (
+                        merge::<H>(&current_node, &x_value),
+                        BranchNode::new(current_child, x_child),
+                    )
+                };
+                let counts = branch.counts();
+                store.insert_branch(parent_branch_key.clone(), branch)?;
+
+                Ok(((merge_value, ChildKey::Branch(parent_branch_key)), counts))
             }
 
             ChildKey::Leaf(x) => {
                 let parent_key = x.parent_path_by_height(current_height);
                 let parent_branch_key = BranchKey::new(current_height, parent_key);
 
-                let x_value =
-                    MergeValue::from_h256(self.store.get_leaf(&x)?.unwrap().to_h256::<H>());
+                let x_value = MergeValue::from_h256(
+                    store
+                        .get_leaf(&x)?
+                        .ok_or(Error::MissingLeaf(x))?
+                        .to_h256::<H>(),
+                );
 
-                self.store.remove_branch(&parent_branch_key)?;
-                Ok((x_value, ChildKey::Leaf(x)))
+                store.remove_branch(&parent_branch_key)?;
+                Ok(((x_value, ChildKey::Leaf(x)), SubtreeCounts::leaf(&x)))
             }
             ChildKey::Branch(key) => {
-                let parent_branch = self.store.get_branch(&key)?.unwrap();
+                let parent_branch = store
+                    .get_branch(&key)?
+                    .ok_or(Error::MissingBranch(key.height, key.node_key))?;
 
                 let left_inter_height = parent_branch
                     .left
@@ -294,103 +549,64 @@ impl<H: Hasher + Default, V: Value + Debug, S: StoreReadOps<V> + StoreWriteOps<V
                     .1
                     .get_intersecting_height(current_key, key.height);
 
+                let BranchNode {
+                    left,
+                    right,
+                    left_counts,
+                    right_counts,
+                } = parent_branch;
+
+                // recurse into a child at height, and rebuild the branch over
+                // the new child and the other one
+                let update_child = |store: &mut T, on_left: bool, height: u8| {
+                    let other = if on_left {
+                        (right.clone(), right_counts)
+                    } else {
+                        (left.clone(), left_counts)
+                    };
+                    let child = if on_left { &left.1 } else { &right.1 };
+                    let new_child = Self::recurse_tree(
+                        store,
+                        current_node.clone(),
+                        current_key,
+                        child.clone(),
+                        height,
+                        insertion,
+                    )?;
+
+                    let branch = if on_left {
+                        BranchNode::new(new_child, other)
+                    } else {
+                        BranchNode::new(other, new_child)
+                    };
+                    let merge_value = merge::<H>(&branch.left.0, &branch.right.0);
+                    let counts = branch.counts();
+                    store.insert_branch(key.clone(), branch)?;
+
+                    Ok(((merge_value, ChildKey::Branch(key.clone())), counts))
+                };
+
                 match (left_inter_height, right_inter_height) {
-                    (Err(Match::Exact), _) if insertion => {
-                        let new_child = self.recurse_tree(
-                            current_node,
-                            current_key,
-                            parent_branch.left.1.clone(),
-                            0,
-                            insertion,
-                        )?;
-
-                        let merge_value = merge::<H>(&new_child.0, &parent_branch.right.0);
-
-                        self.store.insert_branch(
-                            key.clone(),
-                            BranchNode {
-                                left: new_child,
-                                right: parent_branch.right,
-                            },
-                        )?;
-
-                        Ok((merge_value, ChildKey::Branch(key)))
-                    }
+                    (Err(Match::Exact), _) if insertion => update_child(store, true, 0),
 
-                    (_, Err(Match::Exact)) if insertion => {
-                        let new_child = self.recurse_tree(
-                            current_node,
-                            current_key,
-                            parent_branch.right.1.clone(),
-                            0,
-                            insertion,
-                        )?;
-
-                        let merge_value = merge::<H>(&parent_branch.left.0, &new_child.0);
-
-                        self.store.insert_branch(
-                            key.clone(),
-                            BranchNode {
-                                left: parent_branch.left,
-                                right: new_child,
-                            },
-                        )?;
-
-                        Ok((merge_value, ChildKey::Branch(key)))
-                    }
+                    (_, Err(Match::Exact)) if insertion => update_child(store, false, 0),
 
                     (Err(Match::Exact), _) => {
-                        self.store.remove_branch(&key)?;
-                        Ok(parent_branch.right)
+                        store.remove_branch(&key)?;
+                        Ok((right, right_counts))
                     }
 
                     (_, Err(Match::Exact)) => {
-                        self.store.remove_branch(&key)?;
-                        Ok(parent_branch.left)
+                        store.remove_branch(&key)?;
+                        Ok((left, left_counts))
                     }
 
                     (Ok(left_height), Err(Match::NoMatch)) => {
-                        let new_child = self.recurse_tree(
-                            current_node,
-                            current_key,
-                            parent_branch.left.1.clone(),
-                            left_height,
-                            insertion,
-                        )?;
-
-                        let merge_value = merge::<H>(&new_child.0, &parent_branch.right.0);
-
-                        self.store.insert_branch(
-                            key.clone(),
-                            BranchNode {
-                                left: new_child,
-                                right: parent_branch.right,
-                            },
-                        )?;
-
-                        Ok((merge_value, ChildKey::Branch(key)))
+                        update_child(store, true, left_height)
                     }
 
                     (Err(Match::NoMatch), Ok(right_height)) => {
-                        let new_child = self.recurse_tree(
-                            current_node,
-                            current_key,
-                            parent_branch.right.1.clone(),
-                            right_height,
-                            insertion,
-                        )?;
-
-                        let merge_value = merge::<H>(&parent_branch.left.0, &new_child.0);
-
-                        self.store.insert_branch(
-                            key.clone(),
-                            BranchNode {
-                                left: parent_branch.left,
-                                right: new_child,
-                            },
-                        )?;
-
-                        Ok((merge_value, ChildKey::Branch(key)))
+                        update_child(store, false, right_height)
                     }
 
                     (Err(Match::NoMatch), Err(Match::NoMatch)) => {
@@ -405,31 +621,27 @@ impl<H: Hasher + Default, V: Value + Debug, S: StoreReadOps<V> + StoreWriteOps<V
                             current_key.parent_path_by_height(current_height - 1)
                         };
 
-                        let parent_merged_value =
-                            merge::<H>(&parent_branch.left.0, &parent_branch.right.0);
-
-                        if sub_key.is_right() {
-                            let merge_value = merge::<H>(&parent_merged_value, &current_node);
-                            self.store.insert_branch(
-                                parent_branch_key.clone(),
-                                BranchNode {
-                                    left: (parent_merged_value, ChildKey::Branch(key.clone())),
-                                    right: (current_node, ChildKey::Leaf(current_key)),
-                                },
-                            )?;
-
-                            Ok((merge_value, ChildKey::Branch(parent_branch_key)))
+                        let parent_merged_value = merge::<H>(&left.0, &right.0);
+                        let parent_child = (
+                            (parent_merged_value.clone(), ChildKey::Branch(key.clone())),
+                            SubtreeCounts::branch(&left_counts, &right_counts),
+                        );
+
+                        let (merge_value, branch) = if sub_key.is_right() {
+                            (
+                                merge::<H>(&parent_merged_value, &current_node),
+                                BranchNode::new(parent_child, current_child),
+                            )
                         } else {
-                            let merge_value = merge::<H>(&current_node, &parent_merged_value);
-                            self.store.insert_branch(
-                                parent_branch_key.clone(),
-                                BranchNode {
-                                    left: (current_node, ChildKey::Leaf(current_key)),
-                                    right: (parent_merged_value, ChildKey::Branch(key.clone())),
-                                },
-                            )?;
-                            Ok((merge_value, ChildKey::Branch(parent_branch_key)))
-                        }
+                            (
+                                merge::<H>(&current_node, &parent_merged_value),
+                                BranchNode::new(current_child, parent_child),
+                            )
+                        };
+                        let counts = branch.counts();
+                        store.insert_branch(parent_branch_key.clone(), branch)?;
+
+                        Ok(((merge_value, ChildKey::Branch(parent_branch_key)), counts))
                     }
 
                     (Ok(a), Ok(b)) => unreachable!("{a:#?} {b:#?}"),
@@ -439,85 +651,199 @@ impl<H: Hasher + Default, V: Value + Debug, S: StoreReadOps<V> + StoreWriteOps<V
     }
 
     /// Update a leaf, return new merkle root
-    /// set to zero value to delete a key
+    /// insertion adds the key, otherwise the key is removed
+    /// the bound keys are reserved and can not be updated
     pub fn update(&mut self, key: H256, value: V, insertion: bool) -> Result<&H256> {
+        #[cfg(feature = "tracing")]
+        let span = StoreSpan::open(store_span!("update", key = %key, insertion), &self.store);
+        let updated = self.apply_update(key, value, insertion);
+        #[cfg(feature = "tracing")]
+        span.close(&self.store);
+        updated?;
+        Ok(&self.root)
+    }
+
+    fn apply_update(&mut self, key: H256, value: V, insertion: bool) -> Result<()> {
+        if is_bound_key(&key) {
+            return Err(Error::ReservedKey(key));
+        }
+
+        match (insertion, self.store.get_leaf(&key)?.is_some()) {
+            (true, true) => return Err(Error::LeafExists(key)),
+            (false, false) => return Err(Error::LeafNotFound(key)),
+            _ => {}
+        }
+
         // compute and store new leaf
 
         let node = MergeValue::from_h256(value.to_h256::<H>());
 
-        // notice when value is zero the leaf is deleted, so we do not need to store it
+        // stage every write so that a failing update leaves the store as it was
+        let mut staged = OverlayStore::new(&self.store, self.root);
         if insertion {
-            self.store.insert_leaf(key, value)?;
+            staged.insert_leaf(key, value)?;
         } else {
-            self.store.remove_leaf(&key)?;
+            staged.remove_leaf(&key)?;
         };
 
-        // recompute the tree from top to bottom
-        let x: Vec<_> = self
-            .store()
-            .branches_map()
-            .iter()
-            .filter(|x| x.0.height == 255)
-            .collect();
+        // recompute the tree from top to bottom, along with the counts of the
+        // branches on the path
+        let last_intersection_key = ChildKey::Branch(BranchKey::root());
+
+        let ((root_key, _), root_counts) = Self::recurse_tree(
+            &mut staged,
+            node,
+            key,
+            last_intersection_key,
+            u8::MAX,
+            insertion,
+        )?;
+        let counts = Some(TreeCounts::from(root_counts));
+
+        self.commit_staged(StagedBatch {
+            base_root: self.root,
+            root: root_key.hash(),
+            counts,
+            batch: staged.into_batch(),
+        })
+    }
 
-        assert!(x.len() == 1);
+    /// Insert a leaf and generate the proof of its insertion
+    /// return the roots before and after the insertion along with the proof,
+    /// which is made in the tree holding the leaf
+    pub fn insert_with_proof(&mut self, key: H256, value: V) -> Result<UpdateProof<V>> {
+        self.update_with_proof(key, value, true)
+    }
+
+    /// Remove a leaf and generate the proof of its removal
+    /// return the roots before and after the removal and the removed leaf
+    /// along with the proof, which is made in the tree holding the leaf
+    pub fn remove_with_proof(&mut self, key: H256) -> Result<UpdateProof<V>> {
+        self.update_with_proof(key, V::zero(), false)
+    }
 
-        let last_intersection_key = ChildKey::Branch(x[0].0.clone());
+    fn update_with_proof(
+        &mut self,
+        key: H256,
+        value: V,
+        insertion: bool,
+    ) -> Result<UpdateProof<V>> {
+        let old_root = self.root;
+        // nothing is written before both the update and the proof succeed
+        let (staged, leaf, mut proofs) = {
+            let mut overlay = self.overlay();
+            overlay.update(key, value.clone(), insertion)?;
+            let (leaf, proofs) = if insertion {
+                (value, overlay.modify_root_proof(vec![key])?)
+            } else {
+                let leaf = self.store.get_leaf(&key)?.ok_or(Error::MissingLeaf(key))?;
+                (leaf, self.modify_root_proof(vec![key])?)
+            };
+            (overlay.into_batch(), leaf, proofs)
+        };
+        let proof = proofs.pop().ok_or(Error::CorruptedProof)?;
+
+        let new_root = staged.root;
+        self.commit_staged(staged)?;
+        Ok(UpdateProof {
+            old_root,
+            new_root,
+            leaf,
+            proof,
+        })
+    }
+}
 
-        let (root_key, _) =
-            self.recurse_tree(node, key, last_intersection_key, u8::MAX, insertion)?;
+impl<H, V, S: StoreReadOps<V>> SparseMerkleTree<H, V, S> {
+    /// Iterate over all leaves in key order
+    pub fn iter(&self) -> Iter<'_, V, S> {
+        Iter::new(&self.store, &self.root, ..)
+    }
 
-        self.root = root_key.hash();
-        Ok(&self.root)
+    /// Iterate over the leaves whose keys fall within range, in key order
+    pub fn range<R: RangeBounds<H256>>(&self, range: R) -> Iter<'_, V, S> {
+        Iter::new(&self.store, &self.root, range)
     }
 }
 
 impl<H: Hasher + Default, V: Value, S: StoreReadOps<V>> SparseMerkleTree<H, V, S> {
+    /// Check whether the tree has no members besides the bound leaves
+    pub fn is_empty(&self) -> bool {
+        self.root.is_zero() || self.root == empty_root::<H, V>()
+    }
+
+    /// Number of members, the bound leaves are not counted
+    /// only walks the tree if the counts are not known, when the tree was
+    /// opened over a store not keeping them
+    pub fn len(&self) -> Result<usize> {
+        match &self.counts {
+            Some(counts) => Ok(counts.leaves),
+            None => self.walk_counts().map(|counts| counts.leaves),
+        }
+    }
+
+    /// Size and shape of the tree, read from the counts kept by the updates
+    /// only walks the tree if the counts are not known
+    pub fn stats(&self) -> Result<TreeStats> {
+        match &self.counts {
+            Some(counts) => Ok(counts.stats()),
+            None => self.walk_counts().map(|counts| counts.stats()),
+        }
+    }
+
+    /// Walk the whole tree and count its members and branches
+    pub fn walk_counts(&self) -> Result<TreeCounts> {
+        let mut counts = TreeCounts::default();
+        if !self.root.is_zero() {
+            walk_subtree(
+                &self.store,
+                ChildKey::Branch(BranchKey::root()),
+                0,
+                &mut counts,
+            )?;
+        }
+        Ok(counts)
+    }
+
     /// Get value of a leaf
-    /// return zero value if leaf not exists
+    /// return zero value if leaf not exists or is one of the bound keys
     pub fn get(&self, key: &H256) -> Result<V> {
-        if self.is_empty() {
+        if self.root.is_zero() || is_bound_key(key) {
             return Ok(V::zero());
         }
         Ok(self.store.get_leaf(key)?.unwrap_or_else(V::zero))
     }
 
     /// Generate merkle proof
-    #[allow(clippy::type_complexity)]
-    pub fn modify_root_proof(
-        &self,
-        mut keys: Vec<H256>,
-    ) -> Result<
-        Vec<(
-            Vec<Side>,
-            Vec<MergeValue>,
-            Vec<MergeValue>,
-            Vec<MergeValue>,
-            bool,
-            H256,
-        )>,
-    > {
+    pub fn modify_root_proof(&self, keys: Vec<H256>) -> Result<Vec<ModifyProof>> {
+        #[cfg(feature = "tracing")]
+        let span = StoreSpan::open(
+            store_span!("modify_root_proof", keys = keys.len()),
+            &self.store,
+        );
+        let proofs = self.build_modify_root_proof(keys);
+        #[cfg(feature = "tracing")]
+        span.close(&self.store);
+        proofs
+    }
+
+    fn build_modify_root_proof(&self, mut keys: Vec<H256>) -> Result<Vec<ModifyProof>> {
         if keys.is_empty() {
             return Err(Error::EmptyKeys);
         }
 
+        if let Some(key) = keys.iter().find(|key| is_bound_key(key)) {
+            return Err(Error::ReservedKey(*key));
+        }
+
         // sort keys
         keys.sort_unstable();
 
         let mut final_vec = vec![];
 
         for key in keys {
-            // recompute the tree from top to bottom
-            let x: Vec<_> = self
-                .store()
-                .branches_map()
-                .iter()
-                .filter(|x| x.0.height == 255)
-                .collect();
-
-            assert!(x.len() == 1);
-
-            let mut branch_key = x[0].0.clone();
+            // walk the tree from top to bottom
+            let mut branch_key = BranchKey::root();
             let mut proof = Vec::new();
 
             loop {
@@ -686,28 +1012,33 @@ impl<H: Hasher + Default, V: Value, S: StoreReadOps<V>> SparseMerkleTree<H, V, S
 
     /// Generate merkle proof
     #[allow(clippy::type_complexity)]
-    pub fn member_proof(&self, mut keys: Vec<H256>) -> Result<Vec<(Vec<Side>, H256)>> {
+    pub fn member_proof(&self, keys: Vec<H256>) -> Result<Vec<(Vec<Side>, H256)>> {
+        #[cfg(feature = "tracing")]
+        let span = StoreSpan::open(store_span!("member_proof", keys = keys.len()), &self.store);
+        let proofs = self.build_member_proof(keys);
+        #[cfg(feature = "tracing")]
+        span.close(&self.store);
+        proofs
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn build_member_proof(&self, mut keys: Vec<H256>) -> Result<Vec<(Vec<Side>, H256)>> {
         if keys.is_empty() {
             return Err(Error::EmptyKeys);
         }
 
+        if let Some(key) = keys.iter().find(|key| is_bound_key(key)) {
+            return Err(Error::ReservedKey(*key));
+        }
+
         // sort keys
         keys.sort_unstable();
 
         let mut final_vec = vec![];
 
         for key in keys {
-            // recompute the tree from top to bottom
-            let x: Vec<_> = self
-                .store()
-                .branches_map()
-                .iter()
-                .filter(|x| x.0.height == 255)
-                .collect();
-
-            assert!(x.len() == 1);
-
-            let mut branch_key = x[0].0.clone();
+            // walk the tree from top to bottom
+            let mut branch_key = BranchKey::root();
             let mut proof = Vec::new();
 
             loop {
@@ -0,0 +1,350 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{
+    diff::{diff, Diff},
+    error::{Error, Result},
+    h256::H256,
+    prune::{PruneReport, Reachable},
+    traits::{Hasher, StoreKeysOps, StoreReadOps, StoreWriteOps},
+    tree::{BranchKey, BranchNode, SparseMerkleTree, TreeCounts},
+};
+
+/// Every write made at a version, None marks a removal
+type History<T> = Vec<(u64, Option<T>)>;
+
+/// In memory store that keeps every version of the tree
+///
+/// Writes never overwrite a node in place: each update of the tree appends new
+/// entries tagged with the version being written, and `commit_root` closes the
+/// version. Reads see the latest entries, while `snapshot` reads the store as
+/// it was when a retained root was committed.
+///
+/// The versions are shared with the snapshots taken, which own a handle on
+/// them and so can be held while the tree goes on being updated.
+#[derive(Debug)]
+pub struct VersionedStore<V> {
+    versions: Arc<RwLock<Versions<V>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Versions<V> {
+    branches: HashMap<BranchKey, History<BranchNode>>,
+    leaves: HashMap<H256, History<V>>,
+    // root committed by each version
+    roots: BTreeMap<u64, H256>,
+    // latest version that committed each root
+    versions: HashMap<H256, u64>,
+    // counts of the tree at the root of each version
+    counts: BTreeMap<u64, TreeCounts>,
+    // version being written
+    version: u64,
+    // number of live snapshots reading each version
+    pins: BTreeMap<u64, usize>,
+}
+
+impl<V> Default for VersionedStore<V> {
+    fn default() -> Self {
+        VersionedStore {
+            versions: Arc::new(RwLock::new(Versions {
+                branches: HashMap::new(),
+                leaves: HashMap::new(),
+                roots: BTreeMap::new(),
+                versions: HashMap::new(),
+                counts: BTreeMap::new(),
+                version: 0,
+                pins: BTreeMap::new(),
+            })),
+        }
+    }
+}
+
+impl<V: Clone> Clone for VersionedStore<V> {
+    /// Copy the versions, the snapshots taken keep reading the original
+    fn clone(&self) -> Self {
+        let mut versions = self.read().clone();
+        versions.pins.clear();
+        VersionedStore {
+            versions: Arc::new(RwLock::new(versions)),
+        }
+    }
+}
+
+fn write<K: Eq + core::hash::Hash, T>(
+    map: &mut HashMap<K, History<T>>,
+    key: K,
+    version: u64,
+    value: Option<T>,
+) {
+    let history = map.entry(key).or_default();
+    match history.last_mut() {
+        Some(last) if last.0 == version => last.1 = value,
+        _ => history.push((version, value)),
+    }
+}
+
+/// Drop the entries that are not visible at any of versions, return how many
+fn retain_visible<T>(history: &mut History<T>, versions: &BTreeSet<u64>) -> usize {
+    let before = history.len();
+    let mut visible = vec![false; before];
+    for version in versions {
+        let end = history.partition_point(|(v, _)| v <= version);
+        if end > 0 {
+            visible[end - 1] = true;
+        }
+    }
+    let mut visible = visible.into_iter();
+    history.retain(|_| visible.next().unwrap_or_default());
+    // a removal with nothing before it reads the same as no entry
+    let removals = history
+        .iter()
+        .take_while(|(_, value)| value.is_none())
+        .count();
+    history.drain(..removals);
+    before - history.len()
+}
+
+fn read_at<T: Clone>(history: Option<&History<T>>, version: u64) -> Option<T> {
+    let history = history?;
+    let end = history.partition_point(|(v, _)| *v <= version);
+    history[..end].last().and_then(|(_, value)| value.clone())
+}
+
+impl<V> VersionedStore<V> {
+    fn read(&self) -> RwLockReadGuard<'_, Versions<V>> {
+        self.versions.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Versions<V>> {
+        self.versions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Version that the next writes belong to
+    pub fn version(&self) -> u64 {
+        self.read().version
+    }
+
+    /// Retained roots, oldest first
+    pub fn roots(&self) -> Vec<H256> {
+        self.read().roots.values().copied().collect()
+    }
+
+    /// Version that committed root, the latest one if it was committed twice
+    pub fn root_version(&self, root: &H256) -> Option<u64> {
+        self.read().versions.get(root).copied()
+    }
+
+    /// Read only view of the store as it was when root was committed
+    pub fn snapshot(&self, root: &H256) -> Result<Snapshot<V>> {
+        let version = self.root_version(root).ok_or(Error::MissingRoot(*root))?;
+        Ok(Snapshot::pin(self.versions.clone(), version))
+    }
+
+    /// Drop every root but keep_roots, along with the branches and leaves
+    /// only reachable from the dropped roots
+    ///
+    /// The entries read by the tree are always kept, so the tree can go on
+    /// being updated whether or not its current root is retained. So are the
+    /// versions read by live snapshots, which are not counted as removed.
+    pub fn prune(&mut self, keep_roots: &[H256]) -> Result<PruneReport> {
+        let mut state = self.write();
+        let mut versions = BTreeSet::from([u64::MAX]);
+        for root in keep_roots {
+            let version = state.versions.get(root).ok_or(Error::MissingRoot(*root))?;
+            versions.insert(*version);
+        }
+        versions.extend(state.pins.keys());
+
+        let mut report = PruneReport::default();
+        let state = &mut *state;
+        state.roots.retain(|version, _| {
+            let kept = versions.contains(version);
+            report.roots_removed += usize::from(!kept);
+            kept
+        });
+        state
+            .versions
+            .retain(|_, version| versions.contains(version));
+        state.counts.retain(|version, _| versions.contains(version));
+        state.branches.retain(|_, history| {
+            report.branches_removed += retain_visible(history, &versions);
+            !history.is_empty()
+        });
+        state.leaves.retain(|_, history| {
+            report.leaves_removed += retain_visible(history, &versions);
+            !history.is_empty()
+        });
+        Ok(report)
+    }
+}
+
+impl<V: Clone> VersionedStore<V> {
+    /// Leaves added and removed from the state at root_a to the one at root_b
+    pub fn diff(&self, root_a: &H256, root_b: &H256) -> Result<Diff<V>> {
+        diff(
+            &self.snapshot(root_a)?,
+            root_a,
+            &self.snapshot(root_b)?,
+            root_b,
+        )
+    }
+}
+
+impl<V: Clone> StoreReadOps<V> for VersionedStore<V> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        Ok(read_at(self.read().branches.get(branch_key), u64::MAX))
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        Ok(read_at(self.read().leaves.get(leaf_key), u64::MAX))
+    }
+    fn get_counts(&self, root: &H256) -> Result<Option<TreeCounts>> {
+        let state = self.read();
+        Ok(state
+            .versions
+            .get(root)
+            .and_then(|version| state.counts.get(version))
+            .cloned())
+    }
+    fn retained_roots(&self) -> Result<Vec<H256>> {
+        Ok(self.roots())
+    }
+    fn get_branch_at(&self, root: &H256, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        let state = self.read();
+        let version = state.versions.get(root).ok_or(Error::MissingRoot(*root))?;
+        Ok(read_at(state.branches.get(branch_key), *version))
+    }
+}
+
+impl<V> StoreWriteOps<V> for VersionedStore<V> {
+    fn insert_branch(&mut self, branch_key: BranchKey, branch: BranchNode) -> Result<()> {
+        let state = &mut *self.write();
+        write(&mut state.branches, branch_key, state.version, Some(branch));
+        Ok(())
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<()> {
+        let state = &mut *self.write();
+        write(&mut state.leaves, leaf_key, state.version, Some(leaf));
+        Ok(())
+    }
+    fn remove_branch(&mut self, branch_key: &BranchKey) -> Result<()> {
+        let state = &mut *self.write();
+        write(&mut state.branches, branch_key.clone(), state.version, None);
+        Ok(())
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<()> {
+        let state = &mut *self.write();
+        write(&mut state.leaves, *leaf_key, state.version, None);
+        Ok(())
+    }
+    fn commit_counts(&mut self, _root: &H256, counts: &TreeCounts) -> Result<()> {
+        let state = &mut *self.write();
+        state.counts.insert(state.version, counts.clone());
+        Ok(())
+    }
+    fn commit_root(&mut self, root: &H256) -> Result<()> {
+        let state = &mut *self.write();
+        state.roots.insert(state.version, *root);
+        state.versions.insert(*root, state.version);
+        state.version += 1;
+        Ok(())
+    }
+    /// Entries are dropped by version rather than by the nodes reachable
+    fn release_roots(
+        &mut self,
+        keep_roots: &[H256],
+        _reachable: &Reachable,
+    ) -> Result<PruneReport> {
+        self.prune(keep_roots)
+    }
+}
+
+/// Keys of the nodes the latest version holds
+fn live_keys<K: Clone, T>(map: &HashMap<K, History<T>>) -> Vec<K> {
+    map.iter()
+        .filter(|(_, history)| matches!(history.last(), Some((_, Some(_)))))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+impl<V> StoreKeysOps for VersionedStore<V> {
+    fn branch_keys(&self) -> Result<Vec<BranchKey>> {
+        Ok(live_keys(&self.read().branches))
+    }
+    fn leaf_keys(&self) -> Result<Vec<H256>> {
+        Ok(live_keys(&self.read().leaves))
+    }
+}
+
+/// Read only view of a `VersionedStore` at a committed version
+///
+/// The version stays readable until the snapshot is dropped, even once its
+/// root is pruned from the store.
+#[derive(Debug)]
+pub struct Snapshot<V> {
+    versions: Arc<RwLock<Versions<V>>>,
+    version: u64,
+}
+
+impl<V> Snapshot<V> {
+    fn pin(versions: Arc<RwLock<Versions<V>>>, version: u64) -> Self {
+        let snapshot = Snapshot { versions, version };
+        *snapshot.write().pins.entry(version).or_default() += 1;
+        snapshot
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Versions<V>> {
+        self.versions.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Versions<V>> {
+        self.versions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Version this snapshot reads
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl<V> Clone for Snapshot<V> {
+    fn clone(&self) -> Self {
+        Snapshot::pin(self.versions.clone(), self.version)
+    }
+}
+
+impl<V> Drop for Snapshot<V> {
+    fn drop(&mut self) {
+        let mut state = self.write();
+        if let Some(pins) = state.pins.get_mut(&self.version) {
+            *pins -= 1;
+            if *pins == 0 {
+                state.pins.remove(&self.version);
+            }
+        }
+    }
+}
+
+impl<V: Clone> StoreReadOps<V> for Snapshot<V> {
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>> {
+        Ok(read_at(self.read().branches.get(branch_key), self.version))
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>> {
+        Ok(read_at(self.read().leaves.get(leaf_key), self.version))
+    }
+    fn get_counts(&self, _root: &H256) -> Result<Option<TreeCounts>> {
+        Ok(self.read().counts.get(&self.version).cloned())
+    }
+}
+
+impl<H: Hasher + Default, V: Clone> SparseMerkleTree<H, V, VersionedStore<V>> {
+    /// Open a read only tree at any root retained by the store
+    pub fn snapshot(&self, root: &H256) -> Result<SparseMerkleTree<H, V, Snapshot<V>>> {
+        let snapshot = self.store().snapshot(root)?;
+        let counts = snapshot.get_counts(root)?;
+        Ok(SparseMerkleTree::with_counts(*root, snapshot, counts))
+    }
+}